@@ -0,0 +1,53 @@
+//! Encoding echo requests into a new `Vec` each, against into a buffer
+//! reused between requests like a pinger does, which allocates nothing.
+mod common;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use surge_ping::icmp::icmpv4::{make_icmpv4_echo_packet, write_icmpv4_echo_packet};
+use surge_ping::icmp::icmpv6::{make_icmpv6_echo_packet, write_icmpv6_echo_packet};
+
+use common::{allocations, CountingAlloc};
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+const SIZE: usize = 56;
+const PROBES: usize = 1_000_000;
+
+fn echo_request(c: &mut Criterion) {
+    let payload = [0x5a; SIZE];
+    let mut buf = vec![0; 8 + SIZE];
+    let made = allocations(PROBES, || {
+        drop(black_box(make_icmpv4_echo_packet(1, 2, SIZE, &payload)));
+        drop(black_box(make_icmpv6_echo_packet(1, 2, SIZE, &payload)));
+    });
+    let reused = allocations(PROBES, || {
+        write_icmpv4_echo_packet(&mut buf, 1, 2, &payload).unwrap();
+        black_box(&buf);
+        write_icmpv6_echo_packet(&mut buf, 1, 2, &payload).unwrap();
+        black_box(&buf);
+    });
+    eprintln!(
+        "allocations per million IPv4 and IPv6 requests: {} made, {} reused",
+        made, reused
+    );
+    assert_eq!(reused, 0, "encoding into a reused buffer allocated");
+
+    let mut group = c.benchmark_group("echo_request");
+    group.bench_function("icmpv4_make", |b| {
+        b.iter(|| make_icmpv4_echo_packet(1, black_box(2), SIZE, &payload))
+    });
+    group.bench_function("icmpv4_reused", |b| {
+        b.iter(|| write_icmpv4_echo_packet(&mut buf, 1, black_box(2), &payload))
+    });
+    group.bench_function("icmpv6_make", |b| {
+        b.iter(|| make_icmpv6_echo_packet(1, black_box(2), SIZE, &payload))
+    });
+    group.bench_function("icmpv6_reused", |b| {
+        b.iter(|| write_icmpv6_echo_packet(&mut buf, 1, black_box(2), &payload))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, echo_request);
+criterion_main!(benches);
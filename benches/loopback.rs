@@ -0,0 +1,48 @@
+//! Bursts of echo requests to the loopback address, whose replies the
+//! receive task reads in batches, with a single `recvmmsg` per wakeup on
+//! Linux.
+//!
+//! Needs an ICMP socket, see `PingSocketBuilder::new_auto`. Nothing is
+//! measured when none can be opened.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use socket2::Domain;
+use surge_ping::PingSocketBuilder;
+use tokio::runtime::Runtime;
+
+const BURST: u16 = 64;
+
+fn ping_burst(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    // The socket registers with the reactor of the runtime it's built in.
+    let _guard = rt.enter();
+    let builder = PingSocketBuilder::new_auto(Domain::IPV4);
+    let socket = match builder.and_then(PingSocketBuilder::build) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("no ICMP socket, skipped: {}", e);
+            return;
+        }
+    };
+    let mut pinger = rt
+        .block_on(socket.pinger(IpAddr::V4(Ipv4Addr::LOCALHOST)))
+        .unwrap();
+    pinger.timeout(Duration::from_secs(1));
+
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Elements(u64::from(BURST)));
+    let mut seq_cnt = 0u16;
+    group.bench_function("ping_burst", |b| {
+        b.iter(|| {
+            let results = rt.block_on(pinger.ping_burst(seq_cnt, BURST));
+            seq_cnt = seq_cnt.wrapping_add(BURST);
+            results
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, ping_burst);
+criterion_main!(benches);
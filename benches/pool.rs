@@ -0,0 +1,76 @@
+//! Allocations of the receive path per reply: a copy of each packet into a
+//! new `Vec`, against a buffer taken from the pool and returned to it when
+//! the reply is dropped, alone and handed to a pinger through its channel.
+mod common;
+#[allow(dead_code)]
+#[path = "../src/pool.rs"]
+mod pool;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+use common::{allocations, CountingAlloc};
+use pool::{BufferPool, DEFAULT_POOLED_BUFFERS};
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// An echo reply with 56 bytes of data and its IPv4 header.
+const REPLY: [u8; 84] = [0x45; 84];
+
+/// Replies received in a second at 50k pps.
+const PER_SECOND: usize = 50_000;
+
+fn per_reply(c: &mut Criterion) {
+    let pool = BufferPool::new(DEFAULT_POOLED_BUFFERS);
+    let copied = allocations(1_000_000, || drop(black_box(REPLY.to_vec())));
+    let pooled = allocations(1_000_000, || drop(black_box(pool.get(&REPLY))));
+    eprintln!(
+        "allocations per million replies: {} copied, {} pooled",
+        copied, pooled
+    );
+
+    let mut group = c.benchmark_group("recv_buffer");
+    group.bench_function("copied", |b| b.iter(|| black_box(REPLY.to_vec())));
+    group.bench_function("pooled", |b| b.iter(|| black_box(pool.get(&REPLY))));
+    group.finish();
+}
+
+/// Hand a second of replies at 50k pps to a pinger through `tx`, like the
+/// receive task, the pinger reading and dropping each from `rx`.
+fn relay<T>(tx: &Sender<T>, rx: &mut Receiver<T>, reply: impl Fn() -> T) {
+    for _ in 0..PER_SECOND {
+        let _ = tx.try_send(reply());
+        drop(black_box(rx.try_recv()));
+    }
+}
+
+fn at_50k_pps(c: &mut Criterion) {
+    let pool = BufferPool::new(DEFAULT_POOLED_BUFFERS);
+    let (copied_tx, mut copied_rx) = channel(1024);
+    let (pooled_tx, mut pooled_rx) = channel(1024);
+    let copy = || REPLY.to_vec();
+    let take = || pool.get(&REPLY);
+    // Warm the channels up before counting.
+    relay(&copied_tx, &mut copied_rx, copy);
+    relay(&pooled_tx, &mut pooled_rx, take);
+    let copied = allocations(1, || relay(&copied_tx, &mut copied_rx, copy));
+    let pooled = allocations(1, || relay(&pooled_tx, &mut pooled_rx, take));
+    eprintln!(
+        "allocations per second at 50k pps: {} copied, {} pooled",
+        copied, pooled
+    );
+
+    let mut group = c.benchmark_group("recv_50k_pps");
+    group.throughput(Throughput::Elements(PER_SECOND as u64));
+    group.bench_function("copied", |b| {
+        b.iter(|| relay(&copied_tx, &mut copied_rx, copy))
+    });
+    group.bench_function("pooled", |b| {
+        b.iter(|| relay(&pooled_tx, &mut pooled_rx, take))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, per_reply, at_50k_pps);
+criterion_main!(benches);
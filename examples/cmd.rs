@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use structopt::StructOpt;
-use surge_ping::{IcmpPacket, Pinger};
+use surge_ping::Pinger;
 use tokio::time;
 
 #[derive(Default, Debug)]
@@ -150,27 +150,9 @@ async fn main() {
     for idx in 0..opt.count {
         interval.tick().await;
         match pinger.ping(idx).await {
-            Ok((IcmpPacket::V4(reply), dur)) => {
-                println!(
-                    "{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms",
-                    reply.get_size(),
-                    reply.get_source(),
-                    reply.get_sequence(),
-                    reply.get_ttl(),
-                    dur.as_secs_f64() * 1000f64
-                );
-                answer.update(Some(dur));
-            }
-            Ok((IcmpPacket::V6(reply), dur)) => {
-                println!(
-                    "{} bytes from {}: icmp_seq={} hlim={} time={:.3} ms",
-                    reply.get_size(),
-                    reply.get_source(),
-                    reply.get_sequence(),
-                    reply.get_max_hop_limit(),
-                    dur.as_secs_f64() * 1000f64
-                );
-                answer.update(Some(dur));
+            Ok(reply) => {
+                println!("{}", reply);
+                answer.update(Some(reply.rtt));
             }
             Err(e) => {
                 println!("{}", e);
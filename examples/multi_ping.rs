@@ -2,8 +2,8 @@ use std::net::IpAddr;
 use std::sync::Arc;
 use std::time::Duration;
 
-use surge_ping::{IcmpPacket, PingSocket, PingSocketBuilder};
-use tokio::time;
+use surge_ping::{DualPingSocket, PingEvent};
+use tokio_stream::StreamExt;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -15,16 +15,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "240c::6666",
         "2a02:930::ff76",
     ];
-    let builder_v4 = PingSocketBuilder::new(socket2::Domain::IPV4)?;
-    let ping_socket_v4 = Arc::new(builder_v4.build()?);
-    let ping_socket_v6 = Arc::new(PingSocket::new(socket2::Domain::IPV6)?);
+    let ping_socket = Arc::new(DualPingSocket::new()?);
     let mut tasks = Vec::new();
     for ip in &ips {
         let addr: IpAddr = ip.parse()?;
-        let psc = match addr {
-            IpAddr::V4(_) => ping_socket_v4.clone(),
-            IpAddr::V6(_) => ping_socket_v6.clone(),
-        };
+        let psc = ping_socket.clone();
         tasks.push(tokio::spawn(async move {
             ping(psc, addr, 56).await.unwrap();
         }));
@@ -38,33 +33,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 // Ping an address 5 times， and print output message（interval 1s）
 async fn ping(
-    ps: Arc<PingSocket>,
+    ps: Arc<DualPingSocket>,
     addr: IpAddr,
     size: usize,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut pinger = ps.pinger(addr).await;
+    let mut pinger = ps.pinger(addr).await?;
     pinger.size(size).timeout(Duration::from_secs(1));
-    let mut interval = time::interval(Duration::from_secs(1));
-    for idx in 0..5 {
-        interval.tick().await;
-        match pinger.ping(idx).await {
-            Ok((IcmpPacket::V4(packet), dur)) => println!(
+    let mut results = pinger.into_stream(Duration::from_secs(1)).take(5);
+    while let Some(event) = results.next().await {
+        match event {
+            PingEvent::Reply { packet, rtt, .. } => println!(
                 "{} bytes from {}: icmp_seq={} ttl={} time={:?}",
                 packet.get_size(),
                 packet.get_source(),
                 packet.get_sequence(),
-                packet.get_ttl(),
-                dur
-            ),
-            Ok((IcmpPacket::V6(packet), dur)) => println!(
-                "{} bytes from {}: icmp_seq={} hlim={} time={:?}",
-                packet.get_size(),
-                packet.get_source(),
-                packet.get_sequence(),
-                packet.get_max_hop_limit(),
-                dur
+                packet
+                    .get_ttl()
+                    .map_or("?".to_string(), |ttl| ttl.to_string()),
+                rtt
             ),
-            Err(e) => println!("{} ping {}", addr, e),
+            PingEvent::Timeout(seq) => println!("{} ping icmp_seq={} timeout", addr, seq),
+            PingEvent::Error { seq, error } => {
+                println!("{} ping icmp_seq={} {}", addr, seq, error)
+            }
         };
     }
     println!("[+] {} done.", addr);
@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+
+use crate::kstamp::KernelTimestamp;
+#[cfg(target_os = "linux")]
+use crate::transport::Marking;
+
+#[cfg(target_os = "linux")]
+use std::{io, os::unix::io::RawFd};
+
+/// Maximum number of packets pulled from the socket per receive wakeup.
+pub(crate) const MAX_RECV_BATCH: usize = 64;
+/// Maximum number of packets submitted to the kernel per `sendmmsg` call.
+#[cfg(target_os = "linux")]
+pub(crate) const MAX_SEND_BATCH: usize = 64;
+
+/// A preallocated set of receive buffers filled by one batch receive.
+///
+/// On Linux the whole batch is read with a single `recvmmsg` call, on other
+/// platforms only the first slot is used and filled by `recv_from`.
+pub(crate) struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+    addrs: Vec<Option<SocketAddr>>,
+    /// Kernel receive timestamps, on sockets timestamping packets.
+    stamps: Vec<Option<KernelTimestamp>>,
+    filled: usize,
+}
+
+impl RecvBatch {
+    pub fn new(count: usize, buf_size: usize) -> RecvBatch {
+        let count = count.clamp(1, MAX_RECV_BATCH);
+        RecvBatch {
+            bufs: vec![vec![0_u8; buf_size]; count],
+            lens: vec![0; count],
+            addrs: vec![None; count],
+            stamps: vec![None; count],
+            filled: 0,
+        }
+    }
+
+    /// Grow every buffer to at least `buf_size` bytes.
+    pub fn reserve(&mut self, buf_size: usize) {
+        for buf in self.bufs.iter_mut().filter(|buf| buf.len() < buf_size) {
+            buf.resize(buf_size, 0);
+        }
+    }
+
+    /// Iterate over the packets of the last batch receive with their source
+    /// address and kernel receive timestamp.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr, Option<KernelTimestamp>)> + '_ {
+        (0..self.filled).filter_map(move |idx| {
+            self.addrs[idx].map(|addr| (&self.bufs[idx][..self.lens[idx]], addr, self.stamps[idx]))
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn set_single(&mut self, len: usize, addr: SocketAddr) {
+        self.lens[0] = len;
+        self.addrs[0] = Some(addr);
+        self.filled = 1;
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn first_buf_mut(&mut self) -> &mut [u8] {
+        &mut self.bufs[0]
+    }
+
+    /// Read as many pending packets as fit in the batch without blocking.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn recvmmsg(&mut self, fd: RawFd) -> io::Result<usize> {
+        use std::mem;
+
+        let count = self.bufs.len();
+        let mut iovecs: [libc::iovec; MAX_RECV_BATCH] = unsafe { mem::zeroed() };
+        let mut names: [libc::sockaddr_storage; MAX_RECV_BATCH] = unsafe { mem::zeroed() };
+        let mut controls: [TimestampBuf; MAX_RECV_BATCH] = [[0; 8]; MAX_RECV_BATCH];
+        let mut hdrs: [libc::mmsghdr; MAX_RECV_BATCH] = unsafe { mem::zeroed() };
+        for ((((buf, iov), name), control), hdr) in self
+            .bufs
+            .iter_mut()
+            .zip(iovecs.iter_mut())
+            .zip(names.iter_mut())
+            .zip(controls.iter_mut())
+            .zip(hdrs.iter_mut())
+        {
+            iov.iov_base = buf.as_mut_ptr() as *mut libc::c_void;
+            iov.iov_len = buf.len();
+            hdr.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            hdr.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_hdr.msg_controllen = mem::size_of::<TimestampBuf>() as _;
+        }
+        let ret = unsafe {
+            libc::recvmmsg(
+                fd,
+                hdrs.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let received = ret as usize;
+        for ((((len, addr), stamp), hdr), name) in self
+            .lens
+            .iter_mut()
+            .zip(self.addrs.iter_mut())
+            .zip(self.stamps.iter_mut())
+            .zip(hdrs.iter())
+            .zip(names.iter())
+            .take(received)
+        {
+            *len = hdr.msg_len as usize;
+            *addr = sockaddr_to_std(name);
+            *stamp = None;
+            let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&hdr.msg_hdr) };
+            while !cmsg.is_null() {
+                if let Some(ts) = unsafe { KernelTimestamp::from_cmsg(cmsg) } {
+                    *stamp = Some(ts);
+                }
+                cmsg = unsafe { libc::CMSG_NXTHDR(&hdr.msg_hdr, cmsg) };
+            }
+        }
+        self.filled = received;
+        Ok(received)
+    }
+}
+
+/// Control message buffer with room for one `SCM_TIMESTAMPING`, aligned for
+/// `cmsghdr`.
+#[cfg(target_os = "linux")]
+type TimestampBuf = [u64; 8];
+
+/// Control message buffer with room for one `IPV6_PKTINFO`, aligned for `cmsghdr`.
+#[cfg(target_os = "linux")]
+pub(crate) type Pktinfo6Buf = [u64; 8];
+
+/// Whether packets to `target` are sent through the interface set with
+/// `IPV6_PKTINFO`: link-local and multicast IPv6 destinations only, global
+/// ones are left to the routing table.
+#[cfg(target_os = "linux")]
+pub(crate) fn needs_pktinfo6(target: &SocketAddr) -> bool {
+    match target {
+        SocketAddr::V6(addr) => {
+            addr.ip().is_multicast() || (addr.ip().segments()[0] & 0xffc0) == 0xfe80
+        }
+        SocketAddr::V4(_) => false,
+    }
+}
+
+/// Attach an `IPV6_PKTINFO` control message selecting the outgoing interface
+/// `ifindex` to `msg`, the source address is left to the kernel.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_pktinfo6(msg: &mut libc::msghdr, control: &mut Pktinfo6Buf, ifindex: u32) {
+    use std::mem;
+
+    let info = libc::in6_pktinfo {
+        ipi6_addr: libc::in6_addr { s6_addr: [0; 16] },
+        ipi6_ifindex: ifindex,
+    };
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen =
+        unsafe { libc::CMSG_SPACE(mem::size_of::<libc::in6_pktinfo>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(msg);
+        (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+        (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::in6_pktinfo>() as u32) as _;
+        (libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo).write_unaligned(info);
+    }
+}
+
+/// Control message buffer with room for one `IPV6_PKTINFO` and the TTL and
+/// TOS of a single packet, aligned for `cmsghdr`.
+#[cfg(target_os = "linux")]
+type SendControlBuf = [u64; 16];
+
+/// Send one packet with `sendmsg`, through interface `ifindex` if the
+/// destination needs one, see `needs_pktinfo6`, and with the TTL and TOS of
+/// `marking` if set.
+#[cfg(target_os = "linux")]
+pub(crate) fn sendmsg(
+    fd: RawFd,
+    packet: &[u8],
+    target: &SocketAddr,
+    ifindex: Option<u32>,
+    marking: Marking,
+) -> io::Result<usize> {
+    use std::mem;
+
+    let addr = socket2::SockAddr::from(*target);
+    let mut control: SendControlBuf = [0; 16];
+    let mut iov = libc::iovec {
+        iov_base: packet.as_ptr() as *mut libc::c_void,
+        iov_len: packet.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = addr.as_ptr() as *mut libc::c_void;
+    msg.msg_namelen = addr.len();
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let pktinfo = ifindex
+        .filter(|_| needs_pktinfo6(target))
+        .map(|ifindex| libc::in6_pktinfo {
+            ipi6_addr: libc::in6_addr { s6_addr: [0; 16] },
+            ipi6_ifindex: ifindex,
+        });
+    let (ttl, tos) = if target.is_ipv6() {
+        (
+            (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT),
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS),
+        )
+    } else {
+        (
+            (libc::IPPROTO_IP, libc::IP_TTL),
+            (libc::IPPROTO_IP, libc::IP_TOS),
+        )
+    };
+    let ints = [
+        marking.ttl.map(|value| (ttl, libc::c_int::from(value))),
+        marking.tos.map(|value| (tos, libc::c_int::from(value))),
+    ];
+    let int_space = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::c_int>() as u32) } as usize;
+    let mut controllen = ints.iter().flatten().count() * int_space;
+    if pktinfo.is_some() {
+        controllen +=
+            unsafe { libc::CMSG_SPACE(mem::size_of::<libc::in6_pktinfo>() as u32) } as usize;
+    }
+    if controllen > 0 {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = controllen as _;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if let Some(info) = pktinfo {
+                (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::in6_pktinfo>() as u32) as _;
+                (libc::CMSG_DATA(cmsg) as *mut libc::in6_pktinfo).write_unaligned(info);
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+            for &((level, kind), value) in ints.iter().flatten() {
+                if cmsg.is_null() {
+                    break;
+                }
+                (*cmsg).cmsg_level = level;
+                (*cmsg).cmsg_type = kind;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<libc::c_int>() as u32) as _;
+                (libc::CMSG_DATA(cmsg) as *mut libc::c_int).write_unaligned(value);
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+    let ret = unsafe { libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Submit up to `MAX_SEND_BATCH` packets with a single `sendmmsg` call.
+///
+/// Packets to destinations needing an interface are sent through `ifindex`
+/// if set, see `needs_pktinfo6`. Returns how many leading packets were
+/// handed to the kernel.
+#[cfg(target_os = "linux")]
+pub(crate) fn sendmmsg(
+    fd: RawFd,
+    packets: &[&[u8]],
+    targets: &[SocketAddr],
+    ifindex: Option<u32>,
+) -> io::Result<usize> {
+    use std::mem;
+
+    let count = packets.len().min(targets.len()).min(MAX_SEND_BATCH);
+    let addrs: Vec<socket2::SockAddr> = targets[..count]
+        .iter()
+        .map(|t| socket2::SockAddr::from(*t))
+        .collect();
+    let mut iovecs: [libc::iovec; MAX_SEND_BATCH] = unsafe { mem::zeroed() };
+    let mut controls: [Pktinfo6Buf; MAX_SEND_BATCH] = [[0; 8]; MAX_SEND_BATCH];
+    let mut hdrs: [libc::mmsghdr; MAX_SEND_BATCH] = unsafe { mem::zeroed() };
+    for (((((packet, target), addr), iov), control), hdr) in packets
+        .iter()
+        .zip(targets.iter())
+        .zip(addrs.iter())
+        .zip(iovecs.iter_mut())
+        .zip(controls.iter_mut())
+        .zip(hdrs.iter_mut())
+    {
+        iov.iov_base = packet.as_ptr() as *mut libc::c_void;
+        iov.iov_len = packet.len();
+        hdr.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+        hdr.msg_hdr.msg_namelen = addr.len();
+        hdr.msg_hdr.msg_iov = iov;
+        hdr.msg_hdr.msg_iovlen = 1;
+        if let Some(ifindex) = ifindex.filter(|_| needs_pktinfo6(target)) {
+            set_pktinfo6(&mut hdr.msg_hdr, control, ifindex);
+        }
+    }
+    let ret = unsafe { libc::sendmmsg(fd, hdrs.as_mut_ptr(), count as _, libc::MSG_DONTWAIT as _) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn sockaddr_to_std(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,61 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::error::Result;
+use crate::ping::{Pinger, PingerBuilder};
+use crate::result::PingResult;
+
+/// A `Pinger` usable without a tokio runtime, for CLI tools and scripts.
+///
+/// Runs the async `Pinger` on a current-thread runtime of its own, the
+/// receive task only making progress while a ping is blocked on.
+///
+/// # Examples
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use surge_ping::BlockingPinger;
+///
+/// let mut pinger = BlockingPinger::new("114.114.114.114".parse().unwrap()).unwrap();
+/// pinger.get_mut().timeout(Duration::from_secs(1));
+/// println!("{:?}", pinger.ping(0));
+/// ```
+pub struct BlockingPinger {
+    // Dropped before the runtime its receive task runs on.
+    pinger: Pinger,
+    runtime: Runtime,
+}
+
+impl BlockingPinger {
+    /// Creates a pinger to `host` with the default settings.
+    pub fn new(host: IpAddr) -> Result<BlockingPinger> {
+        BlockingPinger::with_builder(&Pinger::builder(host))
+    }
+
+    /// Creates the pinger configured by `builder`, which must not use a
+    /// `PingSocket` as those run on the runtime they were built on.
+    pub fn with_builder(builder: &PingerBuilder) -> Result<BlockingPinger> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let pinger = runtime.block_on(builder.build())?;
+        Ok(BlockingPinger { pinger, runtime })
+    }
+
+    /// The underlying pinger, to change its settings.
+    pub fn get_mut(&mut self) -> &mut Pinger {
+        &mut self.pinger
+    }
+
+    /// Send Ping request with sequence number and wait for the reply, see
+    /// `Pinger::ping`.
+    pub fn ping(&mut self, seq_cnt: u16) -> Result<PingResult> {
+        self.runtime.block_on(self.pinger.ping(seq_cnt))
+    }
+
+    /// Like `ping`, waiting at most `timeout`, see `Pinger::ping_with_timeout`.
+    pub fn ping_with_timeout(&mut self, seq_cnt: u16, timeout: Duration) -> Result<PingResult> {
+        self.runtime
+            .block_on(self.pinger.ping_with_timeout(seq_cnt, timeout))
+    }
+}
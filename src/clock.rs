@@ -0,0 +1,34 @@
+use std::fmt;
+use std::time::Instant;
+
+/// Source of the current time, for the send and receive times of requests
+/// and the send rate limit.
+///
+/// Timeouts always use the runtime's timers, so a clock other than
+/// `TokioClock` is only useful when tokio's clock isn't paused.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// tokio's clock, the default. It is the monotonic system clock unless
+/// paused with `tokio::time::pause`, after which tests move it with
+/// `tokio::time::advance` to check timeouts and RTTs deterministically.
+/// With the `runtime-async-std` feature it is the system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        crate::rt::now()
+    }
+}
+
+/// The monotonic system clock, ignoring whether tokio's clock is paused.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
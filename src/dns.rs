@@ -0,0 +1,67 @@
+//! Host name resolution of the `dns` feature.
+use std::net::IpAddr;
+
+use socket2::Domain;
+
+use crate::error::{Result, SurgeError};
+use crate::ping::Pinger;
+use crate::pingsocket::PingSocket;
+use crate::rt;
+
+/// Resolve `host`, a host name or an IP address literal, to all its
+/// addresses, in the order of the system resolver and without duplicates.
+///
+/// Fails with `SurgeError::ResolutionFailed` if the lookup fails or returns
+/// no address.
+pub async fn resolve_host(host: &str) -> Result<Vec<IpAddr>> {
+    let failed = |source| SurgeError::ResolutionFailed {
+        host: host.to_string(),
+        source,
+    };
+    let mut addrs: Vec<IpAddr> = Vec::new();
+    for addr in rt::lookup_host(host).await.map_err(|e| failed(Some(e)))? {
+        if !addrs.contains(&addr.ip()) {
+            addrs.push(addr.ip());
+        }
+    }
+    if addrs.is_empty() {
+        return Err(failed(None));
+    }
+    Ok(addrs)
+}
+
+/// The first address of `host` of the `domain` family, or of any family
+/// without one.
+async fn resolve_first(host: &str, domain: Option<Domain>) -> Result<IpAddr> {
+    resolve_host(host)
+        .await?
+        .into_iter()
+        .find(|addr| domain.map_or(true, |d| addr.is_ipv4() == (d == Domain::IPV4)))
+        .ok_or_else(|| SurgeError::ResolutionFailed {
+            host: host.to_string(),
+            source: None,
+        })
+}
+
+impl Pinger {
+    /// Creates a pinger owning its own socket to the first address `host`
+    /// resolves to, see `resolve_host` for all of them.
+    ///
+    /// `Pinger::destination` tells the address chosen.
+    pub async fn new_resolved(host: &str) -> Result<Pinger> {
+        let addr = resolve_first(host, None).await?;
+        Pinger::builder(addr).build().await
+    }
+}
+
+impl PingSocket {
+    /// Creates a pinger to the first address `host` resolves to in the
+    /// family of the socket.
+    ///
+    /// Fails with `SurgeError::ResolutionFailed` if `host` has no address of
+    /// that family. `Pinger::destination` tells the address chosen.
+    pub async fn pinger_for_host(&self, host: &str) -> Result<Pinger> {
+        let addr = resolve_first(host, Some(self.domain())).await?;
+        self.pinger(addr).await
+    }
+}
@@ -1,13 +1,19 @@
 #![allow(dead_code)]
+use std::fmt;
 use std::io;
+use std::net::IpAddr;
+use std::time::Duration;
 
 use thiserror::Error;
 
+use crate::errqueue::IcmpErrorReport;
+
 pub type Result<T> = std::result::Result<T, SurgeError>;
 
 /// An error resulting from a ping option-setting or send/receive operation.
 ///
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum SurgeError {
     #[error("buffer size was too small")]
     IncorrectBufferSize,
@@ -15,12 +21,399 @@ pub enum SurgeError {
     MalformedPacket(#[from] MalformedPacketError),
     #[error("io error")]
     IOError(#[from] io::Error),
-    #[error("Request timeout for icmp_seq {seq}")]
-    Timeout { seq: u16 },
+    #[error(
+        "Request timeout for icmp_seq {seq} to {destination} (ident {ident}) after {timeout:?}"
+    )]
+    Timeout {
+        destination: IpAddr,
+        ident: u16,
+        seq: u16,
+        timeout: Duration,
+    },
     #[error("Echo Request packet.")]
     EchoRequestPacket,
     #[error("Network error.")]
     NetworkError,
+    #[error(
+        "raw ICMP sockets require privileges: run as root, grant CAP_NET_RAW \
+         (setcap cap_net_raw+ep) or install the binary setuid root, or fall back to an \
+         unprivileged DGRAM ICMP socket with PingSocketBuilder::new_auto, allowed for the \
+         groups in the net.ipv4.ping_group_range sysctl"
+    )]
+    PermissionDenied(#[source] io::Error),
+    #[error("invalid CIDR: {0}")]
+    InvalidCidr(#[from] ipnet::AddrParseError),
+    #[error("address family mismatch: cannot ping {addr} from an {socket} socket")]
+    AddressFamilyMismatch { addr: IpAddr, socket: &'static str },
+    #[error("invalid payload size {requested}, expected {min} to {max} bytes")]
+    InvalidSize {
+        requested: usize,
+        min: usize,
+        max: usize,
+    },
+    #[error("payload pattern must not be empty")]
+    EmptyPattern,
+    #[error("invalid ICMP checksum")]
+    InvalidChecksum,
+    #[error("echoed payload differs from the request at offset {offset}")]
+    PayloadMismatch { offset: usize },
+    #[error("unexpected ICMP reply type {icmp_type} code {icmp_code}")]
+    UnexpectedReply { icmp_type: u8, icmp_code: u8 },
+    #[error("timeout must be greater than zero")]
+    InvalidTimeout,
+    #[error("{0}")]
+    IcmpError(IcmpErrorReport),
+    #[error("ICMP identifier {ident} is already used by another pinger on the socket")]
+    IdentInUse { ident: u16 },
+    #[error("all ICMP identifiers are in use on the socket")]
+    IdentsExhausted,
+    #[error("{destination} is already pinged by another pinger on the socket")]
+    DestinationInUse { destination: IpAddr },
+    #[error("failed to send echo request to {destination}")]
+    SendError {
+        destination: IpAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("network of {destination} is unreachable")]
+    NetworkUnreachable {
+        destination: IpAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("no route to host {destination}")]
+    HostUnreachable {
+        destination: IpAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error(
+        "sending to {destination} was denied by the system, e.g. a firewall rule dropping \
+         outgoing ICMP"
+    )]
+    SendPermissionDenied {
+        destination: IpAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("cannot ping broadcast address {destination}: enable SO_BROADCAST on the socket")]
+    BroadcastNotEnabled {
+        destination: IpAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Destination unreachable from {from}: {code}")]
+    DestinationUnreachable {
+        code: UnreachableCode,
+        /// The host that sent the error.
+        from: IpAddr,
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+    #[error("Time to live exceeded from {from}")]
+    TimeExceeded {
+        from: IpAddr,
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+    #[error("Source quench from {from}")]
+    SourceQuench {
+        from: IpAddr,
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+    #[error("Redirect from {from} to gateway {gateway}")]
+    RedirectReceived {
+        gateway: IpAddr,
+        from: IpAddr,
+        icmp_type: u8,
+        icmp_code: u8,
+    },
+    #[error("failed to resolve {host}")]
+    ResolutionFailed {
+        host: String,
+        #[source]
+        source: Option<io::Error>,
+    },
+}
+
+impl SurgeError {
+    /// Classify an error returned while creating a socket, so that missing
+    /// raw socket privileges (EPERM or EACCES) are reported as
+    /// `PermissionDenied`.
+    pub(crate) fn from_socket_create(err: io::Error) -> SurgeError {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => SurgeError::PermissionDenied(err),
+            _ => SurgeError::IOError(err),
+        }
+    }
+
+    /// The `io::Error` the error was caused by, also returned by `source`.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            SurgeError::IOError(source)
+            | SurgeError::PermissionDenied(source)
+            | SurgeError::SendError { source, .. }
+            | SurgeError::NetworkUnreachable { source, .. }
+            | SurgeError::HostUnreachable { source, .. }
+            | SurgeError::SendPermissionDenied { source, .. }
+            | SurgeError::BroadcastNotEnabled { source, .. } => Some(source),
+            SurgeError::ResolutionFailed { source, .. } => source.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// The kind of the `io::Error` the error was caused by, see `io_error`.
+    pub fn kind(&self) -> Option<io::ErrorKind> {
+        self.io_error().map(io::Error::kind)
+    }
+
+    /// The OS error code of the `io::Error` the error was caused by, see
+    /// `io_error`.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.io_error().and_then(io::Error::raw_os_error)
+    }
+
+    /// Classify an error returned while sending a request to `destination`,
+    /// keeping it as the source.
+    pub(crate) fn from_send(destination: IpAddr, source: io::Error) -> SurgeError {
+        match source.raw_os_error().and_then(SendErrno::from_raw) {
+            Some(SendErrno::NetworkUnreachable) => SurgeError::NetworkUnreachable {
+                destination,
+                source,
+            },
+            Some(SendErrno::HostUnreachable) => SurgeError::HostUnreachable {
+                destination,
+                source,
+            },
+            Some(SendErrno::Denied) => SurgeError::SendPermissionDenied {
+                destination,
+                source,
+            },
+            Some(SendErrno::Broadcast) => SurgeError::BroadcastNotEnabled {
+                destination,
+                source,
+            },
+            None => SurgeError::SendError {
+                destination,
+                source,
+            },
+        }
+    }
+}
+
+/// Send failures with a dedicated `SurgeError` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendErrno {
+    NetworkUnreachable,
+    HostUnreachable,
+    /// EPERM, e.g. an nftables or iptables rule dropping the request.
+    Denied,
+    /// EACCES, sending to a broadcast address without SO_BROADCAST.
+    Broadcast,
+}
+
+impl SendErrno {
+    #[cfg(target_os = "linux")]
+    fn from_raw(code: i32) -> Option<SendErrno> {
+        match code {
+            libc::ENETUNREACH => Some(SendErrno::NetworkUnreachable),
+            libc::EHOSTUNREACH => Some(SendErrno::HostUnreachable),
+            libc::EPERM => Some(SendErrno::Denied),
+            libc::EACCES => Some(SendErrno::Broadcast),
+            _ => None,
+        }
+    }
+
+    /// BSD errno values, shared by macOS.
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn from_raw(code: i32) -> Option<SendErrno> {
+        match code {
+            51 => Some(SendErrno::NetworkUnreachable),
+            65 => Some(SendErrno::HostUnreachable),
+            1 => Some(SendErrno::Denied),
+            13 => Some(SendErrno::Broadcast),
+            _ => None,
+        }
+    }
+
+    /// Winsock error codes, Windows has no EPERM equivalent.
+    #[cfg(windows)]
+    fn from_raw(code: i32) -> Option<SendErrno> {
+        match code {
+            10051 => Some(SendErrno::NetworkUnreachable),
+            10065 => Some(SendErrno::HostUnreachable),
+            10013 => Some(SendErrno::Broadcast),
+            _ => None,
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn from_raw(_code: i32) -> Option<SendErrno> {
+        None
+    }
+}
+
+/// Serialized with the name of the variant as `kind` and the error message,
+/// e.g. `{"kind": "NetworkError", "message": "Network error."}`.
+///
+/// Errors are serialize-only: the message loses the fields and the source of
+/// the error, so `SurgeError` doesn't implement `Deserialize`. Read them back
+/// as a struct of two strings.
+#[cfg(feature = "serde")]
+impl serde::Serialize for SurgeError {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let name = match self {
+            SurgeError::IncorrectBufferSize => "IncorrectBufferSize",
+            SurgeError::MalformedPacket(_) => "MalformedPacket",
+            SurgeError::IOError(_) => "IOError",
+            SurgeError::Timeout { .. } => "Timeout",
+            SurgeError::EchoRequestPacket => "EchoRequestPacket",
+            SurgeError::NetworkError => "NetworkError",
+            SurgeError::PermissionDenied(_) => "PermissionDenied",
+            SurgeError::InvalidCidr(_) => "InvalidCidr",
+            SurgeError::AddressFamilyMismatch { .. } => "AddressFamilyMismatch",
+            SurgeError::InvalidSize { .. } => "InvalidSize",
+            SurgeError::EmptyPattern => "EmptyPattern",
+            SurgeError::InvalidChecksum => "InvalidChecksum",
+            SurgeError::PayloadMismatch { .. } => "PayloadMismatch",
+            SurgeError::UnexpectedReply { .. } => "UnexpectedReply",
+            SurgeError::InvalidTimeout => "InvalidTimeout",
+            SurgeError::IcmpError(_) => "IcmpError",
+            SurgeError::IdentInUse { .. } => "IdentInUse",
+            SurgeError::IdentsExhausted => "IdentsExhausted",
+            SurgeError::DestinationInUse { .. } => "DestinationInUse",
+            SurgeError::SendError { .. } => "SendError",
+            SurgeError::NetworkUnreachable { .. } => "NetworkUnreachable",
+            SurgeError::HostUnreachable { .. } => "HostUnreachable",
+            SurgeError::SendPermissionDenied { .. } => "SendPermissionDenied",
+            SurgeError::BroadcastNotEnabled { .. } => "BroadcastNotEnabled",
+            SurgeError::DestinationUnreachable { .. } => "DestinationUnreachable",
+            SurgeError::TimeExceeded { .. } => "TimeExceeded",
+            SurgeError::SourceQuench { .. } => "SourceQuench",
+            SurgeError::RedirectReceived { .. } => "RedirectReceived",
+            SurgeError::ResolutionFailed { .. } => "ResolutionFailed",
+        };
+        let mut state = s.serialize_struct("SurgeError", 2)?;
+        state.serialize_field("kind", name)?;
+        state.serialize_field("message", &self.to_string())?;
+        state.end()
+    }
+}
+
+/// Cause of a Destination Unreachable error (RFC 1812 and RFC 4443 codes),
+/// or of an ICMPv6 Packet Too Big error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnreachableCode {
+    /// Net unreachable, no route for ICMPv6.
+    Net,
+    /// Host unreachable, address unreachable for ICMPv6.
+    Host,
+    Protocol,
+    Port,
+    /// Fragmentation needed and DF set, or Packet Too Big for ICMPv6, with
+    /// the MTU of the next hop if reported.
+    FragmentationNeeded {
+        mtu: Option<u32>,
+    },
+    SourceRouteFailed,
+    NetUnknown,
+    HostUnknown,
+    SourceHostIsolated,
+    NetProhibited,
+    HostProhibited,
+    NetUnreachableForTos,
+    HostUnreachableForTos,
+    /// Communication administratively prohibited.
+    AdminProhibited,
+    HostPrecedenceViolation,
+    PrecedenceCutoff,
+    /// Beyond scope of source address (ICMPv6).
+    BeyondScope,
+    /// Source address failed ingress/egress policy (ICMPv6).
+    SourcePolicyFailed,
+    /// Reject route to destination (ICMPv6).
+    RejectRoute,
+    Other(u8),
+}
+
+impl UnreachableCode {
+    /// The cause of an ICMP Destination Unreachable with `code`, `mtu` being
+    /// the next hop MTU field.
+    pub fn from_icmpv4(code: u8, mtu: u16) -> UnreachableCode {
+        match code {
+            0 => UnreachableCode::Net,
+            1 => UnreachableCode::Host,
+            2 => UnreachableCode::Protocol,
+            3 => UnreachableCode::Port,
+            4 => UnreachableCode::FragmentationNeeded {
+                mtu: Some(u32::from(mtu)).filter(|mtu| *mtu > 0),
+            },
+            5 => UnreachableCode::SourceRouteFailed,
+            6 => UnreachableCode::NetUnknown,
+            7 => UnreachableCode::HostUnknown,
+            8 => UnreachableCode::SourceHostIsolated,
+            9 => UnreachableCode::NetProhibited,
+            10 => UnreachableCode::HostProhibited,
+            11 => UnreachableCode::NetUnreachableForTos,
+            12 => UnreachableCode::HostUnreachableForTos,
+            13 => UnreachableCode::AdminProhibited,
+            14 => UnreachableCode::HostPrecedenceViolation,
+            15 => UnreachableCode::PrecedenceCutoff,
+            code => UnreachableCode::Other(code),
+        }
+    }
+
+    /// The cause of an ICMPv6 Destination Unreachable with `code`.
+    pub fn from_icmpv6(code: u8) -> UnreachableCode {
+        match code {
+            0 => UnreachableCode::Net,
+            1 => UnreachableCode::AdminProhibited,
+            2 => UnreachableCode::BeyondScope,
+            3 => UnreachableCode::Host,
+            4 => UnreachableCode::Port,
+            5 => UnreachableCode::SourcePolicyFailed,
+            6 => UnreachableCode::RejectRoute,
+            code => UnreachableCode::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for UnreachableCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnreachableCode::Net => write!(f, "net unreachable"),
+            UnreachableCode::Host => write!(f, "host unreachable"),
+            UnreachableCode::Protocol => write!(f, "protocol unreachable"),
+            UnreachableCode::Port => write!(f, "port unreachable"),
+            UnreachableCode::FragmentationNeeded { mtu: Some(mtu) } => {
+                write!(f, "frag needed (mtu = {})", mtu)
+            }
+            UnreachableCode::FragmentationNeeded { mtu: None } => write!(f, "frag needed"),
+            UnreachableCode::SourceRouteFailed => write!(f, "source route failed"),
+            UnreachableCode::NetUnknown => write!(f, "destination net unknown"),
+            UnreachableCode::HostUnknown => write!(f, "destination host unknown"),
+            UnreachableCode::SourceHostIsolated => write!(f, "source host isolated"),
+            UnreachableCode::NetProhibited => write!(f, "destination net prohibited"),
+            UnreachableCode::HostProhibited => write!(f, "destination host prohibited"),
+            UnreachableCode::NetUnreachableForTos => {
+                write!(f, "destination net unreachable for TOS")
+            }
+            UnreachableCode::HostUnreachableForTos => {
+                write!(f, "destination host unreachable for TOS")
+            }
+            UnreachableCode::AdminProhibited => write!(f, "communication prohibited by filter"),
+            UnreachableCode::HostPrecedenceViolation => write!(f, "host precedence violation"),
+            UnreachableCode::PrecedenceCutoff => write!(f, "precedence cutoff"),
+            UnreachableCode::BeyondScope => write!(f, "beyond scope of source address"),
+            UnreachableCode::SourcePolicyFailed => write!(f, "source address failed policy"),
+            UnreachableCode::RejectRoute => write!(f, "reject route"),
+            UnreachableCode::Other(code) => write!(f, "code {}", code),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -36,3 +429,128 @@ pub enum MalformedPacketError {
     #[error("payload too short, got {got}, want {want}")]
     PayloadTooShort { got: usize, want: usize },
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const DESTINATION: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn send_error(code: i32) -> SurgeError {
+        SurgeError::from_send(DESTINATION, io::Error::from_raw_os_error(code))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn linux_send_errnos() {
+        assert!(matches!(
+            send_error(libc::ENETUNREACH),
+            SurgeError::NetworkUnreachable { .. }
+        ));
+        assert!(matches!(
+            send_error(libc::EHOSTUNREACH),
+            SurgeError::HostUnreachable { .. }
+        ));
+        assert!(matches!(
+            send_error(libc::EPERM),
+            SurgeError::SendPermissionDenied { .. }
+        ));
+        assert!(matches!(
+            send_error(libc::EACCES),
+            SurgeError::BroadcastNotEnabled { .. }
+        ));
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    #[test]
+    fn bsd_send_errnos() {
+        assert!(matches!(
+            send_error(51),
+            SurgeError::NetworkUnreachable { .. }
+        ));
+        assert!(matches!(send_error(65), SurgeError::HostUnreachable { .. }));
+        assert!(matches!(
+            send_error(1),
+            SurgeError::SendPermissionDenied { .. }
+        ));
+        assert!(matches!(
+            send_error(13),
+            SurgeError::BroadcastNotEnabled { .. }
+        ));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn winsock_send_errnos() {
+        assert!(matches!(
+            send_error(10051),
+            SurgeError::NetworkUnreachable { .. }
+        ));
+        assert!(matches!(
+            send_error(10065),
+            SurgeError::HostUnreachable { .. }
+        ));
+        assert!(matches!(
+            send_error(10013),
+            SurgeError::BroadcastNotEnabled { .. }
+        ));
+    }
+
+    #[test]
+    fn unmapped_send_errno_keeps_the_source() {
+        // EIO on unix, a code outside the Winsock range on Windows.
+        match send_error(5) {
+            SurgeError::SendError {
+                destination,
+                source,
+            } => {
+                assert_eq!(destination, DESTINATION);
+                assert_eq!(source.raw_os_error(), Some(5));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn io_variants_keep_the_io_error() {
+        let io = || io::Error::from_raw_os_error(5);
+        let errors = vec![
+            SurgeError::IOError(io()),
+            SurgeError::PermissionDenied(io()),
+            send_error(5),
+            SurgeError::ResolutionFailed {
+                host: "example.invalid".to_string(),
+                source: Some(io()),
+            },
+        ];
+        for error in errors {
+            let source = std::error::Error::source(&error)
+                .and_then(|source| source.downcast_ref::<io::Error>())
+                .unwrap_or_else(|| panic!("no io::Error source in {:?}", error));
+            assert_eq!(source.raw_os_error(), Some(5));
+            assert_eq!(error.raw_os_error(), Some(5));
+            assert_eq!(error.kind(), Some(source.kind()));
+        }
+    }
+
+    #[test]
+    fn io_error_downcasts_through_anyhow() {
+        fn ping() -> anyhow::Result<()> {
+            Err(send_error(5))?;
+            Ok(())
+        }
+        let error = ping().unwrap_err().context("pinging 192.0.2.1");
+        assert!(matches!(
+            error.downcast_ref::<SurgeError>(),
+            Some(SurgeError::SendError { .. })
+        ));
+        let io = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .expect("io::Error in the chain");
+        assert_eq!(io.raw_os_error(), Some(5));
+        assert!(error.root_cause().is::<io::Error>());
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::net::IpAddr;
+
+#[cfg(target_os = "linux")]
+use std::{io, os::unix::io::RawFd};
+
+#[cfg(target_os = "linux")]
+use crate::icmp::wire;
+#[cfg(target_os = "linux")]
+use crate::kstamp::KernelTimestamp;
+
+/// An ICMP error reported by the kernel through the socket error queue.
+///
+/// Only produced on Linux, for sockets built with
+/// `PingSocketBuilder::set_recv_err`. The kernel matched the error with the
+/// echo request it answers, so the fields are more reliable than those
+/// parsed from the quoted packet of a received ICMP error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IcmpErrorReport {
+    /// Destination of the echo request the error answers.
+    pub destination: IpAddr,
+    /// Host that sent the error, e.g. the router for Time Exceeded.
+    pub offender: Option<IpAddr>,
+    /// ICMP or ICMPv6 type of the error.
+    pub icmp_type: u8,
+    /// ICMP or ICMPv6 code of the error.
+    pub icmp_code: u8,
+    /// Errno the kernel mapped the error to.
+    pub errno: i32,
+    /// Extra info of the error, the next hop MTU for Fragmentation Needed.
+    pub info: u32,
+    /// Identifier of the echo request.
+    pub ident: u16,
+    /// Sequence number of the echo request.
+    pub seq: u16,
+}
+
+impl fmt::Display for IcmpErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ICMP error type {} code {} for icmp_seq {} to {}",
+            self.icmp_type, self.icmp_code, self.seq, self.destination
+        )?;
+        if let Some(offender) = self.offender {
+            write!(f, " from {}", offender)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ask the kernel to queue ICMP errors on the socket (`IP_RECVERR` or
+/// `IPV6_RECVERR`).
+#[cfg(target_os = "linux")]
+pub(crate) fn set_recv_err(fd: RawFd, ipv6: bool, enable: bool) -> io::Result<()> {
+    let (level, name) = if ipv6 {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+    } else {
+        (libc::IPPROTO_IP, libc::IP_RECVERR)
+    };
+    let value = enable as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A message read from the socket error queue.
+#[cfg(target_os = "linux")]
+pub(crate) enum Queued {
+    /// An ICMP error answering an echo request.
+    Error(IcmpErrorReport),
+    /// The send timestamp of a packet, the first `len` bytes of the buffer
+    /// holding the packet as sent.
+    Sent { stamp: KernelTimestamp, len: usize },
+}
+
+/// Read one message from the socket error queue without blocking, into
+/// `data` for the packet it refers to.
+///
+/// Returns `Ok(None)` once the queue is empty. Errors that don't come from
+/// an ICMP message answering an echo request are read and skipped.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_err(fd: RawFd, data: &mut [u8]) -> io::Result<Option<Queued>> {
+    use crate::batch::sockaddr_to_std;
+    use std::mem;
+
+    loop {
+        // For ICMP errors the queued payload is the ICMP header of the
+        // offending echo request.
+        let mut control = [0_u8; 256];
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+
+        let ret = unsafe { libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::WouldBlock => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let len = ret as usize;
+
+        let mut stamp = None;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            if let Some(ts) = unsafe { KernelTimestamp::from_cmsg(cmsg) } {
+                stamp = Some(ts);
+            }
+            let hdr = unsafe { &*cmsg };
+            let queued = (hdr.cmsg_level == libc::IPPROTO_IP && hdr.cmsg_type == libc::IP_RECVERR)
+                || (hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == libc::IPV6_RECVERR);
+            if queued {
+                let ee = unsafe { libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err };
+                let err = unsafe { ee.read_unaligned() };
+                if err.ee_origin == libc::SO_EE_ORIGIN_TIMESTAMPING {
+                    // The timestamp comes in its own control message.
+                    cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+                    continue;
+                }
+                if err.ee_origin != libc::SO_EE_ORIGIN_ICMP
+                    && err.ee_origin != libc::SO_EE_ORIGIN_ICMP6
+                {
+                    stamp = None;
+                    break;
+                }
+                let destination = match sockaddr_to_std(&name) {
+                    Some(addr) if len >= 8 => addr.ip(),
+                    _ => {
+                        stamp = None;
+                        break;
+                    }
+                };
+                let offender = unsafe {
+                    let addr = libc::SO_EE_OFFENDER(ee) as *const libc::sockaddr_storage;
+                    sockaddr_to_std(&addr.read_unaligned()).map(|addr| addr.ip())
+                };
+                let (ident, seq) = wire::read_query_header(data).unwrap_or_default();
+                return Ok(Some(Queued::Error(IcmpErrorReport {
+                    destination,
+                    offender,
+                    icmp_type: err.ee_type,
+                    icmp_code: err.ee_code,
+                    errno: err.ee_errno as i32,
+                    info: err.ee_info,
+                    ident,
+                    seq,
+                })));
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        if let Some(stamp) = stamp {
+            return Ok(Some(Queued::Sent {
+                stamp,
+                len: len.min(data.len()),
+            }));
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+/// Distribution of the round trip times of a `Pinger`, in microseconds,
+/// see `Pinger::record_histogram`.
+///
+/// Timed out requests are counted as losses, not as samples.
+#[derive(Debug, Clone)]
+pub struct RttHistogram {
+    rtts: Histogram<u64>,
+    lost: u64,
+}
+
+impl Default for RttHistogram {
+    fn default() -> Self {
+        RttHistogram::new()
+    }
+}
+
+impl RttHistogram {
+    /// Creates a histogram with 3 significant digits.
+    pub fn new() -> RttHistogram {
+        RttHistogram::with_precision(3)
+    }
+
+    /// Creates a histogram keeping `sigfig` significant digits of each
+    /// value, at most 5. More digits cost more memory.
+    pub fn with_precision(sigfig: u8) -> RttHistogram {
+        RttHistogram {
+            rtts: Histogram::new(sigfig.min(5)).expect("up to 5 significant digits are supported"),
+            lost: 0,
+        }
+    }
+
+    pub(crate) fn record(&mut self, rtt: Duration) {
+        let micros = u64::try_from(rtt.as_micros()).unwrap_or(u64::MAX);
+        self.rtts.saturating_record(micros);
+    }
+
+    pub(crate) fn record_loss(&mut self) {
+        self.lost += 1;
+    }
+
+    /// Number of round trip times recorded.
+    pub fn len(&self) -> u64 {
+        self.rtts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rtts.is_empty()
+    }
+
+    /// Number of requests that timed out.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    /// Round trip time at `quantile`, between 0 and 1, or `None` if nothing
+    /// was recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        let micros = self.rtts.value_at_quantile(quantile.clamp(0f64, 1f64));
+        Some(Duration::from_micros(micros))
+    }
+
+    /// Median round trip time.
+    pub fn p50(&self) -> Option<Duration> {
+        self.quantile(0.5)
+    }
+
+    /// 90th percentile of the round trip times.
+    pub fn p90(&self) -> Option<Duration> {
+        self.quantile(0.9)
+    }
+
+    /// 99th percentile of the round trip times.
+    pub fn p99(&self) -> Option<Duration> {
+        self.quantile(0.99)
+    }
+
+    /// 99.9th percentile of the round trip times.
+    pub fn p999(&self) -> Option<Duration> {
+        self.quantile(0.999)
+    }
+
+    /// The recorded round trip times with their count, in increasing order,
+    /// each value standing for the bucket of equivalent values.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.rtts
+            .iter_recorded()
+            .map(|value| {
+                (
+                    Duration::from_micros(value.value_iterated_to()),
+                    value.count_at_value(),
+                )
+            })
+            .collect()
+    }
+
+    /// Forget everything recorded, e.g. at the start of a reporting window.
+    pub fn reset(&mut self) {
+        self.rtts.reset();
+        self.lost = 0;
+    }
+}
@@ -1,41 +1,143 @@
 use std::convert::TryInto;
 use std::net::Ipv4Addr;
+use std::time::Duration;
 
-use pnet_packet::icmp::{self, IcmpCode, IcmpType};
-use pnet_packet::Packet;
-use pnet_packet::{ipv4, PacketSize};
-
+use super::wire::{self, IcmpHeader, Ipv4Header, IPV4_HEADER_SIZE};
 use crate::error::{MalformedPacketError, Result, SurgeError};
 
-pub fn make_icmpv4_echo_packet(ident: u16, seq_cnt: u16, size: usize) -> Result<Vec<u8>> {
+/// The type of an ICMPv4 message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IcmpType(pub u8);
+
+impl IcmpType {
+    pub const ECHO_REPLY: IcmpType = IcmpType(0);
+    pub const DESTINATION_UNREACHABLE: IcmpType = IcmpType(3);
+    pub const SOURCE_QUENCH: IcmpType = IcmpType(4);
+    pub const REDIRECT: IcmpType = IcmpType(5);
+    pub const ECHO_REQUEST: IcmpType = IcmpType(8);
+    pub const TIME_EXCEEDED: IcmpType = IcmpType(11);
+    pub const PARAMETER_PROBLEM: IcmpType = IcmpType(12);
+    pub const TIMESTAMP: IcmpType = IcmpType(13);
+    pub const TIMESTAMP_REPLY: IcmpType = IcmpType(14);
+    pub const ADDRESS_MASK_REQUEST: IcmpType = IcmpType(17);
+    pub const ADDRESS_MASK_REPLY: IcmpType = IcmpType(18);
+
+    pub const fn new(value: u8) -> IcmpType {
+        IcmpType(value)
+    }
+}
+
+/// The code of an ICMPv4 message, whose meaning depends on its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IcmpCode(pub u8);
+
+impl IcmpCode {
+    pub const fn new(value: u8) -> IcmpCode {
+        IcmpCode(value)
+    }
+}
+
+pub fn make_icmpv4_echo_packet(
+    ident: u16,
+    seq_cnt: u16,
+    size: usize,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
     let mut buf = vec![0; 8 + size]; // 8 bytes of header, then payload
-    let mut packet = icmp::echo_request::MutableEchoRequestPacket::new(&mut buf[..])
-        .ok_or(SurgeError::IncorrectBufferSize)?;
-    packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
-    packet.set_identifier(ident);
-    packet.set_sequence_number(seq_cnt);
-
-    // Calculate and set the checksum
-    let icmp_packet =
-        icmp::IcmpPacket::new(packet.packet()).ok_or(SurgeError::IncorrectBufferSize)?;
-    let checksum = icmp::checksum(&icmp_packet);
-    packet.set_checksum(checksum);
-
-    Ok(packet.packet().to_vec())
+    write_icmpv4_echo_packet(&mut buf, ident, seq_cnt, payload)?;
+    Ok(buf)
+}
+
+/// Encode an echo request into `buf`, which holds the whole ICMP packet (header and payload).
+///
+/// `payload` is copied to the start of the data bytes, truncated to fit, the
+/// remaining data bytes are zero.
+pub fn write_icmpv4_echo_packet(
+    buf: &mut [u8],
+    ident: u16,
+    seq_cnt: u16,
+    payload: &[u8],
+) -> Result<()> {
+    if buf.len() < 8 {
+        return Err(SurgeError::IncorrectBufferSize);
+    }
+    buf.fill(0);
+    let data_len = payload.len().min(buf.len() - 8);
+    buf[8..8 + data_len].copy_from_slice(&payload[..data_len]);
+    wire::write_query_header(buf, IcmpType::ECHO_REQUEST.0, ident, seq_cnt);
+    wire::set_icmp_checksum(buf);
+
+    Ok(())
+}
+
+/// Prepend an IPv4 header without options to the ICMP `message`, as raw
+/// sockets receive it.
+pub(crate) fn with_ipv4_header(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    ttl: u8,
+    message: &[u8],
+) -> Vec<u8> {
+    let mut buf = vec![0; IPV4_HEADER_SIZE + message.len()];
+    wire::write_ipv4_header(&mut buf, source, destination, ttl, message.len());
+    buf[IPV4_HEADER_SIZE..].copy_from_slice(message);
+    buf
+}
+
+/// Encode an ICMP Timestamp request (type 13) with the given originate
+/// timestamp, in milliseconds since midnight UT.
+pub fn make_icmpv4_timestamp_packet(ident: u16, seq_cnt: u16, originate: u32) -> Vec<u8> {
+    let mut data = [0; 12];
+    data[0..4].copy_from_slice(&originate.to_be_bytes());
+    make_icmpv4_query_packet(IcmpType::TIMESTAMP, ident, seq_cnt, &data)
+}
+
+/// Encode an ICMP Address Mask request (type 17).
+pub fn make_icmpv4_address_mask_packet(ident: u16, seq_cnt: u16) -> Vec<u8> {
+    make_icmpv4_query_packet(IcmpType::ADDRESS_MASK_REQUEST, ident, seq_cnt, &[0; 4])
+}
+
+/// Encode an ICMP query message: type, code 0, checksum, identifier and
+/// sequence, followed by `data`.
+fn make_icmpv4_query_packet(icmp_type: IcmpType, ident: u16, seq_cnt: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0; 8 + data.len()];
+    wire::write_query_header(&mut buf, icmp_type.0, ident, seq_cnt);
+    buf[8..].copy_from_slice(data);
+    wire::set_icmp_checksum(&mut buf);
+    buf
+}
+
+/// Reply to an ICMP Timestamp request.
+///
+/// Timestamps are milliseconds since midnight UT, as set by the sender
+/// (`originate`) and by the replying host (`receive`, `transmit`). Hosts
+/// without a UT clock set the high order bit of their timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampReply {
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+    pub rtt: Duration,
 }
 
 /// Packet structure returned by ICMPv4.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Icmpv4Packet {
     source: Ipv4Addr,
     destination: Ipv4Addr,
     ttl: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::icmp_type"))]
     icmp_type: IcmpType,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::icmp_code"))]
     icmp_code: IcmpCode,
     size: usize,
     real_dest: Ipv4Addr,
     identifier: u16,
     sequence: u16,
+    payload: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    raw: Vec<u8>,
 }
 
 impl Default for Icmpv4Packet {
@@ -50,6 +152,8 @@ impl Default for Icmpv4Packet {
             real_dest: Ipv4Addr::new(127, 0, 0, 1),
             identifier: 0,
             sequence: 0,
+            payload: Vec::new(),
+            raw: Vec::new(),
         }
     }
 }
@@ -146,51 +250,228 @@ impl Icmpv4Packet {
         self.sequence
     }
 
+    fn payload(&mut self, payload: &[u8]) -> &mut Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Get the data bytes of an echo or query reply, empty for other packets.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The packet as received: the IPv4 header, options included, followed
+    /// by the whole ICMP message, i.e. a raw IPv4 packet (`LINKTYPE_RAW` or
+    /// `LINKTYPE_IPV4` in a capture).
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Get the originate, receive and transmit timestamps of a Timestamp reply.
+    pub fn get_timestamps(&self) -> Option<(u32, u32, u32)> {
+        if self.icmp_type != IcmpType::TIMESTAMP_REPLY || self.payload.len() < 12 {
+            return None;
+        }
+        let field = |at: usize| u32::from_be_bytes(self.payload[at..at + 4].try_into().unwrap());
+        Some((field(0), field(4), field(8)))
+    }
+
+    /// Get the mask of an Address Mask reply.
+    pub fn get_address_mask(&self) -> Option<Ipv4Addr> {
+        if self.icmp_type != IcmpType::ADDRESS_MASK_REPLY || self.payload.len() < 4 {
+            return None;
+        }
+        let mask: [u8; 4] = self.payload[0..4].try_into().unwrap();
+        Some(Ipv4Addr::from(mask))
+    }
+
     /// Decode into icmp packet from the socket message.
+    ///
+    /// `buf` must start with the IPv4 header, as delivered by a raw IPv4
+    /// socket, followed by the ICMP message:
+    ///
+    /// * an echo reply: 8 bytes ICMP header (type, code, checksum, identifier,
+    ///   sequence), then the echoed data.
+    /// * a timestamp reply: 8 bytes ICMP header, then the originate, receive
+    ///   and transmit timestamps (4 bytes each).
+    /// * an address mask reply: 8 bytes ICMP header, then the 4 bytes mask.
+    /// * any other message (destination unreachable, time exceeded, ...):
+    ///   8 bytes ICMP header, then the original IPv4 header (20 bytes, without
+    ///   options) and at least the first 8 bytes of the original echo request,
+    ///   whose identifier and sequence are reported.
+    ///
+    /// Multi-byte fields are in network byte order. Echo requests are rejected
+    /// with `SurgeError::EchoRequestPacket`, truncated messages with
+    /// `MalformedPacketError` and messages whose ICMP checksum doesn't match
+    /// with `SurgeError::InvalidChecksum`.
     pub fn decode(buf: &[u8]) -> Result<Self> {
-        let ipv4_packet = ipv4::Ipv4Packet::new(buf)
+        Self::decode_checked(buf, true)
+    }
+
+    /// Decode like `decode`, but without verifying the ICMP checksum.
+    pub fn decode_lenient(buf: &[u8]) -> Result<Self> {
+        Self::decode_checked(buf, false)
+    }
+
+    fn decode_checked(buf: &[u8], verify_checksum: bool) -> Result<Self> {
+        let mut packet = Self::decode_message(buf, verify_checksum)?;
+        packet.raw = buf.to_vec();
+        Ok(packet)
+    }
+
+    fn decode_message(buf: &[u8], verify_checksum: bool) -> Result<Self> {
+        let ipv4_packet = Ipv4Header::parse(buf)
             .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIpv4Packet))?;
-        let payload = ipv4_packet.payload();
-        let icmp_packet = icmp::IcmpPacket::new(payload)
+        let icmp_packet = IcmpHeader::parse(ipv4_packet.payload())
             .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIcmpv4Packet))?;
-        match icmp_packet.get_icmp_type() {
-            icmp::IcmpTypes::EchoReply => {
-                let icmp_packet = icmp::echo_reply::EchoReplyPacket::new(payload)
+        if verify_checksum && wire::icmp_checksum(icmp_packet.message()) != icmp_packet.checksum() {
+            return Err(SurgeError::InvalidChecksum);
+        }
+        match IcmpType(icmp_packet.icmp_type()) {
+            IcmpType::ECHO_REPLY => {
+                let (identifier, sequence) = wire::read_query_header(icmp_packet.message())
                     .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIcmpv4Packet))?;
-                let mut packet = Icmpv4Packet::default();
+                let mut packet = Self::decode_header(&ipv4_packet, &icmp_packet);
                 packet
-                    .source(ipv4_packet.get_source())
-                    .destination(ipv4_packet.get_destination())
-                    .ttl(ipv4_packet.get_ttl())
-                    .icmp_type(icmp_packet.get_icmp_type())
-                    .icmp_code(icmp_packet.get_icmp_code())
-                    .size(icmp_packet.packet().len())
-                    .real_dest(ipv4_packet.get_source())
-                    .identifier(icmp_packet.get_identifier())
-                    .sequence(icmp_packet.get_sequence_number());
+                    .real_dest(ipv4_packet.source())
+                    .identifier(identifier)
+                    .sequence(sequence)
+                    .payload(&icmp_packet.payload()[4..]);
                 Ok(packet)
             }
-            icmp::IcmpTypes::EchoRequest => Err(SurgeError::EchoRequestPacket),
+            IcmpType::TIMESTAMP_REPLY => Self::decode_query_reply(&ipv4_packet, &icmp_packet, 12),
+            IcmpType::ADDRESS_MASK_REPLY => Self::decode_query_reply(&ipv4_packet, &icmp_packet, 4),
+            IcmpType::ECHO_REQUEST | IcmpType::TIMESTAMP | IcmpType::ADDRESS_MASK_REQUEST => {
+                Err(SurgeError::EchoRequestPacket)
+            }
             _ => {
                 let icmp_payload = icmp_packet.payload();
-                // icmp unused(4) + ip header(20) + echo icmp(4)
-                let real_ip_packet = ipv4::Ipv4Packet::new(&icmp_payload[4..])
+                if icmp_payload.len() < 32 {
+                    return Err(MalformedPacketError::PayloadTooShort {
+                        got: icmp_payload.len(),
+                        want: 32,
+                    }
+                    .into());
+                }
+                // icmp unused(4) + ip header(20, more with options) + echo icmp(8)
+                let real_ip_packet = Ipv4Header::parse(&icmp_payload[4..])
                     .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIpv4Packet))?;
-                let identifier = u16::from_be_bytes(icmp_payload[28..30].try_into().unwrap());
-                let sequence = u16::from_be_bytes(icmp_payload[30..32].try_into().unwrap());
-                let mut packet = Icmpv4Packet::default();
+                let (identifier, sequence) =
+                    wire::read_query_header(real_ip_packet.payload()).unwrap_or_default();
+                let mut packet = Self::decode_header(&ipv4_packet, &icmp_packet);
                 packet
-                    .source(ipv4_packet.get_source())
-                    .destination(ipv4_packet.get_destination())
-                    .ttl(ipv4_packet.get_ttl())
-                    .icmp_type(icmp_packet.get_icmp_type())
-                    .icmp_code(icmp_packet.get_icmp_code())
-                    .size(icmp_packet.packet_size())
-                    .real_dest(real_ip_packet.get_destination())
+                    .real_dest(real_ip_packet.destination())
                     .identifier(identifier)
                     .sequence(sequence);
                 Ok(packet)
             }
         }
     }
+
+    /// A packet with the fields read from the IPv4 and ICMP headers.
+    fn decode_header(ipv4_packet: &Ipv4Header, icmp_packet: &IcmpHeader) -> Self {
+        let mut packet = Icmpv4Packet::default();
+        packet
+            .source(ipv4_packet.source())
+            .destination(ipv4_packet.destination())
+            .ttl(ipv4_packet.ttl())
+            .icmp_type(IcmpType(icmp_packet.icmp_type()))
+            .icmp_code(IcmpCode(icmp_packet.code()))
+            .size(icmp_packet.message().len());
+        packet
+    }
+
+    /// Decode a reply to an ICMP query, whose header carries the identifier
+    /// and sequence like an echo reply, followed by `data_len` bytes of data.
+    fn decode_query_reply(
+        ipv4_packet: &Ipv4Header,
+        icmp_packet: &IcmpHeader,
+        data_len: usize,
+    ) -> Result<Self> {
+        // identifier(2) + sequence(2) + data
+        let icmp_payload = icmp_packet.payload();
+        if icmp_payload.len() < 4 + data_len {
+            return Err(MalformedPacketError::PayloadTooShort {
+                got: icmp_payload.len(),
+                want: 4 + data_len,
+            }
+            .into());
+        }
+        let (identifier, sequence) =
+            wire::read_query_header(icmp_packet.message()).unwrap_or_default();
+        let mut packet = Self::decode_header(ipv4_packet, icmp_packet);
+        packet
+            .real_dest(ipv4_packet.source())
+            .identifier(identifier)
+            .sequence(sequence)
+            .payload(&icmp_payload[4..4 + data_len]);
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 2);
+
+    /// An echo reply from `SOURCE` with a valid checksum, IPv4 header included.
+    fn echo_reply() -> Vec<u8> {
+        let mut message = make_icmpv4_echo_packet(0x1234, 7, 4, b"ping").unwrap();
+        message[0] = IcmpType::ECHO_REPLY.0;
+        wire::set_icmp_checksum(&mut message);
+        with_ipv4_header(SOURCE, DESTINATION, 64, &message)
+    }
+
+    #[test]
+    fn echo_request_bytes() {
+        let buf = make_icmpv4_echo_packet(0x1234, 1, 6, b"abcd").unwrap();
+        assert_eq!(
+            buf,
+            [0x08, 0x00, 0x21, 0x04, 0x12, 0x34, 0x00, 0x01, 0x61, 0x62, 0x63, 0x64, 0x00, 0x00,]
+        );
+    }
+
+    #[test]
+    fn timestamp_request_bytes() {
+        let buf = make_icmpv4_timestamp_packet(0x1234, 2, 0x0102_0304);
+        assert_eq!(
+            buf,
+            [
+                0x0d, 0x00, 0xdc, 0xc3, 0x12, 0x34, 0x00, 0x02, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn address_mask_request_bytes() {
+        let buf = make_icmpv4_address_mask_packet(0x1234, 3);
+        assert_eq!(
+            buf,
+            [0x11, 0x00, 0xdc, 0xc8, 0x12, 0x34, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn decode_accepts_valid_checksum() {
+        let packet = Icmpv4Packet::decode(&echo_reply()).unwrap();
+        assert_eq!(packet.get_identifier(), 0x1234);
+        assert_eq!(packet.get_sequence(), 7);
+        assert_eq!(packet.get_payload(), b"ping");
+    }
+
+    #[test]
+    fn decode_rejects_corrupted_reply() {
+        for at in IPV4_HEADER_SIZE..echo_reply().len() {
+            let mut buf = echo_reply();
+            buf[at] ^= 0x01;
+            assert!(
+                matches!(Icmpv4Packet::decode(&buf), Err(SurgeError::InvalidChecksum)),
+                "byte {} flipped",
+                at
+            );
+        }
+    }
 }
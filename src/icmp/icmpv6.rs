@@ -1,43 +1,88 @@
-use std::convert::TryInto;
 use std::net::Ipv6Addr;
 
-use pnet_packet::icmpv6::{self, Icmpv6Code, Icmpv6Type};
-use pnet_packet::Packet;
-use pnet_packet::PacketSize;
-
+use super::wire::{self, IcmpHeader};
 use crate::error::{MalformedPacketError, Result, SurgeError};
 
-#[allow(dead_code)]
-pub fn make_icmpv6_echo_packet(ident: u16, seq_cnt: u16, size: usize) -> Result<Vec<u8>> {
+/// The type of an ICMPv6 message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Icmpv6Type(pub u8);
+
+impl Icmpv6Type {
+    pub const DESTINATION_UNREACHABLE: Icmpv6Type = Icmpv6Type(1);
+    pub const PACKET_TOO_BIG: Icmpv6Type = Icmpv6Type(2);
+    pub const TIME_EXCEEDED: Icmpv6Type = Icmpv6Type(3);
+    pub const PARAMETER_PROBLEM: Icmpv6Type = Icmpv6Type(4);
+    pub const ECHO_REQUEST: Icmpv6Type = Icmpv6Type(128);
+    pub const ECHO_REPLY: Icmpv6Type = Icmpv6Type(129);
+
+    pub const fn new(value: u8) -> Icmpv6Type {
+        Icmpv6Type(value)
+    }
+}
+
+/// The code of an ICMPv6 message, whose meaning depends on its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Icmpv6Code(pub u8);
+
+impl Icmpv6Code {
+    pub const fn new(value: u8) -> Icmpv6Code {
+        Icmpv6Code(value)
+    }
+}
+
+pub fn make_icmpv6_echo_packet(
+    ident: u16,
+    seq_cnt: u16,
+    size: usize,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
     let mut buf = vec![0u8; 4 + 2 + 2 + size]; // 4 bytes ICMP header + 2 bytes ident + 2 bytes sequence, then payload
-    let mut packet =
-        icmpv6::MutableIcmpv6Packet::new(&mut buf[..]).ok_or(SurgeError::IncorrectBufferSize)?;
-    packet.set_icmpv6_type(icmpv6::Icmpv6Types::EchoRequest);
+    write_icmpv6_echo_packet(&mut buf, ident, seq_cnt, payload)?;
+    Ok(buf)
+}
 
-    // Encode the identifier and sequence directly in the payload
-    let mut payload = vec![0; 4];
-    payload[0..2].copy_from_slice(&ident.to_be_bytes()[..]);
-    payload[2..4].copy_from_slice(&seq_cnt.to_be_bytes()[..]);
-    packet.set_payload(&payload);
+/// Encode an echo request into `buf`, which holds the whole ICMPv6 packet (header and payload).
+///
+/// `payload` is copied to the start of the data bytes, truncated to fit, the
+/// remaining data bytes are zero.
+pub fn write_icmpv6_echo_packet(
+    buf: &mut [u8],
+    ident: u16,
+    seq_cnt: u16,
+    payload: &[u8],
+) -> Result<()> {
+    if buf.len() < 8 {
+        return Err(SurgeError::IncorrectBufferSize);
+    }
+    buf.fill(0);
+    let data_len = payload.len().min(buf.len() - 8);
+    buf[8..8 + data_len].copy_from_slice(&payload[..data_len]);
+    wire::write_query_header(buf, Icmpv6Type::ECHO_REQUEST.0, ident, seq_cnt);
 
     // Per https://tools.ietf.org/html/rfc3542#section-3.1 the checksum is
     // omitted, the kernel will insert it.
 
-    Ok(packet.packet().to_vec())
+    Ok(())
 }
 
 /// Packet structure returned by ICMPv6.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Icmpv6Packet {
     source: Ipv6Addr,
     destination: Ipv6Addr,
     max_hop_limit: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::icmpv6_type"))]
     icmpv6_type: Icmpv6Type,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::icmpv6_code"))]
     icmpv6_code: Icmpv6Code,
     size: usize,
     real_dest: Ipv6Addr,
     identifier: u16,
     sequence: u16,
+    payload: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    raw: Vec<u8>,
 }
 
 impl Default for Icmpv6Packet {
@@ -52,6 +97,8 @@ impl Default for Icmpv6Packet {
             real_dest: Ipv6Addr::LOCALHOST,
             identifier: 0,
             sequence: 0,
+            payload: Vec::new(),
+            raw: Vec::new(),
         }
     }
 }
@@ -148,53 +195,176 @@ impl Icmpv6Packet {
         self.sequence
     }
 
+    fn payload(&mut self, payload: &[u8]) -> &mut Self {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    /// Get the data bytes of an echo reply, empty for other packets.
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// The packet as received: the ICMPv6 message alone, starting with its
+    /// type, as raw IPv6 sockets don't deliver the IPv6 header. Dissect it
+    /// as ICMPv6, or prepend an IPv6 header to capture it as raw IP.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
     /// Decode into icmpv6 packet from the socket message.
+    ///
+    /// `buf` must start with the ICMPv6 header, raw IPv6 sockets don't deliver
+    /// the IPv6 header, so the peer address is passed as `destination`:
+    ///
+    /// * an echo reply: 4 bytes ICMPv6 header (type, code, checksum), 2 bytes
+    ///   identifier, 2 bytes sequence, then the echoed data.
+    /// * any other message (destination unreachable, time exceeded, ...):
+    ///   4 bytes ICMPv6 header, 4 unused bytes, then the original IPv6 header
+    ///   (40 bytes) and at least the first 8 bytes of the original echo
+    ///   request, whose identifier and sequence are reported.
+    ///
+    /// Multi-byte fields are in network byte order. Echo requests are rejected
+    /// with `SurgeError::EchoRequestPacket`, truncated messages with
+    /// `MalformedPacketError`.
+    ///
+    /// The ICMPv6 checksum covers a pseudo-header with both addresses and
+    /// isn't verified here: raw ICMPv6 sockets only deliver messages whose
+    /// checksum the kernel verified. For captured packets use
+    /// `verify_checksum`.
     pub fn decode(buf: &[u8], destination: Ipv6Addr) -> Result<Self> {
-        log::info!("{:?}", buf);
-        // let ipv6_packet = ipv6::Ipv6Packet::new(buf)
-        //     .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIpv4Packet))?;
-        // let payload = ipv6_packet.payload();
-        let icmpv6_packet = icmpv6::Icmpv6Packet::new(buf)
+        let mut packet = Self::decode_message(buf, destination)?;
+        packet.raw = buf.to_vec();
+        Ok(packet)
+    }
+
+    fn decode_message(buf: &[u8], destination: Ipv6Addr) -> Result<Self> {
+        let icmpv6_packet = IcmpHeader::parse(buf)
             .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIcmpv6Packet))?;
-        log::info!("{:?}", &icmpv6_packet);
-        log::info!("{:?}", &icmpv6_packet.get_icmpv6_type());
-        log::info!("{:?}", icmpv6::Icmpv6Types::EchoReply);
         let icmpv6_payload = icmpv6_packet.payload();
-        match icmpv6_packet.get_icmpv6_type() {
-            icmpv6::Icmpv6Types::EchoRequest => Err(SurgeError::EchoRequestPacket),
-            icmpv6::Icmpv6Types::EchoReply => {
-                let identifier = u16::from_be_bytes(icmpv6_payload[0..2].try_into().unwrap());
-                let sequence = u16::from_be_bytes(icmpv6_payload[2..4].try_into().unwrap());
+        match Icmpv6Type(icmpv6_packet.icmp_type()) {
+            Icmpv6Type::ECHO_REQUEST => Err(SurgeError::EchoRequestPacket),
+            Icmpv6Type::ECHO_REPLY => {
+                if icmpv6_payload.len() < 4 {
+                    return Err(MalformedPacketError::PayloadTooShort {
+                        got: icmpv6_payload.len(),
+                        want: 4,
+                    }
+                    .into());
+                }
+                let (identifier, sequence) =
+                    wire::read_query_header(icmpv6_packet.message()).unwrap_or_default();
                 let mut packet = Icmpv6Packet::default();
                 packet
                     .source(destination)
                     .destination(Ipv6Addr::LOCALHOST)
                     .max_hop_limit(0)
-                    .icmpv6_type(icmpv6_packet.get_icmpv6_type())
-                    .icmpv6_code(icmpv6_packet.get_icmpv6_code())
-                    .size(icmpv6_packet.packet().len())
+                    .icmpv6_type(Icmpv6Type(icmpv6_packet.icmp_type()))
+                    .icmpv6_code(Icmpv6Code(icmpv6_packet.code()))
+                    .size(buf.len())
                     .real_dest(destination)
                     .identifier(identifier)
-                    .sequence(sequence);
+                    .sequence(sequence)
+                    .payload(&icmpv6_payload[4..]);
                 Ok(packet)
             }
             _ => {
-                // ipv6 header(40) + icmpv6 echo header(4)
-                log::info!("{:?}", icmpv6_payload);
-                let identifier = u16::from_be_bytes(icmpv6_payload[44..46].try_into().unwrap());
-                let sequence = u16::from_be_bytes(icmpv6_payload[46..48].try_into().unwrap());
+                // unused(4) + ipv6 header(40) + icmpv6 echo header(4)
+                if icmpv6_payload.len() < 52 {
+                    return Err(MalformedPacketError::PayloadTooShort {
+                        got: icmpv6_payload.len(),
+                        want: 52,
+                    }
+                    .into());
+                }
+                let (identifier, sequence) =
+                    wire::read_query_header(&icmpv6_payload[44..]).unwrap_or_default();
                 let mut packet = Icmpv6Packet::default();
                 packet
                     .source(destination)
                     .destination(destination)
                     .max_hop_limit(0)
-                    .icmpv6_type(icmpv6_packet.get_icmpv6_type())
-                    .icmpv6_code(icmpv6_packet.get_icmpv6_code())
-                    .size(icmpv6_packet.packet_size())
+                    .icmpv6_type(Icmpv6Type(icmpv6_packet.icmp_type()))
+                    .icmpv6_code(Icmpv6Code(icmpv6_packet.code()))
+                    .size(buf.len())
                     .identifier(identifier)
                     .sequence(sequence);
                 Ok(packet)
             }
         }
     }
+
+    /// Verify the checksum of the ICMPv6 message in `buf` sent from `source`
+    /// to `destination`.
+    pub fn verify_checksum(buf: &[u8], source: Ipv6Addr, destination: Ipv6Addr) -> Result<()> {
+        let icmpv6_packet = IcmpHeader::parse(buf)
+            .ok_or_else(|| SurgeError::from(MalformedPacketError::NotIcmpv6Packet))?;
+        if wire::icmpv6_checksum(buf, source, destination) != icmpv6_packet.checksum() {
+            return Err(SurgeError::InvalidChecksum);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    const DESTINATION: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2);
+
+    /// An echo reply from `SOURCE` to `DESTINATION` with a valid checksum.
+    fn echo_reply() -> Vec<u8> {
+        let mut buf = make_icmpv6_echo_packet(0x1234, 7, 4, b"ping").unwrap();
+        buf[0] = Icmpv6Type::ECHO_REPLY.0;
+        let checksum = wire::icmpv6_checksum(&buf, SOURCE, DESTINATION);
+        buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn echo_request_bytes() {
+        // The kernel fills in the checksum.
+        let buf = make_icmpv6_echo_packet(0x1234, 1, 6, b"abcd").unwrap();
+        assert_eq!(
+            buf,
+            [0x80, 0x00, 0x00, 0x00, 0x12, 0x34, 0x00, 0x01, 0x61, 0x62, 0x63, 0x64, 0x00, 0x00,]
+        );
+    }
+
+    #[test]
+    fn verify_checksum_accepts_valid_reply() {
+        let buf = echo_reply();
+        Icmpv6Packet::verify_checksum(&buf, SOURCE, DESTINATION).unwrap();
+        let packet = Icmpv6Packet::decode(&buf, SOURCE).unwrap();
+        assert_eq!(packet.get_identifier(), 0x1234);
+        assert_eq!(packet.get_sequence(), 7);
+        assert_eq!(packet.get_payload(), b"ping");
+    }
+
+    #[test]
+    fn verify_checksum_rejects_corrupted_reply() {
+        for at in 0..echo_reply().len() {
+            let mut buf = echo_reply();
+            buf[at] ^= 0x01;
+            assert!(
+                matches!(
+                    Icmpv6Packet::verify_checksum(&buf, SOURCE, DESTINATION),
+                    Err(SurgeError::InvalidChecksum)
+                ),
+                "byte {} flipped",
+                at
+            );
+        }
+    }
+
+    #[test]
+    fn verify_checksum_covers_the_addresses() {
+        let buf = echo_reply();
+        let other = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 3);
+        assert!(matches!(
+            Icmpv6Packet::verify_checksum(&buf, other, DESTINATION),
+            Err(SurgeError::InvalidChecksum)
+        ));
+    }
 }
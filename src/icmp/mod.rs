@@ -1,10 +1,102 @@
+//! ICMP echo packet encoding and reply decoding.
+//!
+//! The decoders can be used on their own to parse ICMP messages captured
+//! elsewhere (pcap, eBPF, ...), see `Icmpv4Packet::decode` and
+//! `Icmpv6Packet::decode` for the expected byte layouts.
 use std::net::IpAddr;
 
+use crate::error::{Result, SurgeError, UnreachableCode};
+
 pub mod icmpv4;
 pub mod icmpv6;
+pub(crate) mod wire;
+
+/// Size of the ICMP echo header (type, code, checksum, identifier, sequence).
+pub(crate) const ICMP_HEADER_SIZE: usize = 8;
+/// Largest echo payload fitting an IPv4 datagram (65535 - 20 IP header - 8 ICMP header).
+pub(crate) const MAX_ICMPV4_PAYLOAD: usize = 65535 - 20 - ICMP_HEADER_SIZE;
+/// Largest echo payload fitting an IPv6 packet without jumbograms (65535 - 8 ICMPv6 header).
+pub(crate) const MAX_ICMPV6_PAYLOAD: usize = 65535 - ICMP_HEADER_SIZE;
+
+/// Size of the largest IPv4 header, with options.
+const MAX_IPV4_HEADER_SIZE: usize = 60;
+
+/// Size of the packet received for the reply to an echo payload of `size`
+/// bytes sent to `destination`, with room for IPv4 options.
+pub(crate) fn reply_packet_size(destination: IpAddr, size: usize) -> usize {
+    match destination {
+        IpAddr::V4(_) => MAX_IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + size,
+        IpAddr::V6(_) => ICMP_HEADER_SIZE + size,
+    }
+}
+
+/// Largest echo payload to `destination` whose reply fits a receive buffer of
+/// `recv_packet_size` bytes, assuming IPv4 replies without IP options.
+pub(crate) fn max_payload_size(destination: IpAddr, recv_packet_size: usize) -> usize {
+    match destination {
+        IpAddr::V4(_) => {
+            MAX_ICMPV4_PAYLOAD.min(recv_packet_size.saturating_sub(20 + ICMP_HEADER_SIZE))
+        }
+        IpAddr::V6(_) => {
+            MAX_ICMPV6_PAYLOAD.min(recv_packet_size.saturating_sub(ICMP_HEADER_SIZE))
+        }
+    }
+}
+
+/// Check that an echo payload of `size` bytes can be sent to `destination`,
+/// and its reply received in a buffer of `recv_packet_size` bytes.
+pub(crate) fn check_payload_size(
+    destination: IpAddr,
+    size: usize,
+    recv_packet_size: usize,
+) -> Result<()> {
+    let max = max_payload_size(destination, recv_packet_size);
+    if size > max {
+        return Err(SurgeError::InvalidSize {
+            requested: size,
+            min: 0,
+            max,
+        });
+    }
+    Ok(())
+}
+
+/// Peek the identifier of the echo request a received packet answers,
+/// quoted in the payload of ICMP errors, without decoding the packet.
+///
+/// IPv4 packets start with the IP header, IPv6 packets with the ICMPv6
+/// header, as received from raw sockets.
+pub(crate) fn peek_ident(packet: &[u8], ipv6: bool) -> Option<u16> {
+    fn ip_payload(packet: &[u8]) -> Option<&[u8]> {
+        packet.get(usize::from(packet.first()? & 0x0f) * 4..)
+    }
+    let ident_of = |icmp: &[u8]| wire::read_u16(icmp, 4);
+    let icmp = if ipv6 { packet } else { ip_payload(packet)? };
+    // Echo, timestamp and address mask replies carry the identifier, errors
+    // quote the IP header and the start of the request after their header.
+    let is_error = if ipv6 {
+        *icmp.first()? < 128
+    } else {
+        !matches!(icmp.first()?, 0 | 14 | 18)
+    };
+    if !is_error {
+        return ident_of(icmp);
+    }
+    let quoted = icmp.get(ICMP_HEADER_SIZE..)?;
+    if ipv6 {
+        ident_of(quoted.get(40..)?)
+    } else {
+        ident_of(ip_payload(quoted)?)
+    }
+}
 
 /// Represents the ICMP reply packet.
+///
+/// The fields common to ICMPv4 and ICMPv6 are read without matching the
+/// variant, e.g. `get_source`, `get_sequence`, `get_ttl` and `get_size`.
+/// Match it for the fields of one version only.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum IcmpPacket {
     /// An ICMPv4 packet abstraction.
     V4(icmpv4::Icmpv4Packet),
@@ -13,6 +105,170 @@ pub enum IcmpPacket {
 }
 
 impl IcmpPacket {
+    /// The echoed data bytes of an echo reply.
+    pub(crate) fn echo_payload(&self) -> &[u8] {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_payload(),
+            IcmpPacket::V6(packet) => packet.get_payload(),
+        }
+    }
+
+    /// Whether the packet is an echo reply rather than an ICMP error.
+    pub(crate) fn is_echo_reply(&self) -> bool {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_icmp_type() == icmpv4::IcmpType::ECHO_REPLY,
+            IcmpPacket::V6(packet) => packet.get_icmpv6_type() == icmpv6::Icmpv6Type::ECHO_REPLY,
+        }
+    }
+
+    /// The ICMP type of the packet.
+    pub(crate) fn icmp_type(&self) -> u8 {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_icmp_type().0,
+            IcmpPacket::V6(packet) => packet.get_icmpv6_type().0,
+        }
+    }
+
+    /// The ICMP code of the packet.
+    pub(crate) fn icmp_code(&self) -> u8 {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_icmp_code().0,
+            IcmpPacket::V6(packet) => packet.get_icmpv6_code().0,
+        }
+    }
+
+    /// The size of the ICMP message, header included.
+    #[doc(alias = "size")]
+    pub fn get_size(&self) -> usize {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_size(),
+            IcmpPacket::V6(packet) => packet.get_size(),
+        }
+    }
+
+    /// The source address of the packet.
+    #[doc(alias = "source")]
+    pub fn get_source(&self) -> IpAddr {
+        match self {
+            IcmpPacket::V4(packet) => IpAddr::V4(packet.get_source()),
+            IcmpPacket::V6(packet) => IpAddr::V6(packet.get_source()),
+        }
+    }
+
+    /// The TTL of an IPv4 packet or the hop limit of an IPv6 packet, `None`
+    /// when the socket didn't report it.
+    #[doc(alias = "hops")]
+    #[doc(alias = "hop_limit")]
+    pub fn get_ttl(&self) -> Option<u8> {
+        let ttl = match self {
+            IcmpPacket::V4(packet) => packet.get_ttl(),
+            IcmpPacket::V6(packet) => packet.get_max_hop_limit(),
+        };
+        match ttl {
+            0 => None,
+            ttl => Some(ttl),
+        }
+    }
+
+    /// The sequence number of the request the packet answers.
+    #[doc(alias = "sequence")]
+    pub fn get_sequence(&self) -> u16 {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_sequence(),
+            IcmpPacket::V6(packet) => packet.get_sequence(),
+        }
+    }
+
+    /// The identifier of the request the packet answers.
+    pub fn get_identifier(&self) -> u16 {
+        match self {
+            IcmpPacket::V4(packet) => packet.get_identifier(),
+            IcmpPacket::V6(packet) => packet.get_identifier(),
+        }
+    }
+
+    /// The data bytes of an echo reply, empty for ICMP errors.
+    pub fn get_payload(&self) -> &[u8] {
+        self.echo_payload()
+    }
+
+    /// The packet as received, see `Icmpv4Packet::as_bytes` and
+    /// `Icmpv6Packet::as_bytes` for the bytes included.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            IcmpPacket::V4(packet) => packet.as_bytes(),
+            IcmpPacket::V6(packet) => packet.as_bytes(),
+        }
+    }
+
+    /// The 4 bytes following the type, code and checksum of the ICMP header,
+    /// holding the next hop MTU or the gateway of some errors.
+    fn rest_of_header(&self) -> Option<[u8; 4]> {
+        let icmp = match self {
+            IcmpPacket::V4(packet) => {
+                let raw = packet.as_bytes();
+                raw.get(usize::from(raw.first()? & 0x0f) * 4..)?
+            }
+            IcmpPacket::V6(packet) => packet.as_bytes(),
+        };
+        let rest = icmp.get(4..8)?;
+        Some([rest[0], rest[1], rest[2], rest[3]])
+    }
+
+    /// The error an ICMP error message stands for, `None` for replies and
+    /// errors without a dedicated `SurgeError` variant.
+    pub(crate) fn to_error(&self) -> Option<SurgeError> {
+        let (icmp_type, icmp_code, from) = (self.icmp_type(), self.icmp_code(), self.get_source());
+        let rest = self.rest_of_header().unwrap_or_default();
+        let mtu = u16::from_be_bytes([rest[2], rest[3]]);
+        let error = match (self, icmp_type) {
+            (IcmpPacket::V4(_), 3) => SurgeError::DestinationUnreachable {
+                code: UnreachableCode::from_icmpv4(icmp_code, mtu),
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            (IcmpPacket::V4(_), 4) => SurgeError::SourceQuench {
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            (IcmpPacket::V4(_), 5) => SurgeError::RedirectReceived {
+                gateway: IpAddr::from(rest),
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            (IcmpPacket::V4(_), 11) | (IcmpPacket::V6(_), 3) => SurgeError::TimeExceeded {
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            (IcmpPacket::V6(_), 1) => SurgeError::DestinationUnreachable {
+                code: UnreachableCode::from_icmpv6(icmp_code),
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            (IcmpPacket::V6(_), 2) => SurgeError::DestinationUnreachable {
+                code: UnreachableCode::FragmentationNeeded {
+                    mtu: Some(u32::from_be_bytes(rest)),
+                },
+                from,
+                icmp_type,
+                icmp_code,
+            },
+            _ => return None,
+        };
+        Some(error)
+    }
+
+    /// Whether the packet answers request `seq_cnt` of `identifier`, from
+    /// any source.
+    pub(crate) fn matches_request(&self, seq_cnt: u16, identifier: u16) -> bool {
+        self.get_sequence() == seq_cnt && self.get_identifier() == identifier
+    }
+
     /// Check reply Icmp packet is corret.
     pub fn check_reply_packet(&self, destination: IpAddr, seq_cnt: u16, identifier: u16) -> bool {
         match self {
@@ -27,3 +283,78 @@ impl IcmpPacket {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::icmpv4::Icmpv4Packet;
+    use super::icmpv6::Icmpv6Packet;
+    use super::*;
+
+    /// Echo reply of 8.8.8.8 to 192.168.1.10, as read from a raw IPv4
+    /// socket: identifier 0x3a2b, sequence 1, 16 bytes of data.
+    #[rustfmt::skip]
+    const IPV4_ECHO_REPLY: [u8; 44] = [
+        0x45, 0x00, 0x00, 0x2c, 0x8f, 0x1c, 0x00, 0x00, 0x75, 0x01, 0xe4, 0xf2,
+        0x08, 0x08, 0x08, 0x08, 0xc0, 0xa8, 0x01, 0x0a, 0x00, 0x00, 0x0d, 0x13,
+        0x3a, 0x2b, 0x00, 0x01, 0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+        0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+    ];
+
+    /// Echo reply of 2001:4860:4860::8888 to 2001:db8::10, as read from a
+    /// raw IPv6 socket: identifier 0x3a2b, sequence 1, 16 bytes of data.
+    #[rustfmt::skip]
+    const IPV6_ECHO_REPLY: [u8; 24] = [
+        0x81, 0x00, 0x24, 0xad, 0x3a, 0x2b, 0x00, 0x01, 0x10, 0x11, 0x12, 0x13,
+        0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+    ];
+
+    #[test]
+    fn decode_ipv4_echo_reply() {
+        let source = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+        let packet = IcmpPacket::V4(Icmpv4Packet::decode(&IPV4_ECHO_REPLY).unwrap());
+        assert!(packet.is_echo_reply());
+        assert_eq!(packet.get_source(), source);
+        assert_eq!(packet.get_identifier(), 0x3a2b);
+        assert_eq!(packet.get_sequence(), 1);
+        assert_eq!(packet.get_payload(), (0x10..0x20).collect::<Vec<u8>>());
+        assert_eq!(packet.get_size(), 24);
+        assert_eq!(packet.as_bytes(), IPV4_ECHO_REPLY);
+        assert!(packet.check_reply_packet(source, 1, 0x3a2b));
+        assert!(packet.to_error().is_none());
+    }
+
+    #[test]
+    fn decode_ipv6_echo_reply() {
+        let source = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+        let destination = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x10);
+        Icmpv6Packet::verify_checksum(&IPV6_ECHO_REPLY, source, destination).unwrap();
+        let packet = IcmpPacket::V6(Icmpv6Packet::decode(&IPV6_ECHO_REPLY, source).unwrap());
+        assert!(packet.is_echo_reply());
+        assert_eq!(packet.get_source(), IpAddr::V6(source));
+        assert_eq!(packet.get_identifier(), 0x3a2b);
+        assert_eq!(packet.get_sequence(), 1);
+        assert_eq!(packet.get_payload(), (0x10..0x20).collect::<Vec<u8>>());
+        assert_eq!(packet.get_size(), 24);
+        assert_eq!(packet.as_bytes(), IPV6_ECHO_REPLY);
+        assert!(packet.check_reply_packet(IpAddr::V6(source), 1, 0x3a2b));
+        assert!(packet.to_error().is_none());
+    }
+
+    #[test]
+    fn ttl_of_decoded_replies() {
+        let packet = IcmpPacket::V4(Icmpv4Packet::decode(&IPV4_ECHO_REPLY).unwrap());
+        assert_eq!(packet.get_ttl(), Some(117));
+        // Raw IPv6 sockets don't deliver the hop limit with the message.
+        let source = Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888);
+        let packet = IcmpPacket::V6(Icmpv6Packet::decode(&IPV6_ECHO_REPLY, source).unwrap());
+        assert_eq!(packet.get_ttl(), None);
+    }
+
+    #[test]
+    fn peek_ident_of_echo_replies() {
+        assert_eq!(peek_ident(&IPV4_ECHO_REPLY, false), Some(0x3a2b));
+        assert_eq!(peek_ident(&IPV6_ECHO_REPLY, true), Some(0x3a2b));
+    }
+}
@@ -0,0 +1,231 @@
+//! Byte level encoding and decoding of the IPv4 and ICMP headers, straight
+//! from and to slices with explicit bounds checks.
+//!
+//! Multi-byte fields are in network byte order.
+use std::convert::TryInto;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Size of an IPv4 header without options.
+pub(crate) const IPV4_HEADER_SIZE: usize = 20;
+/// Protocol number of ICMP in the IPv4 header.
+const IPPROTO_ICMP: u8 = 1;
+/// Next header number of ICMPv6 in the IPv6 pseudo-header.
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// Read the big endian `u16` at `at`, `None` if `buf` is too short.
+pub(crate) fn read_u16(buf: &[u8], at: usize) -> Option<u16> {
+    let bytes = buf.get(at..at.checked_add(2)?)?;
+    Some(u16::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Add the 16 bits words of `data` to `sum`, the odd byte padded with zero.
+fn sum_words(data: &[u8], mut sum: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(u16::from_be_bytes([*last, 0]));
+    }
+    sum
+}
+
+/// Fold the carries of `sum` and complement it.
+fn finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The Internet checksum (RFC 1071) of `data`.
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    finish(sum_words(data, 0))
+}
+
+/// The checksum of the ICMP message `message`, computed as if its checksum
+/// field was zero.
+pub(crate) fn icmp_checksum(message: &[u8]) -> u16 {
+    let head = &message[..message.len().min(2)];
+    let tail = message.get(4..).unwrap_or_default();
+    finish(sum_words(tail, sum_words(head, 0)))
+}
+
+/// The checksum of the ICMPv6 message `message` sent from `source` to
+/// `destination`, covering the IPv6 pseudo-header (RFC 4443), computed as if
+/// its checksum field was zero.
+pub(crate) fn icmpv6_checksum(message: &[u8], source: Ipv6Addr, destination: Ipv6Addr) -> u16 {
+    let mut sum = sum_words(&source.octets(), 0);
+    sum = sum_words(&destination.octets(), sum);
+    sum = sum_words(&(message.len() as u32).to_be_bytes(), sum);
+    sum += u32::from(IPPROTO_ICMPV6);
+    let head = &message[..message.len().min(2)];
+    sum = sum_words(head, sum);
+    finish(sum_words(message.get(4..).unwrap_or_default(), sum))
+}
+
+/// Write the 8 bytes header of an ICMP query message (echo, timestamp,
+/// address mask) to the start of `buf`: type, code 0, checksum zero,
+/// identifier and sequence number.
+///
+/// `buf` must be at least 8 bytes long.
+pub(crate) fn write_query_header(buf: &mut [u8], icmp_type: u8, ident: u16, seq_cnt: u16) {
+    buf[0] = icmp_type;
+    buf[1] = 0;
+    buf[2..4].fill(0);
+    buf[4..6].copy_from_slice(&ident.to_be_bytes());
+    buf[6..8].copy_from_slice(&seq_cnt.to_be_bytes());
+}
+
+/// Read the identifier and sequence number of the ICMP query message
+/// `message`, as written by `write_query_header`. `None` if `message` is
+/// shorter than the 8 bytes header.
+pub(crate) fn read_query_header(message: &[u8]) -> Option<(u16, u16)> {
+    Some((read_u16(message, 4)?, read_u16(message, 6)?))
+}
+
+/// Compute the checksum of the ICMP message `buf` and store it in its
+/// header.
+///
+/// `buf` must be at least 4 bytes long.
+pub(crate) fn set_icmp_checksum(buf: &mut [u8]) {
+    let checksum = icmp_checksum(buf);
+    buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Write an IPv4 header without options carrying an ICMP message of
+/// `payload_len` bytes to the start of `buf`.
+///
+/// `buf` must be at least 20 bytes long.
+pub(crate) fn write_ipv4_header(
+    buf: &mut [u8],
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    ttl: u8,
+    payload_len: usize,
+) {
+    let header = &mut buf[..IPV4_HEADER_SIZE];
+    header.fill(0);
+    // Version 4, header length of 5 words.
+    header[0] = 0x45;
+    let total_length = (IPV4_HEADER_SIZE + payload_len).min(usize::from(u16::MAX)) as u16;
+    header[2..4].copy_from_slice(&total_length.to_be_bytes());
+    header[8] = ttl;
+    header[9] = IPPROTO_ICMP;
+    header[12..16].copy_from_slice(&source.octets());
+    header[16..20].copy_from_slice(&destination.octets());
+    let checksum = checksum(header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// An IPv4 header at the start of a packet.
+pub(crate) struct Ipv4Header<'a> {
+    packet: &'a [u8],
+    header_len: usize,
+}
+
+impl<'a> Ipv4Header<'a> {
+    /// Parse the header at the start of `packet`, `None` if `packet` is
+    /// shorter than the header, options included.
+    pub(crate) fn parse(packet: &'a [u8]) -> Option<Ipv4Header<'a>> {
+        let header_len = usize::from(packet.first()? & 0x0f) * 4;
+        if header_len < IPV4_HEADER_SIZE || packet.len() < header_len {
+            return None;
+        }
+        Some(Ipv4Header { packet, header_len })
+    }
+
+    pub(crate) fn ttl(&self) -> u8 {
+        self.packet[8]
+    }
+
+    pub(crate) fn source(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.packet[12..16].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    pub(crate) fn destination(&self) -> Ipv4Addr {
+        let octets: [u8; 4] = self.packet[16..20].try_into().unwrap();
+        Ipv4Addr::from(octets)
+    }
+
+    /// The bytes following the header, up to the total length when it fits
+    /// the packet.
+    pub(crate) fn payload(&self) -> &'a [u8] {
+        let total_length = usize::from(read_u16(self.packet, 2).unwrap_or_default());
+        let end = if (self.header_len..=self.packet.len()).contains(&total_length) {
+            total_length
+        } else {
+            self.packet.len()
+        };
+        &self.packet[self.header_len..end]
+    }
+}
+
+/// The header of an ICMP or ICMPv6 message.
+pub(crate) struct IcmpHeader<'a> {
+    message: &'a [u8],
+}
+
+impl<'a> IcmpHeader<'a> {
+    /// Parse the message `message`, `None` if it is shorter than the type,
+    /// code and checksum.
+    pub(crate) fn parse(message: &'a [u8]) -> Option<IcmpHeader<'a>> {
+        if message.len() < 4 {
+            return None;
+        }
+        Some(IcmpHeader { message })
+    }
+
+    pub(crate) fn icmp_type(&self) -> u8 {
+        self.message[0]
+    }
+
+    pub(crate) fn code(&self) -> u8 {
+        self.message[1]
+    }
+
+    pub(crate) fn checksum(&self) -> u16 {
+        u16::from_be_bytes([self.message[2], self.message[3]])
+    }
+
+    /// The whole message, header included.
+    pub(crate) fn message(&self) -> &'a [u8] {
+        self.message
+    }
+
+    /// The bytes following the type, code and checksum.
+    pub(crate) fn payload(&self) -> &'a [u8] {
+        &self.message[4..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDARIES: [u16; 4] = [0, 0x00ff, 0xff00, u16::MAX];
+
+    #[test]
+    fn query_header_round_trips() {
+        for &ident in &BOUNDARIES {
+            for &seq in &BOUNDARIES {
+                let mut buf = [0xaa; 8];
+                write_query_header(&mut buf, 8, ident, seq);
+                assert_eq!(read_query_header(&buf), Some((ident, seq)));
+            }
+        }
+    }
+
+    #[test]
+    fn query_header_is_big_endian() {
+        let mut buf = [0; 8];
+        write_query_header(&mut buf, 8, 0x1234, 0xabcd);
+        assert_eq!(buf, [8, 0, 0, 0, 0x12, 0x34, 0xab, 0xcd]);
+    }
+
+    #[test]
+    fn short_query_header_is_rejected() {
+        assert_eq!(read_query_header(&[8, 0, 0, 0, 0x12, 0x34, 0xab]), None);
+    }
+}
@@ -0,0 +1,380 @@
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc::channel;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::NetworkManagement::IpHelper::{
+    Icmp6CreateFile, Icmp6SendEcho2, IcmpCloseHandle, IcmpCreateFile, IcmpSendEcho2,
+    ICMPV6_ECHO_REPLY_LH, ICMP_ECHO_REPLY, IP_OPTION_INFORMATION,
+};
+use windows_sys::Win32::Networking::WinSock::{AF_INET6, SOCKADDR_IN6};
+
+use crate::clock::{Clock, TokioClock};
+use crate::error::Result;
+use crate::icmp::icmpv4::{with_ipv4_header, IcmpType};
+use crate::icmp::icmpv6::Icmpv6Type;
+use crate::icmp::wire;
+use crate::kstamp::KernelTimestamp;
+use crate::ping::{Pinger, PingerConfig};
+use crate::pingsocket::MAX_RECV_PACKET_SIZE;
+use crate::pmap::PingerMap;
+use crate::transport::{IoFuture, Marking, Transport};
+
+// Status codes of the replies (IP_STATUS).
+const IP_SUCCESS: u32 = 0;
+const IP_DEST_NET_UNREACHABLE: u32 = 11002;
+const IP_DEST_HOST_UNREACHABLE: u32 = 11003;
+const IP_DEST_PROT_UNREACHABLE: u32 = 11004;
+const IP_DEST_PORT_UNREACHABLE: u32 = 11005;
+const IP_PACKET_TOO_BIG: u32 = 11009;
+const IP_TTL_EXPIRED_TRANSIT: u32 = 11013;
+const IP_TTL_EXPIRED_REASSEM: u32 = 11014;
+const IP_DEST_UNREACHABLE: u32 = 11040;
+const IP_TIME_EXCEEDED: u32 = 11041;
+
+/// Extra room the reply buffers need besides the reply and its data: an
+/// ICMP error and an `IO_STATUS_BLOCK`.
+const REPLY_SLACK: usize = 8 + 16;
+
+/// A handle of the ICMP helper API, closed on drop.
+struct IcmpHandle(HANDLE);
+
+// The handle can be used from any thread.
+unsafe impl Send for IcmpHandle {}
+unsafe impl Sync for IcmpHandle {}
+
+impl IcmpHandle {
+    fn open(ipv6: bool) -> io::Result<IcmpHandle> {
+        let handle = unsafe {
+            if ipv6 {
+                Icmp6CreateFile()
+            } else {
+                IcmpCreateFile()
+            }
+        };
+        if handle == 0 || handle == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(IcmpHandle(handle))
+    }
+}
+
+impl Drop for IcmpHandle {
+    fn drop(&mut self) {
+        unsafe {
+            IcmpCloseHandle(self.0);
+        }
+    }
+}
+
+/// A transport sending echo requests with `IcmpSendEcho2` and
+/// `Icmp6SendEcho2` from `iphlpapi`, which unlike raw sockets don't require
+/// administrator privileges. Windows only.
+///
+/// Each request blocks a thread of tokio's blocking pool until its reply or
+/// the transport's timeout.
+///
+/// # Examples
+/// ```no_run
+/// use surge_ping::IcmpApiTransport;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let transport = IcmpApiTransport::new()?;
+///     let mut pinger = transport.pinger("114.114.114.114".parse()?);
+///     println!("{:?}", pinger.ping(0).await?);
+///     Ok(())
+/// }
+/// ```
+#[derive(Clone)]
+pub struct IcmpApiTransport {
+    inner: Arc<ApiInner>,
+}
+
+struct ApiInner {
+    v4: IcmpHandle,
+    v6: IcmpHandle,
+    ttl: Mutex<u32>,
+    timeout: Mutex<Duration>,
+    clock: Arc<dyn Clock>,
+    pmap: Arc<PingerMap>,
+}
+
+impl IcmpApiTransport {
+    pub fn new() -> Result<IcmpApiTransport> {
+        Ok(IcmpApiTransport {
+            inner: Arc::new(ApiInner {
+                v4: IcmpHandle::open(false)?,
+                v6: IcmpHandle::open(true)?,
+                ttl: Mutex::new(64),
+                timeout: Mutex::new(Duration::from_secs(2)),
+                clock: Arc::new(TokioClock),
+                pmap: Arc::new(PingerMap::new()),
+            }),
+        })
+    }
+
+    /// Creates a pinger to `destination` sending through this transport.
+    pub fn pinger(&self, destination: IpAddr) -> Pinger {
+        let config = PingerConfig::default();
+        let (tx, rx) = channel(config.channel_capacity);
+        let registration = self.inner.pmap.insert(destination, config.ident, tx);
+        Pinger::new_pinger(
+            destination,
+            Arc::new(self.clone()),
+            rx,
+            config,
+            registration,
+            None,
+        )
+    }
+
+    /// Set how long a request waits for its reply in the helper API, which
+    /// bounds how long it holds a blocking thread. (default: 2 seconds)
+    ///
+    /// The pinger's own timeout applies independently.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self.inner.timeout.lock() = timeout;
+    }
+}
+
+impl ApiInner {
+    /// Send the echo request `request` to `destination` and wait for its
+    /// reply, returning the address and the ICMP message of the reply as a
+    /// raw socket receives it, `None` if it timed out.
+    fn send_echo(
+        &self,
+        request: &[u8],
+        destination: IpAddr,
+        marking: Marking,
+    ) -> Option<(IpAddr, Vec<u8>)> {
+        let data = &request[8..];
+        let ttl = *self.ttl.lock();
+        let timeout = self.timeout.lock().as_millis().min(u128::from(u32::MAX)) as u32;
+        let options = IP_OPTION_INFORMATION {
+            Ttl: marking.ttl.unwrap_or(ttl.min(255) as u8),
+            Tos: marking.tos.unwrap_or(0),
+            Flags: 0,
+            OptionsSize: 0,
+            OptionsData: std::ptr::null_mut(),
+        };
+        match destination {
+            IpAddr::V4(destination) => {
+                let mut reply =
+                    vec![0u8; mem::size_of::<ICMP_ECHO_REPLY>() + data.len() + REPLY_SLACK];
+                let count = unsafe {
+                    IcmpSendEcho2(
+                        self.v4.0,
+                        0,
+                        None,
+                        std::ptr::null(),
+                        u32::from_ne_bytes(destination.octets()),
+                        data.as_ptr().cast(),
+                        data.len() as u16,
+                        &options,
+                        reply.as_mut_ptr().cast(),
+                        reply.len() as u32,
+                        timeout,
+                    )
+                };
+                if count == 0 {
+                    let status = io::Error::last_os_error().raw_os_error()? as u32;
+                    let message = error_message(status, false, request, IpAddr::V4(destination))?;
+                    // The helper API doesn't tell who reported the error.
+                    let from = IpAddr::V4(destination);
+                    return Some((
+                        from,
+                        with_ipv4_header(destination, Ipv4Addr::UNSPECIFIED, 0, &message),
+                    ));
+                }
+                let echo = unsafe { (reply.as_ptr() as *const ICMP_ECHO_REPLY).read_unaligned() };
+                let from = Ipv4Addr::from(echo.Address.to_ne_bytes());
+                let message = if echo.Status == IP_SUCCESS {
+                    let echoed = unsafe {
+                        std::slice::from_raw_parts(
+                            echo.Data as *const u8,
+                            usize::from(echo.DataSize),
+                        )
+                    };
+                    echo_reply(false, request, echoed)
+                } else {
+                    error_message(echo.Status, false, request, IpAddr::V4(destination))?
+                };
+                Some((
+                    IpAddr::V4(from),
+                    with_ipv4_header(from, Ipv4Addr::UNSPECIFIED, echo.Options.Ttl, &message),
+                ))
+            }
+            IpAddr::V6(destination) => {
+                let source = sockaddr_in6(Ipv6Addr::UNSPECIFIED);
+                let target = sockaddr_in6(destination);
+                let header = mem::size_of::<ICMPV6_ECHO_REPLY_LH>();
+                let mut reply = vec![0u8; header + data.len() + REPLY_SLACK];
+                let count = unsafe {
+                    Icmp6SendEcho2(
+                        self.v6.0,
+                        0,
+                        None,
+                        std::ptr::null(),
+                        &source,
+                        &target,
+                        data.as_ptr().cast(),
+                        data.len() as u16,
+                        &options,
+                        reply.as_mut_ptr().cast(),
+                        reply.len() as u32,
+                        timeout,
+                    )
+                };
+                if count == 0 {
+                    let status = io::Error::last_os_error().raw_os_error()? as u32;
+                    let message = error_message(status, true, request, IpAddr::V6(destination))?;
+                    return Some((IpAddr::V6(destination), message));
+                }
+                let echo =
+                    unsafe { (reply.as_ptr() as *const ICMPV6_ECHO_REPLY_LH).read_unaligned() };
+                let words = echo.Address.sin6_addr;
+                let from = Ipv6Addr::from(words.map(u16::from_be));
+                let message = if echo.Status == IP_SUCCESS {
+                    let end = (header + data.len()).min(reply.len());
+                    echo_reply(true, request, &reply[header..end])
+                } else {
+                    error_message(echo.Status, true, request, IpAddr::V6(destination))?
+                };
+                Some((IpAddr::V6(from), message))
+            }
+        }
+    }
+}
+
+/// A `SOCKADDR_IN6` of `addr`.
+fn sockaddr_in6(addr: Ipv6Addr) -> SOCKADDR_IN6 {
+    let mut sockaddr: SOCKADDR_IN6 = unsafe { mem::zeroed() };
+    sockaddr.sin6_family = AF_INET6;
+    sockaddr.sin6_addr.u.Byte = addr.octets();
+    sockaddr
+}
+
+/// The echo reply to `request` echoing `echoed`.
+fn echo_reply(ipv6: bool, request: &[u8], echoed: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + echoed.len());
+    message.extend_from_slice(&request[..8]);
+    message.extend_from_slice(echoed);
+    if ipv6 {
+        message[0] = Icmpv6Type::ECHO_REPLY.0;
+    } else {
+        message[0] = IcmpType::ECHO_REPLY.0;
+        wire::set_icmp_checksum(&mut message);
+    }
+    message
+}
+
+/// The ICMP error for reply status `status`, quoting `request` sent to
+/// `destination`, `None` for timeouts and statuses without an ICMP
+/// counterpart.
+fn error_message(status: u32, ipv6: bool, request: &[u8], destination: IpAddr) -> Option<Vec<u8>> {
+    let (icmp_type, code) = match (ipv6, status) {
+        (false, IP_DEST_NET_UNREACHABLE) => (3, 0),
+        (false, IP_DEST_HOST_UNREACHABLE) => (3, 1),
+        (false, IP_DEST_PROT_UNREACHABLE) => (3, 2),
+        (false, IP_DEST_PORT_UNREACHABLE) => (3, 3),
+        (false, IP_PACKET_TOO_BIG) => (3, 4),
+        (false, IP_TTL_EXPIRED_TRANSIT) => (11, 0),
+        (false, IP_TTL_EXPIRED_REASSEM) => (11, 1),
+        (true, IP_DEST_NET_UNREACHABLE) => (1, 0),
+        (true, IP_DEST_UNREACHABLE) | (true, IP_DEST_HOST_UNREACHABLE) => (1, 3),
+        (true, IP_DEST_PORT_UNREACHABLE) => (1, 4),
+        (true, IP_PACKET_TOO_BIG) => (2, 0),
+        (true, IP_TIME_EXCEEDED) | (true, IP_TTL_EXPIRED_TRANSIT) => (3, 0),
+        (true, IP_TTL_EXPIRED_REASSEM) => (3, 1),
+        _ => return None,
+    };
+    let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+    match destination {
+        IpAddr::V4(destination) => {
+            let quoted = with_ipv4_header(Ipv4Addr::UNSPECIFIED, destination, 0, &request[..8]);
+            message.extend_from_slice(&quoted);
+            wire::set_icmp_checksum(&mut message);
+        }
+        IpAddr::V6(destination) => {
+            let mut quoted = [0; 40];
+            quoted[0] = 0x60;
+            quoted[24..40].copy_from_slice(&destination.octets());
+            message.extend_from_slice(&quoted);
+            message.extend_from_slice(&request[..8]);
+        }
+    }
+    Some(message)
+}
+
+impl Transport for IcmpApiTransport {
+    fn send_to<'a>(&'a self, packet: &'a [u8], target: SocketAddr) -> IoFuture<'a, usize> {
+        self.send_marked(packet, target, Marking::default())
+    }
+
+    fn send_marked<'a>(
+        &'a self,
+        packet: &'a [u8],
+        target: SocketAddr,
+        marking: Marking,
+    ) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            if packet.len() < 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ICMP message too short",
+                ));
+            }
+            let request = packet.to_vec();
+            let inner = self.inner.clone();
+            let destination = target.ip();
+            crate::rt::spawn_blocking(move || {
+                if let Some((from, reply)) = inner.send_echo(&request, destination, marking) {
+                    let now = inner.clock.now();
+                    inner
+                        .pmap
+                        .dispatch(&destination, None, from, now, None, &reply);
+                }
+            });
+            Ok(packet.len())
+        })
+    }
+
+    fn connect(&self, _target: SocketAddr) -> IoFuture<'_, bool> {
+        Box::pin(async { Ok(false) })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "the ICMP helper API has no local address",
+        ))
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        *self.inner.ttl.lock() = ttl;
+        Ok(())
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        Ok(*self.inner.ttl.lock())
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.clock.now()
+    }
+
+    fn take_tx_timestamp(&self, _ident: u16, _seq: u16) -> Option<KernelTimestamp> {
+        None
+    }
+
+    fn recv_packet_size(&self) -> usize {
+        MAX_RECV_PACKET_SIZE
+    }
+
+    fn reserve_recv_packet_size(&self, _size: usize) {}
+}
@@ -0,0 +1,91 @@
+use std::collections::HashSet;
+use std::sync::{Arc, OnceLock};
+
+use parking_lot::Mutex;
+use rand::random;
+
+use crate::error::{Result, SurgeError};
+
+/// ICMP identifiers in use by the pingers of one socket, or by all the
+/// pingers owning their socket.
+pub(crate) struct IdentRegistry {
+    used: Mutex<HashSet<u16>>,
+}
+
+impl IdentRegistry {
+    pub fn new() -> Arc<IdentRegistry> {
+        Arc::new(IdentRegistry {
+            used: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// The registry shared by the pingers owning their socket.
+    pub fn global() -> &'static Arc<IdentRegistry> {
+        static GLOBAL: OnceLock<Arc<IdentRegistry>> = OnceLock::new();
+        GLOBAL.get_or_init(IdentRegistry::new)
+    }
+
+    /// Claim `ident` if it was chosen by the user (`fixed`), any free
+    /// identifier preferably `ident` otherwise.
+    pub fn claim_for(self: &Arc<Self>, ident: u16, fixed: bool) -> Result<IdentLease> {
+        if fixed {
+            self.claim(ident)
+        } else {
+            self.claim_any(ident)
+        }
+    }
+
+    /// Claim `ident`, failing with `SurgeError::IdentInUse` if another
+    /// pinger holds it.
+    pub fn claim(self: &Arc<Self>, ident: u16) -> Result<IdentLease> {
+        if !self.used.lock().insert(ident) {
+            return Err(SurgeError::IdentInUse { ident });
+        }
+        Ok(IdentLease {
+            registry: self.clone(),
+            ident,
+        })
+    }
+
+    /// Claim `preferred` if it is free, any free identifier otherwise.
+    pub fn claim_any(self: &Arc<Self>, preferred: u16) -> Result<IdentLease> {
+        let mut used = self.used.lock();
+        let start = if used.contains(&preferred) {
+            random()
+        } else {
+            preferred
+        };
+        let ident = (0..=u16::MAX)
+            .map(|offset| start.wrapping_add(offset))
+            .find(|ident| !used.contains(ident))
+            .ok_or(SurgeError::IdentsExhausted)?;
+        used.insert(ident);
+        Ok(IdentLease {
+            registry: self.clone(),
+            ident,
+        })
+    }
+}
+
+/// An identifier claimed in an `IdentRegistry`, released on drop.
+pub(crate) struct IdentLease {
+    registry: Arc<IdentRegistry>,
+    ident: u16,
+}
+
+impl IdentLease {
+    pub fn ident(&self) -> u16 {
+        self.ident
+    }
+
+    /// Claim `ident` in the same registry, see `IdentRegistry::claim`.
+    pub fn claim(&self, ident: u16) -> Result<IdentLease> {
+        self.registry.claim(ident)
+    }
+}
+
+impl Drop for IdentLease {
+    fn drop(&mut self) {
+        self.registry.used.lock().remove(&self.ident);
+    }
+}
@@ -0,0 +1,195 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::icmp::wire;
+
+#[cfg(target_os = "linux")]
+use std::{io, os::unix::io::RawFd};
+
+/// Number of sent requests remembered until their send timestamp is read,
+/// and of send timestamps kept until their reply arrives.
+const MAX_TX_STAMPS: usize = 1024;
+
+/// A timestamp taken by the kernel or the NIC (`SO_TIMESTAMPING`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct KernelTimestamp {
+    /// Taken by the kernel, since the Unix epoch.
+    software: Option<Duration>,
+    /// Taken by the NIC, in its own clock.
+    hardware: Option<Duration>,
+}
+
+impl KernelTimestamp {
+    /// Parse the timestamps of an `SCM_TIMESTAMPING` control message, `None`
+    /// for other control messages.
+    ///
+    /// # Safety
+    /// `cmsg` must point to a valid control message.
+    #[cfg(target_os = "linux")]
+    pub(crate) unsafe fn from_cmsg(cmsg: *const libc::cmsghdr) -> Option<KernelTimestamp> {
+        let hdr = &*cmsg;
+        if hdr.cmsg_level != libc::SOL_SOCKET || hdr.cmsg_type != libc::SCM_TIMESTAMPING {
+            return None;
+        }
+        // struct scm_timestamping: software, deprecated, raw hardware.
+        let ts = (libc::CMSG_DATA(cmsg) as *const [libc::timespec; 3]).read_unaligned();
+        let duration = |ts: &libc::timespec| {
+            (ts.tv_sec != 0 || ts.tv_nsec != 0)
+                .then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+        };
+        Some(KernelTimestamp {
+            software: duration(&ts[0]),
+            hardware: duration(&ts[2]),
+        })
+    }
+
+    /// Time from `sent` to this timestamp, measured with hardware timestamps
+    /// when both have one, with software ones otherwise.
+    pub(crate) fn since(&self, sent: &KernelTimestamp) -> Option<Duration> {
+        match (self.hardware, sent.hardware) {
+            (Some(received), Some(sent)) => received.checked_sub(sent),
+            _ => self.software?.checked_sub(sent.software?),
+        }
+    }
+}
+
+/// Send timestamps of the requests, matched to their identifier and
+/// sequence number.
+#[derive(Default)]
+pub(crate) struct TxStamps {
+    /// Identifier, sequence number and length of the requests sent whose
+    /// timestamp wasn't read yet.
+    pending: VecDeque<(u16, u16, usize)>,
+    stamps: HashMap<(u16, u16), KernelTimestamp>,
+    /// Keys of `stamps`, oldest first.
+    stamped: VecDeque<(u16, u16)>,
+}
+
+impl TxStamps {
+    /// Remember that the ICMP message `packet` is being sent.
+    pub(crate) fn sending(&mut self, packet: &[u8]) {
+        let (ident, seq) = match wire::read_query_header(packet) {
+            Some(header) => header,
+            None => return,
+        };
+        if self.pending.len() == MAX_TX_STAMPS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((ident, seq, packet.len()));
+    }
+
+    /// Record `stamp` for the request `looped` was sent for.
+    ///
+    /// The kernel loops the sent packet back with its link and IP headers,
+    /// so the request is found by comparing the end of the packet with the
+    /// pending requests.
+    pub(crate) fn stamped(&mut self, looped: &[u8], stamp: KernelTimestamp) {
+        let matches = |&(ident, seq, len): &(u16, u16, usize)| {
+            let start = match looped.len().checked_sub(len) {
+                Some(start) => start,
+                None => return false,
+            };
+            wire::read_query_header(&looped[start..]) == Some((ident, seq))
+        };
+        let index = match self.pending.iter().position(matches) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some((ident, seq, _)) = self.pending.remove(index) {
+            self.take(ident, seq);
+            // Evict the oldest timestamp, whose reply most likely timed out.
+            if self.stamps.len() == MAX_TX_STAMPS {
+                if let Some(oldest) = self.stamped.pop_front() {
+                    self.stamps.remove(&oldest);
+                }
+            }
+            self.stamps.insert((ident, seq), stamp);
+            self.stamped.push_back((ident, seq));
+        }
+    }
+
+    /// Take the send timestamp of the request `seq` of `ident`.
+    pub(crate) fn take(&mut self, ident: u16, seq: u16) -> Option<KernelTimestamp> {
+        let stamp = self.stamps.remove(&(ident, seq))?;
+        if let Some(index) = self.stamped.iter().position(|&key| key == (ident, seq)) {
+            self.stamped.remove(index);
+        }
+        Some(stamp)
+    }
+}
+
+/// Ask the kernel to timestamp the packets sent and received on the socket
+/// (`SO_TIMESTAMPING`), in hardware when the NIC supports it.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_timestamping(fd: RawFd, enable: bool) -> io::Result<()> {
+    let flags = if enable {
+        libc::SOF_TIMESTAMPING_SOFTWARE
+            | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+            | libc::SOF_TIMESTAMPING_RX_HARDWARE
+            | libc::SOF_TIMESTAMPING_TX_HARDWARE
+    } else {
+        0
+    };
+    let value = flags as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPING,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(ident: u16, seq: u16) -> [u8; 8] {
+        let mut packet = [0; 8];
+        wire::write_query_header(&mut packet, 8, ident, seq);
+        packet
+    }
+
+    fn stamp(secs: u64) -> KernelTimestamp {
+        KernelTimestamp {
+            software: Some(Duration::from_secs(secs)),
+            hardware: None,
+        }
+    }
+
+    #[test]
+    fn full_stamps_evict_the_oldest() {
+        let mut stamps = TxStamps::default();
+        for seq in 0..=MAX_TX_STAMPS as u16 {
+            let packet = request(1, seq);
+            stamps.sending(&packet);
+            stamps.stamped(&packet, stamp(u64::from(seq)));
+        }
+        assert_eq!(stamps.take(1, 0), None);
+        assert_eq!(stamps.take(1, 1), Some(stamp(1)));
+        assert_eq!(
+            stamps.take(1, MAX_TX_STAMPS as u16),
+            Some(stamp(MAX_TX_STAMPS as u64))
+        );
+    }
+
+    #[test]
+    fn looped_packet_matches_by_its_tail() {
+        let mut stamps = TxStamps::default();
+        let packet = request(7, 9);
+        stamps.sending(&packet);
+        let mut looped = vec![0x45; 34];
+        looped.extend_from_slice(&packet);
+        stamps.stamped(&looped, stamp(3));
+        assert_eq!(stamps.take(7, 9), Some(stamp(3)));
+        assert_eq!(stamps.take(7, 9), None);
+    }
+}
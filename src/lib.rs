@@ -1,10 +1,65 @@
+/// Emit a `tracing` event at `$level` when the `tracing` feature is enabled,
+/// compiled out otherwise.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)+);
+    };
+}
+
+mod batch;
+#[cfg(feature = "blocking")]
+mod blocking;
+mod clock;
+#[cfg(feature = "dns")]
+mod dns;
 mod error;
-mod icmp;
+mod errqueue;
+#[cfg(feature = "hdrhistogram")]
+mod histogram;
+pub mod icmp;
+#[cfg(windows)]
+mod icmpapi;
+mod ident;
+mod kstamp;
+mod meter;
 mod ping;
 mod pingsocket;
+mod pmap;
+mod pool;
+mod probe;
+mod result;
+mod rt;
+#[cfg(feature = "serde")]
+mod serde_util;
+mod stats;
+mod sweep;
+mod tap;
+mod timestamp;
+mod transport;
 
-pub use error::SurgeError;
-pub use icmp::icmpv4::Icmpv4Packet;
+#[cfg(feature = "blocking")]
+pub use blocking::BlockingPinger;
+pub use clock::{Clock, SystemClock, TokioClock};
+#[cfg(feature = "dns")]
+pub use dns::resolve_host;
+pub use error::{MalformedPacketError, SurgeError, UnreachableCode};
+pub use errqueue::IcmpErrorReport;
+#[cfg(feature = "hdrhistogram")]
+pub use histogram::RttHistogram;
+pub use icmp::icmpv4::{Icmpv4Packet, TimestampReply};
+pub use icmp::icmpv6::Icmpv6Packet;
 pub use icmp::IcmpPacket;
-pub use ping::Pinger;
-pub use pingsocket::{PingSocket, PingSocketBuilder};
+#[cfg(windows)]
+pub use icmpapi::IcmpApiTransport;
+#[cfg(feature = "metrics")]
+pub use meter::set_metrics_target_labels;
+pub use ping::{PingOptions, Pinger, PingerBuilder, ProbeSender};
+pub use pingsocket::{DualPingSocket, PingSocket, PingSocketBuilder, SocketKind};
+pub use probe::{PingEvent, PingReply, SentProbe};
+pub use result::PingResult;
+pub use stats::{PingStats, PingSummary};
+pub use sweep::{sweep, Sweep, SweepOptions};
+pub use tap::Tap;
+pub use timestamp::RttSource;
+pub use transport::MockTransport;
@@ -0,0 +1,104 @@
+//! Counters and histograms of the `metrics` feature, empty without it.
+//!
+//! Every metric carries a `family` label, `ipv4` or `ipv6`, and a `target`
+//! label with the destination address once enabled with
+//! `set_metrics_target_labels`.
+use std::net::IpAddr;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::error::SurgeError;
+
+#[cfg(feature = "metrics")]
+static TARGET_LABELS: AtomicBool = AtomicBool::new(false);
+
+/// Label the metrics with the address of their destination. (default: false)
+///
+/// Each destination becomes a time series of its own, only enable it for a
+/// bounded number of targets.
+#[cfg(feature = "metrics")]
+pub fn set_metrics_target_labels(enable: bool) {
+    TARGET_LABELS.store(enable, Ordering::Relaxed);
+}
+
+#[cfg(feature = "metrics")]
+fn labels(target: IpAddr) -> Vec<metrics::Label> {
+    let family = match target {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    };
+    let mut labels = vec![metrics::Label::new("family", family)];
+    if TARGET_LABELS.load(Ordering::Relaxed) {
+        labels.push(metrics::Label::new("target", target.to_string()));
+    }
+    labels
+}
+
+/// Count a request sent to `target`.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn sent(target: IpAddr) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("surge_ping_sent_total", 1, &labels(target));
+}
+
+/// Count an echo reply from `target` and record its round trip time.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn received(target: IpAddr, rtt: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        let labels = labels(target);
+        metrics::counter!("surge_ping_received_total", 1, &labels);
+        metrics::histogram!("surge_ping_rtt_seconds", rtt.as_secs_f64(), &labels);
+    }
+}
+
+/// Count a request to `target` that timed out.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn timed_out(target: IpAddr) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("surge_ping_timeout_total", 1, &labels(target));
+}
+
+/// Count the ICMP error answering a request to `target`, labelled with its
+/// `type`. Other errors aren't counted.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn icmp_error(target: IpAddr, error: &SurgeError) {
+    #[cfg(feature = "metrics")]
+    if let Some(icmp_type) = icmp_error_type(target, error) {
+        let mut labels = labels(target);
+        labels.push(metrics::Label::new("type", icmp_type));
+        metrics::counter!("surge_ping_icmp_error_total", 1, &labels);
+    }
+}
+
+/// Count a reply from `target` dropped because its pinger's channel was
+/// full.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn recv_dropped(target: IpAddr) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!("surge_ping_recv_dropped_total", 1, &labels(target));
+}
+
+/// Name of the ICMP error type of `error`, `None` if it isn't an ICMP error.
+#[cfg(feature = "metrics")]
+fn icmp_error_type(target: IpAddr, error: &SurgeError) -> Option<&'static str> {
+    let icmp_type = match error {
+        SurgeError::DestinationUnreachable { icmp_type, .. }
+        | SurgeError::TimeExceeded { icmp_type, .. }
+        | SurgeError::SourceQuench { icmp_type, .. }
+        | SurgeError::RedirectReceived { icmp_type, .. } => *icmp_type,
+        SurgeError::IcmpError(report) => report.icmp_type,
+        _ => return None,
+    };
+    let name = match (target.is_ipv6(), icmp_type) {
+        (false, 3) | (true, 1) => "destination_unreachable",
+        (false, 4) => "source_quench",
+        (false, 5) => "redirect",
+        (false, 11) | (true, 3) => "time_exceeded",
+        (false, 12) | (true, 4) => "parameter_problem",
+        (true, 2) => "packet_too_big",
+        _ => "other",
+    };
+    Some(name)
+}
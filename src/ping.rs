@@ -1,41 +1,187 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    future::Future,
     io,
-    net::{IpAddr, SocketAddr},
-    sync::Arc,
-    time::{Duration, Instant},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use log::{trace, warn};
 use parking_lot::Mutex;
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{random, Rng, RngCore, SeedableRng};
 use tokio::sync::mpsc::Receiver;
-use tokio::time::timeout;
+use tokio_stream::Stream;
 
-use crate::error::{Result, SurgeError};
-use crate::icmp::{icmpv4, icmpv6, IcmpPacket};
-use crate::pingsocket::{AsyncSocket, PingResponse};
+use crate::error::{MalformedPacketError, Result, SurgeError};
+#[cfg(feature = "hdrhistogram")]
+use crate::histogram::RttHistogram;
+use crate::icmp::icmpv4::TimestampReply;
+use crate::icmp::{
+    check_payload_size, icmpv4, icmpv6, max_payload_size, reply_packet_size, IcmpPacket,
+    ICMP_HEADER_SIZE,
+};
+use crate::ident::IdentLease;
+use crate::meter;
+use crate::pingsocket::{PingResponse, PingSocket, MAX_RECV_PACKET_SIZE};
+use crate::pmap::{Registration, Rekey};
+use crate::probe::{interval_stream, summary, PingEvent, PingReply, Replies, SentProbe};
+use crate::result::{PingResult, ReplyMeta};
+use crate::rt::{self, timeout, timeout_at, Sleep};
+use crate::stats::{PingSummary, RttEstimator};
+use crate::timestamp::{
+    read_nonce, read_timestamp, write_timestamp, RttSource, NONCE_SIZE, TIMESTAMP_SIZE,
+};
+use crate::transport::{Marking, Transport};
 
 type Token = (u16, u16);
 
+/// Number of answered requests remembered to detect duplicate replies, and
+/// of timed out requests remembered to detect late replies.
+const MAX_COMPLETED: usize = 1024;
+
+/// Called with the sequence number and RTT of a reply that arrived after its
+/// request timed out.
+type LateReplyCallback = Box<dyn FnMut(u16, Duration) + Send + Sync>;
+
+/// Sequence numbers and send times of the probes sent by `send_probe`, in send order.
+type ProbeQueue = Arc<Mutex<VecDeque<(u16, Instant)>>>;
+
+/// Number of locks the outstanding requests are spread over, so that sends
+/// and receives of different requests rarely contend.
+const CACHE_SHARDS: usize = 16;
+
+/// Number of requests in a shard above which those past their deadline are
+/// dropped, e.g. when `ping` futures are dropped before their reply.
+const CACHE_PURGE_THRESHOLD: usize = 1024;
+
+/// An outstanding request.
+#[derive(Debug, Clone, Copy)]
+struct CacheEntry {
+    sent: Instant,
+    /// When the request can't be answered anymore.
+    deadline: Instant,
+    /// Nonce sent in the payload, if any.
+    nonce: Option<u64>,
+}
+
+type CacheShard = Mutex<HashMap<Token, CacheEntry>>;
+
+/// Send time and payload nonce of the outstanding requests, sharded by
+/// identifier and sequence number.
 #[derive(Debug, Clone)]
-struct Cache {
-    inner: Arc<Mutex<HashMap<Token, Instant>>>,
+pub(crate) struct Cache {
+    shards: Arc<[CacheShard]>,
+    /// Number of outstanding requests.
+    len: Arc<AtomicUsize>,
+    /// Largest number of outstanding requests seen.
+    high_water: Arc<AtomicUsize>,
 }
 
 impl Cache {
     fn new() -> Cache {
         Cache {
-            inner: Arc::new(Mutex::new(HashMap::new())),
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            len: Arc::new(AtomicUsize::new(0)),
+            high_water: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    fn insert(&self, ident: u16, seq_cnt: u16, time: Instant) {
-        self.inner.lock().insert((ident, seq_cnt), time);
+    fn shard(&self, ident: u16, seq_cnt: u16) -> &CacheShard {
+        // Consecutive sequence numbers land in different shards.
+        &self.shards[usize::from(seq_cnt ^ ident.rotate_left(8)) % CACHE_SHARDS]
+    }
+
+    /// Record request `seq_cnt` of `ident` sent at `time`, outstanding for
+    /// `lifetime` at most.
+    pub(crate) fn insert(
+        &self,
+        ident: u16,
+        seq_cnt: u16,
+        time: Instant,
+        lifetime: Duration,
+        nonce: Option<u64>,
+    ) {
+        let entry = CacheEntry {
+            sent: time,
+            deadline: time + lifetime,
+            nonce,
+        };
+        let mut shard = self.shard(ident, seq_cnt).lock();
+        if shard.len() >= CACHE_PURGE_THRESHOLD {
+            let before = shard.len();
+            shard.retain(|_, outstanding| outstanding.deadline > time);
+            self.len.fetch_sub(before - shard.len(), Ordering::Relaxed);
+        }
+        if shard.insert((ident, seq_cnt), entry).is_none() {
+            let len = self.len.fetch_add(1, Ordering::Relaxed) + 1;
+            self.high_water.fetch_max(len, Ordering::Relaxed);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    fn get(&self, ident: u16, seq_cnt: u16) -> Option<Instant> {
+        self.shard(ident, seq_cnt)
+            .lock()
+            .get(&(ident, seq_cnt))
+            .map(|entry| entry.sent)
+    }
+
+    /// The nonce sent in the payload of request `seq_cnt`, if any.
+    fn nonce(&self, ident: u16, seq_cnt: u16) -> Option<u64> {
+        self.shard(ident, seq_cnt)
+            .lock()
+            .get(&(ident, seq_cnt))?
+            .nonce
+    }
+
+    pub(crate) fn remove(&self, ident: u16, seq_cnt: u16) -> Option<Instant> {
+        let entry = self
+            .shard(ident, seq_cnt)
+            .lock()
+            .remove(&(ident, seq_cnt))?;
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        Some(entry.sent)
+    }
+
+    /// Forget the outstanding requests of `ident`.
+    fn clear(&self, ident: u16) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock();
+            let before = shard.len();
+            shard.retain(|(id, _), _| *id != ident);
+            self.len.fetch_sub(before - shard.len(), Ordering::Relaxed);
+        }
     }
 
-    fn remove(&self, ident: u16, seq_cnt: u16) -> Option<Instant> {
-        self.inner.lock().remove(&(ident, seq_cnt))
+    /// Outstanding sequence numbers of `ident` with their send time.
+    fn pending(&self, ident: u16) -> Vec<(u16, Instant)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .lock()
+                    .iter()
+                    .filter(|((id, _), _)| *id == ident)
+                    .map(|((_, seq_cnt), entry)| (*seq_cnt, entry.sent))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
     }
 }
 
@@ -57,117 +203,1906 @@ impl Cache {
 ///
 pub struct Pinger {
     destination: IpAddr,
-    ident: u16,
-    size: usize,
-    ttl: u8,
-    timeout: Duration,
-    socket: AsyncSocket,
+    config: PingerConfig,
+    socket: Arc<dyn Transport>,
     rx: Receiver<PingResponse>,
     cache: Cache,
+    seq: Arc<AtomicU16>,
+    probes: ProbeQueue,
+    last_rtt_source: Option<RttSource>,
+    last_kernel_rtt: Option<Duration>,
+    /// Sequence numbers, send and reply times of the answered requests.
+    completed: VecDeque<(u16, Instant, Instant)>,
+    /// Sequence numbers and send times of the requests that timed out.
+    timed_out: VecDeque<(u16, Instant)>,
+    late_replies: u64,
+    on_late_reply: Option<LateReplyCallback>,
+    rtt_estimator: RttEstimator,
+    registration: Registration,
+    ident_lease: Option<IdentLease>,
+    duplicates: u64,
+    /// Sequence number of the next retransmission, see `next_retry_seq`.
+    retry_seq: u16,
+    /// The request being sent, reused between sends.
+    send_buf: Vec<u8>,
+    /// The data bytes of the echo request being sent, reused between sends.
+    data_buf: Vec<u8>,
+    #[cfg(feature = "hdrhistogram")]
+    histogram: Option<RttHistogram>,
+}
+
+/// Options of a single request sent with `Pinger::ping_with`, overriding the
+/// pinger's settings for that request only.
+///
+/// # Examples
+/// ```no_run
+/// use surge_ping::{PingOptions, Pinger};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let mut pinger = Pinger::new("192.0.2.1".parse().unwrap()).unwrap();
+///     // Expedited Forwarding, DSCP 46.
+///     let options = PingOptions { tos: Some(46 << 2), ..Default::default() };
+///     println!("{:?}", pinger.ping_with(0, &options).await);
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PingOptions {
+    /// The TOS byte, or the traffic class for IPv6, of the request. The DSCP
+    /// is its upper 6 bits.
+    pub tos: Option<u8>,
+    /// The TTL, or the hop limit for IPv6, of the request.
+    pub ttl: Option<u8>,
+    /// The data bytes of the request, sent verbatim in place of the pinger's
+    /// payload.
+    pub payload: Option<Vec<u8>>,
+    /// How long to wait for the reply, see `Pinger::ping_with_timeout`.
+    pub timeout: Option<Duration>,
+}
+
+impl PingOptions {
+    fn marking(&self) -> Marking {
+        Marking {
+            ttl: self.ttl,
+            tos: self.tos,
+        }
+    }
+}
+
+/// Settings of a `Pinger`, shared by `PingerBuilder` and the `Pinger` setters.
+#[derive(Debug, Clone)]
+pub(crate) struct PingerConfig {
+    pub ident: u16,
+    /// Whether `ident` was chosen by the user rather than at random.
+    pub ident_fixed: bool,
+    pub size: usize,
+    pub ttl: u8,
+    pub timeout: Duration,
+    pub payload: Vec<u8>,
+    pub pattern: Vec<u8>,
+    pub channel_capacity: usize,
+    pub verify_checksum: bool,
+    pub verify_payload: bool,
+    pub timestamp_payload: bool,
+    pub nonce_payload: bool,
+    pub auto_timeout: bool,
+    pub retries: usize,
+    pub retry_backoff: Duration,
+    /// Whether replies must come from the destination, false when the
+    /// socket routes them by identifier.
+    pub match_source: bool,
+    /// Whether replies must carry `ident`, false on datagram sockets where
+    /// the kernel sets the identifier and filters the replies.
+    pub match_ident: bool,
+}
+
+impl Default for PingerConfig {
+    fn default() -> Self {
+        PingerConfig {
+            ident: random(),
+            ident_fixed: false,
+            size: 56,
+            ttl: 60,
+            timeout: Duration::from_secs(2),
+            payload: Vec::new(),
+            pattern: Vec::new(),
+            channel_capacity: 100,
+            verify_checksum: true,
+            verify_payload: false,
+            timestamp_payload: true,
+            nonce_payload: false,
+            auto_timeout: false,
+            retries: 0,
+            retry_backoff: Duration::ZERO,
+            match_source: true,
+            match_ident: true,
+        }
+    }
+}
+
+impl PingerConfig {
+    /// Whether echo requests carry a send timestamp at the start of the payload.
+    fn timestamp_active(&self) -> bool {
+        self.timestamp_payload && self.payload.is_empty() && self.size >= TIMESTAMP_SIZE
+    }
+
+    /// Offset of the nonce in the payload, after the timestamp if any.
+    fn nonce_offset(&self) -> usize {
+        if self.timestamp_active() {
+            TIMESTAMP_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Whether echo requests carry a random nonce in the payload.
+    fn nonce_active(&self) -> bool {
+        self.nonce_payload
+            && self.payload.is_empty()
+            && self.size >= self.nonce_offset() + NONCE_SIZE
+    }
+
+    /// Write a new random nonce to the data bytes `data` if enabled,
+    /// returning it.
+    fn write_nonce(&self, data: &mut [u8]) -> Option<u64> {
+        if !self.nonce_active() {
+            return None;
+        }
+        let nonce: u64 = random();
+        let offset = self.nonce_offset();
+        data[offset..offset + NONCE_SIZE].copy_from_slice(&nonce.to_be_bytes());
+        Some(nonce)
+    }
+
+    /// The data bytes of an echo request: room for the timestamp and the
+    /// nonce if enabled, then the pattern tiled over the rest, overwritten at the start by the
+    /// payload.
+    fn data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write_data(&mut data);
+        data
+    }
+
+    /// Write the data bytes of an echo request to `data`, reusing its
+    /// allocation.
+    fn write_data(&self, data: &mut Vec<u8>) {
+        data.clear();
+        data.resize(self.size, 0);
+        let start = if self.nonce_active() {
+            self.nonce_offset() + NONCE_SIZE
+        } else {
+            self.nonce_offset()
+        };
+        for (byte, pattern) in data[start..].iter_mut().zip(self.pattern.iter().cycle()) {
+            *byte = *pattern;
+        }
+        let len = self.payload.len().min(self.size);
+        data[..len].copy_from_slice(&self.payload[..len]);
+    }
+}
+
+/// Configures a `Pinger` before its first use.
+///
+/// # Examples
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use surge_ping::Pinger;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let mut pinger = Pinger::builder("114.114.114.114".parse()?)
+///         .size(56)
+///         .ttl(64)
+///         .timeout(Duration::from_secs(1))
+///         .build()
+///         .await?;
+///     let result = pinger.ping(0).await;
+///     println!("{:?}", result);
+///     Ok(())
+/// }
+/// ```
+pub struct PingerBuilder {
+    destination: IpAddr,
+    socket: Option<PingSocket>,
+    config: PingerConfig,
+    ttl: Option<u8>,
+    pattern_set: bool,
+}
+
+impl PingerBuilder {
+    pub(crate) fn new(destination: IpAddr, socket: Option<PingSocket>) -> PingerBuilder {
+        PingerBuilder {
+            destination,
+            socket,
+            config: PingerConfig::default(),
+            ttl: None,
+            pattern_set: false,
+        }
+    }
+
+    /// Set the identification of ICMP. (default: random)
+    ///
+    /// Building fails with `SurgeError::IdentInUse` if another pinger of the
+    /// socket uses it, or of the process for a pinger owning its socket.
+    /// Random identifiers are always unique, building fails with
+    /// `SurgeError::IdentsExhausted` once all 65536 are in use.
+    pub fn ident(&mut self, val: u16) -> &mut Self {
+        self.config.ident = val;
+        self.config.ident_fixed = true;
+        self
+    }
+
+    /// Derive the identification of ICMP from `seed`, the same seed always
+    /// giving the same identification.
+    pub fn ident_from(&mut self, seed: u64) -> &mut Self {
+        self.ident(ident_from_seed(seed))
+    }
+
+    /// Draw the identification of ICMP from `rng`.
+    pub fn ident_from_rng<R: RngCore + ?Sized>(&mut self, rng: &mut R) -> &mut Self {
+        self.ident(rng.gen())
+    }
+
+    /// Set the packet size.(default: 56)
+    ///
+    /// Building fails with `SurgeError::InvalidSize` if the reply wouldn't fit
+    /// an IP packet or the receive buffer of the socket, see
+    /// `PingSocketBuilder::set_recv_packet_size`. Sizes below 16 bytes leave
+    /// no room for the send timestamp, RTTs are then measured from the send
+    /// time recorded by the pinger.
+    pub fn size(&mut self, size: usize) -> &mut Self {
+        self.config.size = size;
+        self
+    }
+
+    /// Set the TTL of the echo requests.
+    ///
+    /// Only allowed for a pinger owning its socket, the TTL of a shared
+    /// `PingSocket` is set with `PingSocketBuilder::set_ttl`.
+    pub fn ttl(&mut self, ttl: u8) -> &mut Self {
+        self.ttl = Some(ttl);
+        self.config.ttl = ttl;
+        self
+    }
+
+    /// The timeout of each Ping. (default: 2s)
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Set the bytes placed at the start of the payload, and the packet size
+    /// to their length.
+    pub fn payload(&mut self, payload: &[u8]) -> &mut Self {
+        self.config.payload = payload.to_vec();
+        self.config.size = payload.len();
+        self
+    }
+
+    /// Fill the payload with repetitions of `pattern`, like `ping -p`.
+    ///
+    /// The pattern is truncated to the payload size, an empty pattern makes
+    /// `build` fail with `SurgeError::EmptyPattern`.
+    pub fn pattern(&mut self, pattern: &[u8]) -> &mut Self {
+        self.config.pattern = pattern.to_vec();
+        self.pattern_set = true;
+        self
+    }
+
+    /// Set how many received packets may wait to be processed. (default: 100)
+    pub fn channel_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.config.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Embed the send timestamp in the payload to measure the RTT. (default: true)
+    pub fn timestamp_payload(&mut self, enable: bool) -> &mut Self {
+        self.config.timestamp_payload = enable;
+        self
+    }
+
+    /// Embed a random nonce in the payload to reject stale replies.
+    /// (default: false)
+    pub fn nonce_payload(&mut self, enable: bool) -> &mut Self {
+        self.config.nonce_payload = enable;
+        self
+    }
+
+    /// Derive the timeout of each Ping from the measured RTTs. (default: false)
+    pub fn auto_timeout(&mut self, enable: bool) -> &mut Self {
+        self.config.auto_timeout = enable;
+        self
+    }
+
+    /// Retransmit a Ping up to `retries` times on timeout. (default: 0)
+    pub fn retries(&mut self, retries: usize) -> &mut Self {
+        self.config.retries = retries;
+        self
+    }
+
+    /// Wait between a timeout and the retransmission. (default: 0)
+    pub fn retry_backoff(&mut self, backoff: Duration) -> &mut Self {
+        self.config.retry_backoff = backoff;
+        self
+    }
+
+    /// Verify the ICMP checksum of IPv4 replies. (default: true)
+    pub fn verify_checksum(&mut self, verify: bool) -> &mut Self {
+        self.config.verify_checksum = verify;
+        self
+    }
+
+    /// Compare echoed payloads with the requests. (default: false)
+    pub fn verify_payload(&mut self, verify: bool) -> &mut Self {
+        self.config.verify_payload = verify;
+        self
+    }
+
+    /// Creates the configured `Pinger`, applying its socket options.
+    pub async fn build(&self) -> Result<Pinger> {
+        // A pinger owning its socket sizes the receive buffer for its replies.
+        let recv_packet_size = self
+            .socket
+            .as_ref()
+            .map_or(MAX_RECV_PACKET_SIZE, PingSocket::recv_packet_size);
+        check_payload_size(self.destination, self.config.size, recv_packet_size)?;
+        if self.pattern_set && self.config.pattern.is_empty() {
+            return Err(SurgeError::EmptyPattern);
+        }
+        match &self.socket {
+            Some(socket) => {
+                if self.ttl.is_some() {
+                    return Err(SurgeError::IOError(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the TTL of a shared PingSocket is set with PingSocketBuilder::set_ttl",
+                    )));
+                }
+                socket
+                    .pinger_with_config(self.destination, self.config.clone())
+                    .await
+            }
+            None => PingSocket::create_pinger(
+                self.destination,
+                self.config.clone(),
+                self.ttl.map(u32::from),
+            ),
+        }
+    }
 }
 
 impl Pinger {
     /// Creates a new Ping instance from `IpAddr`.
     #[deprecated(note = "Use the pingsocket::PingSocketBuilder::build as Pinger constructor")]
-    pub fn new(host: IpAddr) -> io::Result<Pinger> {
-        crate::pingsocket::PingSocket::create_pinger(host)
+    pub fn new(host: IpAddr) -> Result<Pinger> {
+        PingSocket::create_pinger(host, PingerConfig::default(), None)
+    }
+
+    /// Creates a builder for a `Pinger` to `host` owning its own socket.
+    pub fn builder(host: IpAddr) -> PingerBuilder {
+        PingerBuilder::new(host, None)
     }
+
     pub(crate) fn new_pinger(
         host: IpAddr,
-        socket: AsyncSocket,
+        socket: Arc<dyn Transport>,
         rx: Receiver<PingResponse>,
+        config: PingerConfig,
+        registration: Registration,
+        ident_lease: Option<IdentLease>,
     ) -> Pinger {
+        let cache = Cache::new();
+        registration.attach_cache(&host, config.ident, cache.clone(), config.timeout);
         Pinger {
             destination: host,
-            ident: random(),
-            size: 56,
-            ttl: 60,
-            timeout: Duration::from_secs(2),
+            config,
             socket,
             rx,
-            cache: Cache::new(),
+            cache,
+            seq: Arc::new(AtomicU16::new(0)),
+            probes: Arc::new(Mutex::new(VecDeque::new())),
+            last_rtt_source: None,
+            last_kernel_rtt: None,
+            completed: VecDeque::with_capacity(MAX_COMPLETED),
+            timed_out: VecDeque::new(),
+            late_replies: 0,
+            on_late_reply: None,
+            rtt_estimator: RttEstimator::default(),
+            registration,
+            ident_lease,
+            duplicates: 0,
+            retry_seq: u16::MAX,
+            send_buf: Vec::new(),
+            data_buf: Vec::new(),
+            #[cfg(feature = "hdrhistogram")]
+            histogram: None,
         }
     }
 
+    /// Retarget the pinger to `destination`, keeping its socket and settings.
+    ///
+    /// Outstanding requests are forgotten and replies already received from
+    /// the previous destination are discarded. The new destination must be
+    /// of the same address family, otherwise
+    /// `SurgeError::AddressFamilyMismatch` is returned. Fails with
+    /// `SurgeError::DestinationInUse` if another pinger of a shared
+    /// `PingSocket` pings `destination`, and with `SurgeError::NetworkError`
+    /// if the socket stopped receiving, or another pinger took over the
+    /// previous destination.
+    pub fn set_destination(&mut self, destination: IpAddr) -> Result<()> {
+        if destination.is_ipv4() != self.destination.is_ipv4() {
+            return Err(SurgeError::AddressFamilyMismatch {
+                addr: destination,
+                socket: if self.destination.is_ipv4() {
+                    "IPv4"
+                } else {
+                    "IPv6"
+                },
+            });
+        }
+        if destination == self.destination {
+            return Ok(());
+        }
+        match self.registration.rekey(&self.destination, destination) {
+            Rekey::Moved => {}
+            Rekey::Taken => return Err(SurgeError::DestinationInUse { destination }),
+            Rekey::Gone => return Err(SurgeError::NetworkError),
+        }
+        self.destination = destination;
+        self.cache.clear(self.config.ident);
+        self.probes.lock().clear();
+        self.completed.clear();
+        self.timed_out.clear();
+        while self.rx.try_recv().is_ok() {}
+        Ok(())
+    }
+
+    /// The address the pinger sends its requests to.
+    pub fn destination(&self) -> IpAddr {
+        self.destination
+    }
+
+    /// The local address of the socket, see `PingSocket::local_addr`.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Connect the socket to the destination, so the kernel drops the
+    /// packets of other hosts and requests are sent with `send`.
+    ///
+    /// Only pingers owning their socket can connect it, pingers of a
+    /// `PingSocket` fail with `SurgeError::IOError`. Returns false if the
+    /// platform doesn't support connecting raw sockets, the pinger then
+    /// keeps working unconnected. After `set_destination`, connect again.
+    pub async fn connect(&mut self) -> Result<bool> {
+        let target = SocketAddr::new(self.destination, 0);
+        Ok(self.socket.connect(target).await?)
+    }
+
+    /// Set the TTL of IPv4 requests, or the hop limit of IPv6 requests.
+    ///
+    /// Only pingers owning their socket can change it, the TTL of a shared
+    /// `PingSocket` is set with `PingSocketBuilder::set_ttl` and a warning
+    /// is logged instead.
     pub fn set_ttl(&mut self, ttl: u8) -> &mut Pinger {
-        self.ttl = ttl;
+        if let Err(e) = self.socket.set_ttl(u32::from(ttl)) {
+            warn!("failed to set TTL {} for {}: {}", ttl, self.destination, e);
+            return self;
+        }
+        self.config.ttl = ttl;
         self
     }
 
+    /// The TTL of IPv4 requests, or the hop limit of IPv6 requests, as set
+    /// on the socket.
+    pub fn get_ttl(&self) -> io::Result<u8> {
+        let ttl = self.socket.ttl()?;
+        Ok(u8::try_from(ttl).unwrap_or(u8::MAX))
+    }
+
     /// Set the identification of ICMP.
+    ///
+    /// The identifier stays reserved for this pinger on its socket, or in the
+    /// process for a pinger owning its socket. If another pinger uses it, a
+    /// warning is logged and the identifier is left unchanged, see
+    /// `Pinger::try_ident`.
     pub fn ident(&mut self, val: u16) -> &mut Pinger {
-        self.ident = val;
+        if let Err(e) = self.try_ident(val) {
+            warn!("{} {}", self.destination, e);
+        }
         self
     }
 
+    /// Set the identification of ICMP, failing with `SurgeError::IdentInUse`
+    /// if another pinger uses it, in which case the pinger keeps its
+    /// identifier.
+    pub fn try_ident(&mut self, val: u16) -> Result<&mut Pinger> {
+        if val == self.config.ident {
+            return Ok(self);
+        }
+        if let Some(lease) = &self.ident_lease {
+            self.ident_lease = Some(lease.claim(val)?);
+        }
+        self.registration
+            .set_ident(self.destination, self.config.ident, val);
+        self.config.ident = val;
+        Ok(self)
+    }
+
+    /// The identification of ICMP used by the echo requests.
+    pub fn get_ident(&self) -> u16 {
+        self.config.ident
+    }
+
+    /// Derive the identification of ICMP from `seed`, see
+    /// `PingerBuilder::ident_from`.
+    pub fn ident_from(&mut self, seed: u64) -> &mut Pinger {
+        self.ident(ident_from_seed(seed))
+    }
+
+    /// Draw the identification of ICMP from `rng`.
+    pub fn ident_from_rng<R: RngCore + ?Sized>(&mut self, rng: &mut R) -> &mut Pinger {
+        self.ident(rng.gen())
+    }
+
     /// Set the packet size.(default: 56)
+    ///
+    /// This is the number of data bytes after the 8 bytes ICMP header. Sizes
+    /// above the largest payload an IP packet can carry, or whose reply
+    /// doesn't fit the receive buffer of the socket, make `ping` fail with
+    /// `SurgeError::InvalidSize`, see `max_size`. Packets larger than the
+    /// path MTU are fragmented unless the DF bit is set on the socket, in
+    /// which case they are dropped. Sizes below 16 bytes leave no room for
+    /// the send timestamp, see `PingerBuilder::size`.
     pub fn size(&mut self, size: usize) -> &mut Pinger {
-        self.size = size;
+        self.config.size = size;
         self
     }
 
+    /// The largest packet size accepted by `size`.
+    pub fn max_size(&self) -> usize {
+        max_payload_size(self.destination, self.socket.recv_packet_size())
+    }
+
     /// The timeout of each Ping, in seconds. (default: 2s)
     pub fn timeout(&mut self, timeout: Duration) -> &mut Pinger {
-        self.timeout = timeout;
+        self.config.timeout = timeout;
+        self.registration.attach_cache(
+            &self.destination,
+            self.config.ident,
+            self.cache.clone(),
+            timeout,
+        );
+        self
+    }
+
+    /// Embed a monotonic send timestamp in the first 16 bytes of the payload
+    /// and compute the RTT from the echoed timestamp, like classic ping.
+    /// (default: true)
+    ///
+    /// Only used when the payload size is at least 16 bytes and no explicit
+    /// payload was configured. If a reply's payload is too short or the
+    /// timestamp is corrupted, the send time recorded by the pinger is used,
+    /// see `last_rtt_source`.
+    pub fn timestamp_payload(&mut self, enable: bool) -> &mut Pinger {
+        self.config.timestamp_payload = enable;
+        self
+    }
+
+    /// Embed a random nonce in 8 bytes of the payload, after the timestamp
+    /// if any, and reject replies that don't echo it. (default: false)
+    ///
+    /// This rejects a late reply to an earlier use of the same sequence
+    /// number, e.g. after the counter wrapped around, which would otherwise
+    /// be accepted when it arrives after the current request was sent. Only
+    /// used when the payload has room for it and no explicit payload was
+    /// configured.
+    pub fn nonce_payload(&mut self, enable: bool) -> &mut Pinger {
+        self.config.nonce_payload = enable;
+        self
+    }
+
+    /// Set each Ping's timeout to `srtt + 4 * rttvar` of the measured RTTs,
+    /// like TCP's retransmission timeout (RFC 6298). (default: false)
+    ///
+    /// The configured timeout is used until a reply was measured and is the
+    /// upper bound of the derived timeout. A timeout restarts the estimate.
+    pub fn auto_timeout(&mut self, enable: bool) -> &mut Pinger {
+        self.config.auto_timeout = enable;
+        self
+    }
+
+    /// Retransmit a Ping up to `retries` times when it times out. (default: 0)
+    ///
+    /// Retransmissions keep the identifier and take their sequence numbers
+    /// from a counter of the pinger going down from 65535, skipping those of
+    /// outstanding and recently answered or timed out requests. They only
+    /// collide with the sequence numbers passed to `ping` when these come
+    /// close to 65535, e.g. after `ping_next` wraps: avoid retries then. A
+    /// late reply to an earlier attempt is still accepted.
+    ///
+    /// Only timeouts are retried, other errors and ICMP error replies are
+    /// returned right away. `PingResult::attempts` tells how many requests
+    /// were sent.
+    pub fn retries(&mut self, retries: usize) -> &mut Pinger {
+        self.config.retries = retries;
         self
     }
 
-    async fn recv_reply(&mut self, seq_cnt: u16) -> Result<(IcmpPacket, Duration)> {
+    /// Wait `backoff` after a timeout before retransmitting. (default: 0)
+    pub fn retry_backoff(&mut self, backoff: Duration) -> &mut Pinger {
+        self.config.retry_backoff = backoff;
+        self
+    }
+
+    /// Exponentially weighted moving average of the RTTs, `None` before the
+    /// first reply or after an idle period of over a minute.
+    pub fn smoothed_rtt(&self) -> Option<Duration> {
+        self.rtt_estimator.srtt()
+    }
+
+    /// Exponentially weighted moving average of the RTT deviation from
+    /// `smoothed_rtt`, the jitter.
+    pub fn rtt_variance(&self) -> Option<Duration> {
+        self.rtt_estimator.rttvar()
+    }
+
+    /// Number of duplicate replies received so far, answers to a request
+    /// that was already answered within the timeout (`DUP!` in ping).
+    ///
+    /// Only the last 1024 answered requests are remembered to detect them.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Number of replies received after their request timed out.
+    ///
+    /// Late replies are dropped rather than matched to a later request
+    /// reusing the sequence number. Only the last 1024 timed out requests
+    /// are remembered to detect them.
+    pub fn late_replies(&self) -> u64 {
+        self.late_replies
+    }
+
+    /// Call `callback` with the sequence number and RTT of every reply
+    /// received after its request timed out, see `late_replies`.
+    ///
+    /// Late replies are only noticed while the pinger receives, e.g. during
+    /// the next `ping`.
+    pub fn on_late_reply<F>(&mut self, callback: F) -> &mut Pinger
+    where
+        F: FnMut(u16, Duration) + Send + Sync + 'static,
+    {
+        self.on_late_reply = Some(Box::new(callback));
+        self
+    }
+
+    /// Number of requests awaiting a reply, including those sent by the
+    /// `ProbeSender`s of this Pinger.
+    pub fn pending(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Largest number of requests that awaited a reply at the same time.
+    pub fn pending_high_water(&self) -> usize {
+        self.cache.high_water()
+    }
+
+    /// Record the round trip times into a histogram with 3 significant
+    /// digits, or stop recording them. (default: false)
+    ///
+    /// Enabling an already enabled histogram keeps what it recorded.
+    #[cfg(feature = "hdrhistogram")]
+    pub fn record_histogram(&mut self, enable: bool) -> &mut Pinger {
+        match (enable, &self.histogram) {
+            (true, None) => self.histogram = Some(RttHistogram::new()),
+            (false, _) => self.histogram = None,
+            (true, Some(_)) => {}
+        }
+        self
+    }
+
+    /// Record the round trip times into `histogram`, e.g. one created with
+    /// `RttHistogram::with_precision`.
+    #[cfg(feature = "hdrhistogram")]
+    pub fn set_histogram(&mut self, histogram: RttHistogram) -> &mut Pinger {
+        self.histogram = Some(histogram);
+        self
+    }
+
+    /// Distribution of the round trip times measured since recording was
+    /// enabled, see `record_histogram`.
+    #[cfg(feature = "hdrhistogram")]
+    pub fn histogram(&self) -> Option<&RttHistogram> {
+        self.histogram.as_ref()
+    }
+
+    /// Clear the histogram, e.g. at the start of a reporting window.
+    #[cfg(feature = "hdrhistogram")]
+    pub fn reset_histogram(&mut self) {
+        if let Some(histogram) = &mut self.histogram {
+            histogram.reset();
+        }
+    }
+
+    /// The timeout of the next Ping.
+    fn current_timeout(&self) -> Duration {
+        match self.rtt_estimator.rto() {
+            Some(rto) if self.config.auto_timeout => rto.min(self.config.timeout),
+            _ => self.config.timeout,
+        }
+    }
+
+    /// How the RTT of the last successful ping was measured.
+    pub fn last_rtt_source(&self) -> Option<RttSource> {
+        self.last_rtt_source
+    }
+
+    /// The RTT of the last successful ping measured from kernel timestamps,
+    /// see `PingSocketBuilder::set_kernel_timestamps`.
+    pub fn last_kernel_rtt(&self) -> Option<Duration> {
+        self.last_kernel_rtt
+    }
+
+    /// Verify the ICMP checksum of IPv4 replies, corrupted replies are ignored.
+    /// (default: true)
+    ///
+    /// IPv6 replies are always verified by the kernel.
+    pub fn verify_checksum(&mut self, verify: bool) -> &mut Pinger {
+        self.config.verify_checksum = verify;
+        self
+    }
+
+    /// Compare the payload echoed by a reply with the one sent, a reply
+    /// that differs fails with `SurgeError::PayloadMismatch` carrying the
+    /// first differing offset. (default: false)
+    ///
+    /// The embedded send timestamp, if any, is not compared.
+    pub fn verify_payload(&mut self, verify: bool) -> &mut Pinger {
+        self.config.verify_payload = verify;
+        self
+    }
+
+    /// Fill the payload with repetitions of `pattern`, like `ping -p`, for
+    /// both IPv4 and IPv6.
+    ///
+    /// The pattern is truncated to the payload size. An empty pattern is
+    /// rejected with `SurgeError::EmptyPattern`.
+    pub fn pattern(&mut self, pattern: &[u8]) -> Result<&mut Pinger> {
+        if pattern.is_empty() {
+            return Err(SurgeError::EmptyPattern);
+        }
+        self.config.pattern = pattern.to_vec();
+        Ok(self)
+    }
+
+    /// Wait for the reply to any of the requests sent with sequence numbers
+    /// `seqs`, returning it with its details.
+    async fn recv_reply(
+        &mut self,
+        seqs: &[u16],
+        data: &[u8],
+        timestamped: bool,
+    ) -> Result<(ReplyMeta, IcmpPacket)> {
+        let ident = self.config.ident;
         loop {
             let response = self.rx.recv().await.ok_or(SurgeError::NetworkError)?;
-            let packet = match self.destination {
-                IpAddr::V4(_) => icmpv4::Icmpv4Packet::decode(&response.packet).map(IcmpPacket::V4),
-                IpAddr::V6(a) => {
-                    icmpv6::Icmpv6Packet::decode(&response.packet, a).map(IcmpPacket::V6)
-                }
-            };
-            match packet {
+            match self.decode(&response) {
                 Ok(packet) => {
-                    if packet.check_reply_packet(self.destination, seq_cnt, self.ident) {
-                        if let Some(ins) = self.cache.remove(self.ident, seq_cnt) {
-                            return Ok((packet, response.when - ins));
-                        } else {
-                            warn!(
-                                "Invalid reply ident {} {} {}",
-                                self.destination, self.ident, seq_cnt
-                            );
+                    let matched = seqs
+                        .iter()
+                        .copied()
+                        .find(|seq_cnt| self.is_reply(&packet, *seq_cnt, ident));
+                    if let Some(seq_cnt) = matched {
+                        match self.cache.get(ident, seq_cnt) {
+                            // Received before the request was sent, so it answers
+                            // an earlier use of this sequence number.
+                            Some(ins) if response.when < ins => {
+                                if !self.late_reply(seq_cnt, response.when) {
+                                    trace!(
+                                        "Stale reply {} {} {}",
+                                        self.destination,
+                                        ident,
+                                        seq_cnt
+                                    );
+                                }
+                            }
+                            Some(_) if !self.nonce_matches(&packet, seq_cnt, timestamped) => {
+                                trace!(
+                                    "Reply with a stale nonce {} {} {}",
+                                    self.destination,
+                                    ident,
+                                    seq_cnt
+                                );
+                            }
+                            Some(ins) => {
+                                let nonced = self.cache.nonce(ident, seq_cnt).is_some();
+                                self.cache.remove(ident, seq_cnt);
+                                self.complete(seq_cnt, ins, response.when);
+                                if self.config.verify_payload {
+                                    let skip = if timestamped { TIMESTAMP_SIZE } else { 0 }
+                                        + if nonced { NONCE_SIZE } else { 0 };
+                                    if let Some(offset) =
+                                        first_mismatch(data, packet.echo_payload(), skip)
+                                    {
+                                        return Err(SurgeError::PayloadMismatch { offset });
+                                    }
+                                }
+                                let rtt =
+                                    self.measure_rtt(&packet, response.when, ins, timestamped);
+                                self.last_kernel_rtt = self.kernel_rtt(&response, seq_cnt);
+                                trace_event!(
+                                    DEBUG,
+                                    dest = %self.destination,
+                                    ident,
+                                    seq = seq_cnt,
+                                    rtt = ?rtt,
+                                    "reply matched"
+                                );
+                                let meta = reply_meta(&response, &packet, seq_cnt, ident, rtt);
+                                return Ok((meta, packet));
+                            }
+                            None => {
+                                warn!(
+                                    "Invalid reply ident {} {} {}",
+                                    self.destination, ident, seq_cnt
+                                );
+                            }
                         }
+                    } else if self
+                        .duplicate_of(packet.get_sequence(), response.when)
+                        .is_some()
+                        && self.is_reply(&packet, packet.get_sequence(), ident)
+                    {
+                        self.duplicates += 1;
+                        trace!(
+                            "Duplicate reply {} {} {}",
+                            self.destination,
+                            ident,
+                            packet.get_sequence()
+                        );
+                    } else if self.is_reply(&packet, packet.get_sequence(), ident)
+                        && self.late_reply(packet.get_sequence(), response.when)
+                    {
+                        continue;
                     } else {
                         warn!("Invalid reply {:?}", packet);
                     }
                 }
+                Err(SurgeError::IcmpError(report)) => {
+                    if report.ident != ident || !seqs.contains(&report.seq) {
+                        continue;
+                    }
+                    match self.cache.get(ident, report.seq) {
+                        Some(ins) if response.when >= ins => {
+                            self.cache.remove(ident, report.seq);
+                            return Err(SurgeError::IcmpError(report));
+                        }
+                        _ => continue,
+                    }
+                }
                 Err(SurgeError::EchoRequestPacket) => continue,
+                Err(SurgeError::InvalidChecksum) => {
+                    warn!("Invalid checksum in reply from {}", self.destination);
+                    continue;
+                }
+                Err(SurgeError::MalformedPacket(e)) => {
+                    warn!("Malformed reply from {}: {}", self.destination, e);
+                    continue;
+                }
                 Err(e) => return Err(e),
             }
         }
     }
 
+    /// Take a sequence number for a retransmission, counting down from 65535
+    /// away from sequence numbers counting up from 0, and skipping `taken`
+    /// and those of outstanding and recent requests.
+    fn next_retry_seq(&mut self, taken: &[u16]) -> u16 {
+        let ident = self.config.ident;
+        for _ in 0..=u16::MAX {
+            let seq_cnt = self.retry_seq;
+            self.retry_seq = seq_cnt.wrapping_sub(1);
+            if !taken.contains(&seq_cnt)
+                && self.cache.get(ident, seq_cnt).is_none()
+                && !self.completed.iter().any(|&(seq, _, _)| seq == seq_cnt)
+                && !self.timed_out.iter().any(|&(seq, _)| seq == seq_cnt)
+            {
+                return seq_cnt;
+            }
+        }
+        self.retry_seq
+    }
+
+    /// Remember that `seq_cnt` sent at `sent` was answered at `when`, to
+    /// detect duplicates.
+    fn complete(&mut self, seq_cnt: u16, sent: Instant, when: Instant) {
+        if self.completed.len() == MAX_COMPLETED {
+            self.completed.pop_front();
+        }
+        self.completed.push_back((seq_cnt, sent, when));
+    }
+
+    /// Forget request `seq_cnt`, remembering it as timed out if it is still
+    /// outstanding so that its reply is recognized as late.
+    fn expire(&mut self, seq_cnt: u16) {
+        if let Some(sent) = self.cache.remove(self.config.ident, seq_cnt) {
+            if self.timed_out.len() == MAX_COMPLETED {
+                self.timed_out.pop_front();
+            }
+            self.timed_out.push_back((seq_cnt, sent));
+        }
+    }
+
+    /// Whether a reply to `seq_cnt` received at `when` answers a request that
+    /// timed out, counting it as late if so.
+    fn late_reply(&mut self, seq_cnt: u16, when: Instant) -> bool {
+        let index = self
+            .timed_out
+            .iter()
+            .rposition(|&(seq, sent)| seq == seq_cnt && sent <= when);
+        let (_, sent) = match index.and_then(|index| self.timed_out.remove(index)) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        self.late_replies += 1;
+        trace!(
+            "Late reply {} {} {}",
+            self.destination,
+            self.config.ident,
+            seq_cnt
+        );
+        if let Some(callback) = &mut self.on_late_reply {
+            callback(seq_cnt, when - sent);
+        }
+        true
+    }
+
+    /// Whether `packet` echoes the nonce sent with request `seq_cnt`, true
+    /// for requests sent without one.
+    fn nonce_matches(&self, packet: &IcmpPacket, seq_cnt: u16, timestamped: bool) -> bool {
+        let nonce = match self.cache.nonce(self.config.ident, seq_cnt) {
+            Some(nonce) => nonce,
+            None => return true,
+        };
+        let offset = if timestamped { TIMESTAMP_SIZE } else { 0 };
+        let echoed = packet.echo_payload().get(offset..).and_then(read_nonce);
+        echoed == Some(nonce)
+    }
+
+    /// Whether `packet` answers request `seq_cnt` of `ident`.
+    fn is_reply(&self, packet: &IcmpPacket, seq_cnt: u16, ident: u16) -> bool {
+        let ident = if self.config.match_ident {
+            ident
+        } else {
+            packet.get_identifier()
+        };
+        if self.config.match_source {
+            packet.check_reply_packet(self.destination, seq_cnt, ident)
+        } else {
+            packet.matches_request(seq_cnt, ident)
+        }
+    }
+
+    /// Send time of request `seq_cnt` if a reply to it received at `when`
+    /// repeats one received within the timeout before.
+    fn duplicate_of(&self, seq_cnt: u16, when: Instant) -> Option<Instant> {
+        self.completed
+            .iter()
+            .rev()
+            .find(|&&(seq, _, answered)| {
+                seq == seq_cnt
+                    && matches!(
+                        when.checked_duration_since(answered),
+                        Some(elapsed) if elapsed <= self.config.timeout
+                    )
+            })
+            .map(|&(_, sent, _)| sent)
+    }
+
+    /// RTT of a reply received at `when` to a request sent at `sent`, from
+    /// the echoed send timestamp if there is a valid one.
+    fn measure_rtt(
+        &mut self,
+        packet: &IcmpPacket,
+        when: Instant,
+        sent: Instant,
+        timestamped: bool,
+    ) -> Duration {
+        let echoed = if timestamped {
+            read_timestamp(packet.echo_payload()).and_then(|sent| when.checked_duration_since(sent))
+        } else {
+            None
+        };
+        let rtt = match echoed {
+            Some(rtt) => {
+                self.last_rtt_source = Some(RttSource::Payload);
+                rtt
+            }
+            None => {
+                self.last_rtt_source = Some(RttSource::Cache);
+                when - sent
+            }
+        };
+        self.rtt_estimator.update(rtt, when);
+        #[cfg(feature = "hdrhistogram")]
+        if let Some(histogram) = &mut self.histogram {
+            histogram.record(rtt);
+        }
+        rtt
+    }
+
+    /// RTT of the reply `response` to request `seq_cnt` from the kernel
+    /// timestamps of both.
+    fn kernel_rtt(&self, response: &PingResponse, seq_cnt: u16) -> Option<Duration> {
+        let received = response.stamp?;
+        let sent = self.socket.take_tx_timestamp(self.config.ident, seq_cnt)?;
+        received.since(&sent)
+    }
+
+    fn decode(&self, response: &PingResponse) -> Result<IcmpPacket> {
+        if let Some(report) = &response.error {
+            return Err(SurgeError::IcmpError(report.clone()));
+        }
+        match self.destination {
+            IpAddr::V4(_) if self.config.verify_checksum => {
+                icmpv4::Icmpv4Packet::decode(&response.packet).map(IcmpPacket::V4)
+            }
+            IpAddr::V4(_) => {
+                icmpv4::Icmpv4Packet::decode_lenient(&response.packet).map(IcmpPacket::V4)
+            }
+            IpAddr::V6(_) => {
+                let from = match response.from {
+                    IpAddr::V6(from) => from,
+                    IpAddr::V4(_) => return Err(MalformedPacketError::NotIpv6Packet.into()),
+                };
+                icmpv6::Icmpv6Packet::decode(&response.packet, from).map(IcmpPacket::V6)
+            }
+        }
+    }
+
+    /// Send Ping request with the next sequence number of an internal counter.
+    ///
+    /// The counter wraps around after 65535. After a wraparound, a very late
+    /// reply to the previous use of a sequence number may be taken for the
+    /// current one if it arrives within the timeout window. Replies received
+    /// before the current request was sent are still rejected, by comparing
+    /// their receive time with the send time recorded for that sequence.
+    ///
+    /// Returns the sequence number used with the result of the ping.
+    pub async fn ping_next(&mut self) -> (u16, Result<PingResult>) {
+        let seq_cnt = self.next_seq();
+        (seq_cnt, self.ping(seq_cnt).await)
+    }
+
+    /// Reserve the next sequence number of the internal counter used by
+    /// `ping_next`, wrapping after 65535.
+    ///
+    /// The counter is atomic, concurrent callers never get the same sequence
+    /// number until it wraps.
+    pub fn next_seq(&self) -> u16 {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+
     /// Send Ping request with sequence number.
-    pub async fn ping(&mut self, seq_cnt: u16) -> Result<(IcmpPacket, Duration)> {
-        let sender = self.socket.clone();
-        let mut packet = match self.destination {
-            IpAddr::V4(_) => icmpv4::make_icmpv4_echo_packet(self.ident, seq_cnt, self.size)?,
-            IpAddr::V6(_) => icmpv6::make_icmpv6_echo_packet(self.ident, seq_cnt, self.size)?,
+    ///
+    /// An ICMP error answering the request fails the ping with the matching
+    /// variant, e.g. `SurgeError::DestinationUnreachable`.
+    pub async fn ping(&mut self, seq_cnt: u16) -> Result<PingResult> {
+        let limit = self.current_timeout();
+        self.echo(seq_cnt, limit, &PingOptions::default()).await
+    }
+
+    /// Send Ping request with sequence number, with the TOS, TTL, payload or
+    /// timeout of `options` for this request only, the pinger is left
+    /// unchanged.
+    ///
+    /// Setting the TOS or TTL of a single request is supported on Linux, and
+    /// with `IcmpApiTransport` on Windows, other platforms fail with
+    /// `SurgeError::SendError`.
+    pub async fn ping_with(&mut self, seq_cnt: u16, options: &PingOptions) -> Result<PingResult> {
+        let limit = match options.timeout {
+            Some(timeout) if timeout.is_zero() => return Err(SurgeError::InvalidTimeout),
+            Some(timeout) => timeout,
+            None => self.current_timeout(),
+        };
+        self.echo(seq_cnt, limit, options).await
+    }
+
+    /// Send Ping request with sequence number, waiting at most `timeout` for
+    /// the reply instead of the configured timeout.
+    ///
+    /// A zero `timeout` is rejected with `SurgeError::InvalidTimeout`.
+    pub async fn ping_with_timeout(
+        &mut self,
+        seq_cnt: u16,
+        timeout: Duration,
+    ) -> Result<PingResult> {
+        if timeout.is_zero() {
+            return Err(SurgeError::InvalidTimeout);
+        }
+        self.echo(seq_cnt, timeout, &PingOptions::default()).await
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            name = "ping",
+            skip_all,
+            fields(dest = %self.destination, ident = self.config.ident, seq = seq_cnt)
+        )
+    )]
+    async fn echo(
+        &mut self,
+        seq_cnt: u16,
+        limit: Duration,
+        options: &PingOptions,
+    ) -> Result<PingResult> {
+        let mut seqs = Vec::new();
+        let mut data = std::mem::take(&mut self.data_buf);
+        let reply = loop {
+            let attempt_seq = if seqs.is_empty() {
+                seq_cnt
+            } else {
+                self.next_retry_seq(&seqs)
+            };
+            if !seqs.is_empty() && !self.config.retry_backoff.is_zero() {
+                rt::sleep(self.config.retry_backoff).await;
+            }
+            let (timestamped, nonce) = match &options.payload {
+                Some(payload) => {
+                    data.clear();
+                    data.extend_from_slice(payload);
+                    (false, None)
+                }
+                None => {
+                    self.config.write_data(&mut data);
+                    let timestamped = self.config.timestamp_active();
+                    if timestamped {
+                        write_timestamp(&mut data, self.socket.now());
+                    }
+                    (timestamped, self.config.write_nonce(&mut data))
+                }
+            };
+            if let Err(e) = self.encode(attempt_seq, data.len(), &data) {
+                break Err(e);
+            }
+            if let Err(e) = self.transmit(attempt_seq, nonce, options.marking()).await {
+                break Err(e);
+            }
+            seqs.push(attempt_seq);
+            match self.wait_reply(&seqs, &data, timestamped, limit).await {
+                Err(SurgeError::Timeout { .. }) if seqs.len() <= self.config.retries => continue,
+                reply => break reply,
+            }
         };
-        // let mut packet = EchoRequest::new(self.host, self.ident, seq_cnt, self.size).encode()?;
+        self.data_buf = data;
+        for seq in &seqs {
+            self.expire(*seq);
+        }
+        self.record_timeout(&reply);
+        let (meta, packet) = reply?;
+        let mut result = PingResult::new(meta, packet);
+        result.attempts = seqs.len();
+        result.kernel_rtt = self.last_kernel_rtt;
+        Ok(result)
+    }
+
+    /// Send Ping request with sequence number, using `payload` verbatim as
+    /// the data bytes of the echo request.
+    ///
+    /// The echoed data can be read from the reply, e.g. with
+    /// `Icmpv4Packet::get_payload`.
+    pub async fn ping_with_payload(&mut self, seq_cnt: u16, payload: &[u8]) -> Result<PingResult> {
+        self.encode(seq_cnt, payload.len(), payload)?;
+        let limit = self.current_timeout();
+        let (meta, packet) = self.send_and_wait(seq_cnt, payload, false, limit).await?;
+        let mut result = PingResult::new(meta, packet);
+        result.kernel_rtt = self.last_kernel_rtt;
+        Ok(result)
+    }
+
+    /// Send `count` echo requests back to back with sequence numbers from
+    /// `start_seq`, without waiting for their replies, then wait for all of
+    /// them and return their results in sequence order.
+    ///
+    /// Each request times out on its own, counted from when it was sent.
+    /// Sends go through the socket's rate limit like any other request.
+    pub async fn ping_burst(&mut self, start_seq: u16, count: u16) -> Vec<Result<PingResult>> {
+        let ident = self.config.ident;
+        let limit = self.current_timeout();
+        let timestamped = self.config.timestamp_active();
+        let mut data = std::mem::take(&mut self.data_buf);
+        self.config.write_data(&mut data);
+        let mut results: Vec<Option<Result<PingResult>>> = (0..count).map(|_| None).collect();
+        // Index, sequence number and send time of the requests outstanding.
+        let mut pending = Vec::new();
+        for (index, result) in results.iter_mut().enumerate() {
+            let seq_cnt = start_seq.wrapping_add(index as u16);
+            if timestamped {
+                write_timestamp(&mut data, self.socket.now());
+            }
+            let nonce = self.config.write_nonce(&mut data);
+            let sent = match self.encode(seq_cnt, data.len(), &data) {
+                Ok(()) => self.transmit(seq_cnt, nonce, Marking::default()).await,
+                Err(e) => Err(e),
+            };
+            match sent.map(|_| self.cache.get(ident, seq_cnt)) {
+                Ok(Some(sent)) => pending.push((index, seq_cnt, sent)),
+                Ok(None) => *result = Some(Err(SurgeError::NetworkError)),
+                Err(e) => *result = Some(Err(e)),
+            }
+        }
+
+        while let Some(deadline) = pending.iter().map(|&(_, _, sent)| sent + limit).min() {
+            let seqs: Vec<u16> = pending.iter().map(|&(_, seq_cnt, _)| seq_cnt).collect();
+            let remaining = deadline.saturating_duration_since(self.socket.now());
+            let (seq_cnt, reply) =
+                match timeout(remaining, self.recv_reply(&seqs, &data, timestamped)).await {
+                    Ok(Ok((meta, packet))) => match packet.to_error() {
+                        Some(error) => {
+                            meter::icmp_error(self.destination, &error);
+                            (meta.seq, Err(error))
+                        }
+                        None => {
+                            meter::received(self.destination, meta.rtt);
+                            let mut result = PingResult::new(meta, packet);
+                            result.kernel_rtt = self.last_kernel_rtt;
+                            (result.seq, Ok(result))
+                        }
+                    },
+                    Ok(Err(SurgeError::IcmpError(report))) => {
+                        let seq_cnt = report.seq;
+                        let error = SurgeError::IcmpError(report);
+                        meter::icmp_error(self.destination, &error);
+                        (seq_cnt, Err(error))
+                    }
+                    // The failed request is the one no longer outstanding.
+                    Ok(Err(e)) => match seqs
+                        .iter()
+                        .copied()
+                        .find(|&seq_cnt| self.cache.get(ident, seq_cnt).is_none())
+                    {
+                        Some(seq_cnt) => (seq_cnt, Err(e)),
+                        None => {
+                            // The socket stopped receiving, nothing else will be answered.
+                            let mut error = Some(e);
+                            for (index, seq_cnt, _) in pending.drain(..) {
+                                self.expire(seq_cnt);
+                                let error = error.take().unwrap_or(SurgeError::NetworkError);
+                                results[index] = Some(Err(error));
+                            }
+                            break;
+                        }
+                    },
+                    Err(_) => {
+                        // The request waited for has expired, whatever the clock says.
+                        let now = self.socket.now().max(deadline);
+                        let expired: Vec<_> = pending
+                            .iter()
+                            .filter(|&&(_, _, sent)| sent + limit <= now)
+                            .copied()
+                            .collect();
+                        pending.retain(|&(_, _, sent)| sent + limit > now);
+                        for (index, seq_cnt, _) in expired {
+                            self.expire(seq_cnt);
+                            let reply = Err(SurgeError::Timeout {
+                                destination: self.destination,
+                                ident,
+                                seq: seq_cnt,
+                                timeout: limit,
+                            });
+                            self.record_timeout(&reply);
+                            results[index] = Some(reply);
+                        }
+                        continue;
+                    }
+                };
+            if let Some(position) = pending.iter().position(|&(_, seq, _)| seq == seq_cnt) {
+                let (index, _, _) = pending.remove(position);
+                results[index] = Some(reply);
+            }
+        }
+        self.data_buf = data;
+
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or_else(|| Err(SurgeError::NetworkError)))
+            .collect()
+    }
+
+    /// Send an ICMP Timestamp request (type 13) with sequence number and
+    /// wait for the Timestamp reply (type 14). IPv4 only.
+    ///
+    /// Hosts that don't answer timestamp requests fail with
+    /// `SurgeError::Timeout` like an unanswered ping.
+    pub async fn timestamp_request(&mut self, seq_cnt: u16) -> Result<TimestampReply> {
+        if !self.destination.is_ipv4() {
+            return Err(SurgeError::AddressFamilyMismatch {
+                addr: self.destination,
+                socket: "IPv4",
+            });
+        }
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let originate = (since_epoch.as_millis() % 86_400_000) as u32;
+        self.send_buf = icmpv4::make_icmpv4_timestamp_packet(self.config.ident, seq_cnt, originate);
+        let limit = self.current_timeout();
+        let (meta, reply) = self.send_and_wait(seq_cnt, &[], false, limit).await?;
+        match reply {
+            IcmpPacket::V4(ref packet) => match packet.get_timestamps() {
+                Some((originate, receive, transmit)) => Ok(TimestampReply {
+                    originate,
+                    receive,
+                    transmit,
+                    rtt: meta.rtt,
+                }),
+                None => Err(SurgeError::UnexpectedReply {
+                    icmp_type: packet.get_icmp_type().0,
+                    icmp_code: packet.get_icmp_code().0,
+                }),
+            },
+            IcmpPacket::V6(_) => unreachable!("IPv4 pinger decoded an ICMPv6 packet"),
+        }
+    }
+
+    /// Send an ICMP Address Mask request (type 17) with sequence number and
+    /// return the mask of the Address Mask reply (type 18). IPv4 only.
+    ///
+    /// Most hosts ignore these requests, which then fail with
+    /// `SurgeError::Timeout`.
+    pub async fn address_mask_request(&mut self, seq_cnt: u16) -> Result<Ipv4Addr> {
+        if !self.destination.is_ipv4() {
+            return Err(SurgeError::AddressFamilyMismatch {
+                addr: self.destination,
+                socket: "IPv4",
+            });
+        }
+        self.send_buf = icmpv4::make_icmpv4_address_mask_packet(self.config.ident, seq_cnt);
+        let limit = self.current_timeout();
+        let (_, reply) = self.send_and_wait(seq_cnt, &[], false, limit).await?;
+        match reply {
+            IcmpPacket::V4(ref packet) => {
+                packet
+                    .get_address_mask()
+                    .ok_or_else(|| SurgeError::UnexpectedReply {
+                        icmp_type: packet.get_icmp_type().0,
+                        icmp_code: packet.get_icmp_code().0,
+                    })
+            }
+            IcmpPacket::V6(_) => unreachable!("IPv4 pinger decoded an ICMPv6 packet"),
+        }
+    }
+
+    /// Encode echo request `seq_cnt` into the send buffer.
+    fn encode(&mut self, seq_cnt: u16, size: usize, payload: &[u8]) -> Result<()> {
+        encode(
+            &mut self.send_buf,
+            self.destination,
+            self.config.ident,
+            seq_cnt,
+            size,
+            payload,
+            self.socket.recv_packet_size(),
+        )?;
+        self.socket
+            .reserve_recv_packet_size(reply_packet_size(self.destination, size));
+        Ok(())
+    }
+
+    /// Send an echo request with sequence number without waiting for its
+    /// reply, which is delivered by the `replies` stream.
+    pub async fn send_probe(&self, seq_cnt: u16) -> Result<SentProbe> {
+        send_probe(
+            self.destination,
+            &self.config,
+            self.socket.as_ref(),
+            &self.cache,
+            &self.probes,
+            seq_cnt,
+        )
+        .await
+    }
+
+    /// A handle sending probes like `send_probe`, to send from another task
+    /// than the one reading `replies`. It uses the settings of the pinger at
+    /// the time it is created.
+    pub fn probe_sender(&self) -> ProbeSender {
+        ProbeSender {
+            destination: self.destination,
+            config: self.config.clone(),
+            socket: self.socket.clone(),
+            cache: self.cache.clone(),
+            probes: self.probes.clone(),
+            seq: self.seq.clone(),
+        }
+    }
+
+    /// Send `count` echo requests every `interval`, without waiting for the
+    /// previous reply, and summarize their results like `ping` does when it
+    /// exits.
+    ///
+    /// Sequence numbers are taken from the counter of `ping_next`, results
+    /// are kept in send order so `count` may exceed 65535.
+    pub async fn summary(&mut self, count: usize, interval: Duration) -> PingSummary {
+        summary(self, count, interval).await
+    }
+
+    /// Ping every `interval` with increasing sequence numbers, without
+    /// waiting for the previous reply, and yield a `PingEvent` per request in
+    /// sequence order: its reply, its timeout or its error.
+    ///
+    /// Each request is answered or times out after the pinger's timeout, so
+    /// several requests are in flight when `interval` is shorter than it.
+    /// Dropping the stream stops sending.
+    pub fn into_stream(self, interval: Duration) -> impl Stream<Item = PingEvent> {
+        interval_stream(self, interval)
+    }
+
+    /// Stream of the replies to the probes sent with `send_probe`, as they
+    /// arrive. Probes without a reply within the timeout are reported as
+    /// `PingReply::Timeout`, further replies to answered probes as
+    /// `PingReply::Duplicate`.
+    ///
+    /// The stream ends when the socket stops receiving.
+    pub fn replies(&mut self) -> impl Stream<Item = PingReply> + '_ {
+        Replies::new(self)
+    }
+
+    pub(crate) fn poll_reply(
+        &mut self,
+        cx: &mut Context<'_>,
+        sleep: &mut Sleep,
+    ) -> Poll<Option<PingReply>> {
+        let ident = self.config.ident;
+        loop {
+            match self.rx.poll_recv(cx) {
+                Poll::Ready(Some(response)) => {
+                    if let Some(reply) = self.match_probe(&response) {
+                        return Poll::Ready(Some(reply));
+                    }
+                    continue;
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {}
+            }
+
+            let mut deadline = None;
+            {
+                let mut probes = self.probes.lock();
+                while let Some(&(seq_cnt, sent)) = probes.front() {
+                    // Answered, or its sequence number was reused since.
+                    if self.cache.get(ident, seq_cnt) != Some(sent) {
+                        probes.pop_front();
+                        continue;
+                    }
+                    if sent + self.config.timeout <= self.socket.now() {
+                        probes.pop_front();
+                        self.cache.remove(ident, seq_cnt);
+                        meter::timed_out(self.destination);
+                        return Poll::Ready(Some(PingReply::Timeout(seq_cnt)));
+                    }
+                    deadline = Some(sent + self.config.timeout);
+                    break;
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    sleep.reset(deadline);
+                    if sleep.poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    fn match_probe(&mut self, response: &PingResponse) -> Option<PingReply> {
+        let packet = self.decode(response).ok()?;
+        let seq_cnt = packet.get_sequence();
+        let ident = self.config.ident;
+        if !self.is_reply(&packet, seq_cnt, ident) {
+            return None;
+        }
+        let sent = match self.cache.get(ident, seq_cnt) {
+            Some(sent) => sent,
+            None => {
+                if let Some(sent) = self.duplicate_of(seq_cnt, response.when) {
+                    self.duplicates += 1;
+                    let rtt = response.when.saturating_duration_since(sent);
+                    let meta = reply_meta(response, &packet, seq_cnt, ident, rtt);
+                    let mut result = PingResult::new(meta, packet);
+                    result.duplicate = true;
+                    return Some(PingReply::Duplicate(result));
+                }
+                self.late_reply(seq_cnt, response.when);
+                return None;
+            }
+        };
+        let timestamped = self.config.timestamp_active();
+        if response.when < sent || !self.nonce_matches(&packet, seq_cnt, timestamped) {
+            return None;
+        }
+        self.cache.remove(ident, seq_cnt);
+        self.complete(seq_cnt, sent, response.when);
+        let rtt = self.measure_rtt(&packet, response.when, sent, timestamped);
+        self.last_kernel_rtt = self.kernel_rtt(response, seq_cnt);
+        if packet.is_echo_reply() {
+            meter::received(self.destination, rtt);
+        } else if let Some(error) = packet.to_error() {
+            meter::icmp_error(self.destination, &error);
+        }
+        Some(PingReply::Reply {
+            seq: seq_cnt,
+            rtt,
+            packet,
+        })
+    }
+
+    /// Send the request in the send buffer and wait for its reply.
+    async fn send_and_wait(
+        &mut self,
+        seq_cnt: u16,
+        data: &[u8],
+        timestamped: bool,
+        limit: Duration,
+    ) -> Result<(ReplyMeta, IcmpPacket)> {
+        self.transmit(seq_cnt, None, Marking::default()).await?;
+        let reply = self.wait_reply(&[seq_cnt], data, timestamped, limit).await;
+        self.expire(seq_cnt);
+        self.record_timeout(&reply);
+        reply
+    }
+
+    /// Record the send time and payload nonce of request `seq_cnt` and send
+    /// it from the send buffer, marked with `marking`.
+    async fn transmit(&mut self, seq_cnt: u16, nonce: Option<u64>, marking: Marking) -> Result<()> {
+        let ident = self.config.ident;
         let sock_addr = SocketAddr::new(self.destination, 0);
-        let ident = self.ident;
-        let cache = self.cache.clone();
-        cache.insert(ident, seq_cnt, Instant::now());
-        if let Err(e) = sender.send_to(&mut packet, &sock_addr).await {
+        let lifetime = self.config.timeout * (self.config.retries as u32 + 1);
+        self.cache
+            .insert(ident, seq_cnt, self.socket.now(), lifetime, nonce);
+        trace_event!(DEBUG, dest = %self.destination, ident, seq = seq_cnt, "request sent");
+        if let Err(e) = self
+            .socket
+            .send_marked(&self.send_buf, sock_addr, marking)
+            .await
+        {
             trace!("socket send packet error: {}", e);
-            return Err(SurgeError::IOError(e));
+            trace_event!(
+                WARN,
+                dest = %self.destination,
+                ident,
+                seq = seq_cnt,
+                error = %e,
+                "send failed"
+            );
+            self.cache.remove(ident, seq_cnt);
+            return Err(SurgeError::from_send(self.destination, e));
         }
+        meter::sent(self.destination);
+        Ok(())
+    }
 
-        match timeout(self.timeout, self.recv_reply(seq_cnt)).await {
-            Ok(reply) => reply.map_err(|err| {
-                self.cache.remove(ident, seq_cnt);
-                err
-            }),
+    /// Count a request that finally timed out as lost in the histogram and
+    /// the metrics.
+    fn record_timeout<T>(&mut self, reply: &Result<T>) {
+        if let Err(SurgeError::Timeout { .. }) = reply {
+            meter::timed_out(self.destination);
+            #[cfg(feature = "hdrhistogram")]
+            if let Some(histogram) = &mut self.histogram {
+                histogram.record_loss();
+            }
+        }
+    }
+
+    /// Wait up to `limit` for the reply to any of the requests `seqs`, the
+    /// first one being reported on timeout.
+    async fn wait_reply(
+        &mut self,
+        seqs: &[u16],
+        data: &[u8],
+        timestamped: bool,
+        limit: Duration,
+    ) -> Result<(ReplyMeta, IcmpPacket)> {
+        match timeout(limit, self.recv_reply(seqs, data, timestamped)).await {
+            // An ICMP error answering the request fails it.
+            Ok(Ok((meta, packet))) => match packet.to_error() {
+                Some(error) => {
+                    trace_event!(
+                        DEBUG,
+                        dest = %self.destination,
+                        ident = self.config.ident,
+                        seq = meta.seq,
+                        error = %error,
+                        "request failed"
+                    );
+                    meter::icmp_error(self.destination, &error);
+                    Err(error)
+                }
+                None => {
+                    meter::received(self.destination, meta.rtt);
+                    Ok((meta, packet))
+                }
+            },
+            Ok(Err(e)) => {
+                meter::icmp_error(self.destination, &e);
+                trace_event!(
+                    DEBUG,
+                    dest = %self.destination,
+                    ident = self.config.ident,
+                    error = %e,
+                    "receive failed"
+                );
+                Err(e)
+            }
             Err(_) => {
+                trace_event!(
+                    DEBUG,
+                    dest = %self.destination,
+                    ident = self.config.ident,
+                    seq = seqs[0],
+                    timeout = ?limit,
+                    "request timed out"
+                );
+                if self.config.auto_timeout {
+                    self.rtt_estimator.reset();
+                }
+                Err(SurgeError::Timeout {
+                    destination: self.destination,
+                    ident: self.config.ident,
+                    seq: seqs[0],
+                    timeout: limit,
+                })
+            }
+        }
+    }
+
+    /// Stop pinging and wait for the replies of requests still outstanding,
+    /// e.g. because a `ping` future was dropped before its reply arrived.
+    ///
+    /// Each outstanding sequence number is waited for until its own timeout,
+    /// counted from when it was sent, expires. Returns the final result of
+    /// every outstanding request, ordered by send time.
+    pub async fn drain(mut self) -> Vec<(u16, Result<PingResult>)> {
+        let ident = self.config.ident;
+        let limit = self.config.timeout;
+        let timestamped = self.config.timestamp_active();
+        let mut pending = self.cache.pending(ident);
+        pending.sort_by_key(|(_, sent)| *sent);
+
+        let mut results = HashMap::new();
+        let mut closed = false;
+        if let Some(deadline) = pending.iter().map(|(_, sent)| *sent + limit).max() {
+            while results.len() < pending.len() {
+                let response = match timeout_at(deadline, self.rx.recv()).await {
+                    Ok(Some(response)) => response,
+                    Ok(None) => {
+                        closed = true;
+                        break;
+                    }
+                    Err(_) => break,
+                };
+                let packet = match self.decode(&response) {
+                    Ok(packet) => packet,
+                    Err(SurgeError::InvalidChecksum) => {
+                        warn!("Invalid checksum in reply from {}", self.destination);
+                        continue;
+                    }
+                    Err(SurgeError::MalformedPacket(e)) => {
+                        warn!("Malformed reply from {}: {}", self.destination, e);
+                        continue;
+                    }
+                    Err(_) => continue,
+                };
+                for &(seq_cnt, sent) in pending.iter() {
+                    if results.contains_key(&seq_cnt)
+                        || response.when < sent
+                        || response.when > sent + limit
+                        || !self.is_reply(&packet, seq_cnt, ident)
+                        || !self.nonce_matches(&packet, seq_cnt, timestamped)
+                    {
+                        continue;
+                    }
+                    let rtt = response.when - sent;
+                    let meta = reply_meta(&response, &packet, seq_cnt, ident, rtt);
+                    results.insert(seq_cnt, Ok(PingResult::new(meta, packet)));
+                    break;
+                }
+            }
+        }
+
+        pending
+            .into_iter()
+            .map(|(seq_cnt, _)| {
                 self.cache.remove(ident, seq_cnt);
-                Err(SurgeError::Timeout { seq: seq_cnt })
+                let result = results.remove(&seq_cnt).unwrap_or(if closed {
+                    Err(SurgeError::NetworkError)
+                } else {
+                    Err(SurgeError::Timeout {
+                        destination: self.destination,
+                        ident,
+                        seq: seq_cnt,
+                        timeout: limit,
+                    })
+                });
+                (seq_cnt, result)
+            })
+            .collect()
+    }
+}
+
+/// Sends probes for a `Pinger`, see `Pinger::probe_sender`.
+#[derive(Clone)]
+pub struct ProbeSender {
+    destination: IpAddr,
+    config: PingerConfig,
+    socket: Arc<dyn Transport>,
+    cache: Cache,
+    probes: ProbeQueue,
+    seq: Arc<AtomicU16>,
+}
+
+impl ProbeSender {
+    /// Reserve the next sequence number of the pinger, see `Pinger::next_seq`.
+    pub fn next_seq(&self) -> u16 {
+        self.seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Send an echo request with sequence number, see `Pinger::send_probe`.
+    pub async fn send_probe(&self, seq_cnt: u16) -> Result<SentProbe> {
+        send_probe(
+            self.destination,
+            &self.config,
+            self.socket.as_ref(),
+            &self.cache,
+            &self.probes,
+            seq_cnt,
+        )
+        .await
+    }
+}
+
+/// A deterministic identification for `seed`.
+fn ident_from_seed(seed: u64) -> u16 {
+    StdRng::seed_from_u64(seed).gen()
+}
+
+/// Encode an echo request with `size` data bytes into `buf`, reusing its
+/// allocation.
+fn encode(
+    buf: &mut Vec<u8>,
+    destination: IpAddr,
+    ident: u16,
+    seq_cnt: u16,
+    size: usize,
+    payload: &[u8],
+    recv_packet_size: usize,
+) -> Result<()> {
+    check_payload_size(destination, size, recv_packet_size)?;
+    buf.clear();
+    buf.resize(ICMP_HEADER_SIZE + size, 0);
+    match destination {
+        IpAddr::V4(_) => icmpv4::write_icmpv4_echo_packet(buf, ident, seq_cnt, payload),
+        IpAddr::V6(_) => icmpv6::write_icmpv6_echo_packet(buf, ident, seq_cnt, payload),
+    }
+}
+
+async fn send_probe(
+    destination: IpAddr,
+    config: &PingerConfig,
+    socket: &dyn Transport,
+    cache: &Cache,
+    probes: &ProbeQueue,
+    seq_cnt: u16,
+) -> Result<SentProbe> {
+    let mut data = config.data();
+    if config.timestamp_active() {
+        write_timestamp(&mut data, socket.now());
+    }
+    let nonce = config.write_nonce(&mut data);
+    let mut packet = Vec::new();
+    encode(
+        &mut packet,
+        destination,
+        config.ident,
+        seq_cnt,
+        data.len(),
+        &data,
+        socket.recv_packet_size(),
+    )?;
+    socket.reserve_recv_packet_size(reply_packet_size(destination, data.len()));
+    let sent = socket.now();
+    cache.insert(config.ident, seq_cnt, sent, config.timeout, nonce);
+    if let Err(e) = socket
+        .send_to(&packet, SocketAddr::new(destination, 0))
+        .await
+    {
+        trace!("socket send packet error: {}", e);
+        cache.remove(config.ident, seq_cnt);
+        return Err(SurgeError::from_send(destination, e));
+    }
+    meter::sent(destination);
+    probes.lock().push_back((seq_cnt, sent));
+    Ok(SentProbe { seq: seq_cnt, sent })
+}
+
+/// Offset of the first byte after `skip` where the echoed payload differs from
+/// the sent one, a truncated echo differs at its end.
+fn first_mismatch(sent: &[u8], echoed: &[u8], skip: usize) -> Option<usize> {
+    sent.iter()
+        .zip(echoed.iter())
+        .enumerate()
+        .skip(skip)
+        .find(|(_, (a, b))| a != b)
+        .map(|(offset, _)| offset)
+        .or_else(|| {
+            if echoed.len() < sent.len() {
+                Some(echoed.len().max(skip))
+            } else {
+                None
             }
+        })
+}
+
+/// Details of the reply `packet` decoded from `response`, answering request
+/// `seq` of `ident` after `rtt`.
+fn reply_meta(
+    response: &PingResponse,
+    packet: &IcmpPacket,
+    seq: u16,
+    ident: u16,
+    rtt: Duration,
+) -> ReplyMeta {
+    ReplyMeta {
+        seq,
+        ident,
+        rtt,
+        received: response.when,
+        source: SocketAddr::new(response.from, 0),
+        ttl: packet.get_ttl(),
+        len: response.packet.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const TASKS: u16 = 16;
+    const PER_TASK: u16 = 100_000 / TASKS;
+
+    #[test]
+    fn cache_stress() {
+        let cache = Cache::new();
+        let lifetime = Duration::from_secs(60);
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|ident| {
+                let cache = cache.clone();
+                thread::spawn(move || {
+                    let sent = Instant::now();
+                    for seq_cnt in 0..PER_TASK {
+                        cache.insert(ident, seq_cnt, sent, lifetime, Some(u64::from(seq_cnt)));
+                    }
+                    for seq_cnt in 0..PER_TASK {
+                        assert_eq!(cache.get(ident, seq_cnt), Some(sent));
+                        assert_eq!(cache.nonce(ident, seq_cnt), Some(u64::from(seq_cnt)));
+                        assert_eq!(cache.remove(ident, seq_cnt), Some(sent));
+                        assert_eq!(cache.remove(ident, seq_cnt), None);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.join().unwrap();
         }
+        assert_eq!(cache.len(), 0);
+        assert!(cache.high_water() >= usize::from(PER_TASK));
+        assert!(cache.high_water() <= usize::from(TASKS) * usize::from(PER_TASK));
     }
 }
@@ -1,47 +1,231 @@
 use std::sync::Arc;
-use std::{io, net::IpAddr};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr},
+};
 
-use crate::ping::Pinger;
-use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-use std::collections::BTreeMap;
+use crate::batch::{RecvBatch, MAX_RECV_BATCH};
+use crate::clock::{Clock, TokioClock};
+use crate::error::{Result, SurgeError};
+use crate::errqueue::IcmpErrorReport;
+#[cfg(target_os = "linux")]
+use crate::errqueue::Queued;
+use crate::icmp::{
+    check_payload_size, icmpv4, icmpv6, peek_ident, reply_packet_size, ICMP_HEADER_SIZE,
+    MAX_ICMPV6_PAYLOAD,
+};
+use crate::ident::IdentRegistry;
+use crate::kstamp::{KernelTimestamp, TxStamps};
+use crate::meter;
+use crate::ping::{Pinger, PingerBuilder, PingerConfig};
+use crate::pmap::{Dispatch, PingerMap};
+#[cfg(target_os = "linux")]
+use crate::pool::BufferPool;
+use crate::pool::PooledBuffer;
+use crate::rt::{self, UdpSocket};
+use crate::stats::PingStats;
+use crate::tap::{Tap, TapSet};
+use crate::transport::Marking;
+use log::trace;
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, Type};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc::{channel, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::channel;
+use tokio::sync::{watch, Mutex};
 
 #[cfg(unix)]
-use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 #[cfg(windows)]
-use std::os::windows::io::{FromRawSocket, IntoRawSocket};
+use std::os::windows::io::{AsRawSocket, FromRawSocket, IntoRawSocket, RawSocket};
 
 const DEFAULT_LIMIT_PPS: usize = 10000;
+/// Fits a reply to a ping filling a 9000 bytes jumbo frame.
+const DEFAULT_RECV_PACKET_SIZE: usize = 9216;
+/// The minimum datagram size every IPv4 host must accept.
+const MIN_RECV_PACKET_SIZE: usize = 576;
+pub(crate) const MAX_RECV_PACKET_SIZE: usize = 65535;
+/// Sends retried after a transient failure by default.
+const DEFAULT_SEND_RETRIES: usize = 3;
+/// Wait before the first retry of a send, doubled on each further retry.
+const SEND_RETRY_BACKOFF: Duration = Duration::from_millis(1);
+
+/// `ENOBUFS`: the interface queue or the socket send buffer is full.
+#[cfg(target_os = "linux")]
+const ENOBUFS: Option<i32> = Some(libc::ENOBUFS);
+#[cfg(all(unix, not(target_os = "linux")))]
+const ENOBUFS: Option<i32> = Some(55);
+/// `WSAENOBUFS`.
+#[cfg(windows)]
+const ENOBUFS: Option<i32> = Some(10055);
+#[cfg(not(any(unix, windows)))]
+const ENOBUFS: Option<i32> = None;
+
+/// Whether a send failed for lack of buffer space, and may succeed later.
+fn is_transient_send_error(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || (ENOBUFS.is_some() && e.raw_os_error() == ENOBUFS)
+}
 
 pub(crate) struct PingResponse {
     pub when: Instant,
-    pub packet: Vec<u8>,
+    /// Kernel receive timestamp, on sockets timestamping packets.
+    pub stamp: Option<KernelTimestamp>,
+    /// Source address of the packet.
+    pub from: IpAddr,
+    pub packet: PooledBuffer,
+    /// Set instead of a packet for errors read from the socket error queue.
+    pub error: Option<IcmpErrorReport>,
 }
 impl PingResponse {
-    pub fn new(when: Instant, packet: Vec<u8>) -> PingResponse {
-        PingResponse { when, packet }
+    pub fn new(
+        when: Instant,
+        stamp: Option<KernelTimestamp>,
+        from: IpAddr,
+        packet: PooledBuffer,
+    ) -> PingResponse {
+        PingResponse {
+            when,
+            stamp,
+            from,
+            packet,
+            error: None,
+        }
     }
+    #[cfg(target_os = "linux")]
+    pub fn error(when: Instant, packet: PooledBuffer, report: IcmpErrorReport) -> PingResponse {
+        PingResponse {
+            when,
+            stamp: None,
+            from: report.offender.unwrap_or(report.destination),
+            packet,
+            error: Some(report),
+        }
+    }
+}
+/// Type of the socket opened by a `PingSocketBuilder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    /// A raw ICMP socket, requiring privileges.
+    Raw,
+    /// An unprivileged ICMP datagram socket. The kernel sets the identifier
+    /// of the requests and only delivers the replies to them.
+    Dgram,
 }
 pub struct PingSocketBuilder {
     socket: Socket,
+    domain: Domain,
+    options: SocketOptions,
+    binds: parking_lot::Mutex<Binds>,
+}
+/// Bindings recorded by `PingSocketBuilder`, applied in order on build.
+#[derive(Default)]
+struct Binds {
+    /// Device to bind to, `Some(None)` to remove the binding.
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    device: Option<Option<Vec<u8>>>,
+    /// Source address to bind to.
+    addr: Option<SockAddr>,
+}
+/// Socket options handled by the library rather than the kernel.
+// The receive error queue and outgoing interface options are Linux only.
+#[derive(Clone)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct SocketOptions {
     send_limit_pps: usize,
+    /// Requests sent at once before pacing, 0 for `send_limit_pps / 100`.
+    send_burst: usize,
+    recv_batch_size: usize,
+    recv_packet_size: usize,
+    recv_err: bool,
+    outgoing_if6: Option<u32>,
+    /// Whether the socket belongs to a single pinger, which may connect it.
+    exclusive: bool,
+    ipv6: bool,
+    /// Whether the socket is an ICMP datagram socket, receiving IPv4 replies
+    /// without their IP header.
+    dgram: bool,
+    route_by_ident: bool,
+    /// Whether the kernel timestamps the packets sent and received.
+    timestamping: bool,
+    clock: Arc<dyn Clock>,
+    /// Largest number of requests sent per second to one destination, 0 for
+    /// no limit.
+    per_target_limit_pps: usize,
+    /// Retries of a send failing with `ENOBUFS` or `EAGAIN`.
+    send_retries: usize,
+}
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            send_limit_pps: DEFAULT_LIMIT_PPS,
+            send_burst: 0,
+            recv_batch_size: MAX_RECV_BATCH,
+            recv_packet_size: MAX_RECV_PACKET_SIZE,
+            recv_err: false,
+            outgoing_if6: None,
+            exclusive: false,
+            ipv6: false,
+            dgram: false,
+            route_by_ident: false,
+            timestamping: false,
+            clock: Arc::new(TokioClock),
+            per_target_limit_pps: 0,
+            send_retries: DEFAULT_SEND_RETRIES,
+        }
+    }
 }
 impl PingSocketBuilder {
-    pub fn new(d: Domain) -> io::Result<PingSocketBuilder> {
-        let socket = match d {
-            Domain::IPV4 => Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?,
-            Domain::IPV6 => Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?,
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid domain",
-                ))
-            }
+    /// Creates a raw ICMP socket builder for the given domain.
+    ///
+    /// Returns `SurgeError::PermissionDenied` if the process lacks the
+    /// privileges required to open a raw socket, see `new_auto`.
+    pub fn new(d: Domain) -> Result<PingSocketBuilder> {
+        match d {
+            Domain::IPV4 => Self::with_protocol(Domain::IPV4, Protocol::ICMPV4),
+            Domain::IPV6 => Self::with_protocol(Domain::IPV6, Protocol::ICMPV6),
+            _ => Err(SurgeError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid domain",
+            ))),
+        }
+    }
+
+    /// Creates a raw socket builder for any IP protocol number.
+    ///
+    /// Advanced: nothing but the socket type assumes ICMP here, while pingers
+    /// created from the socket still send ICMP echo requests and decode the
+    /// received packets as ICMP. With another protocol, read the packets with
+    /// `PingSocket::tap` and decode them yourself.
+    pub fn with_protocol(d: Domain, protocol: Protocol) -> Result<PingSocketBuilder> {
+        Self::open(d, Type::RAW, protocol)
+    }
+
+    /// Creates an ICMP socket builder for the given domain, opening a raw
+    /// socket if the process is allowed to, an unprivileged datagram socket
+    /// otherwise. See `socket_kind` for the socket opened.
+    ///
+    /// Datagram ICMP sockets are available on Linux to the groups in the
+    /// `net.ipv4.ping_group_range` sysctl, and on macOS. The identifier of
+    /// the requests is chosen by the kernel, so pingers ignore their own.
+    /// Returns `SurgeError::PermissionDenied` if neither can be opened.
+    pub fn new_auto(d: Domain) -> Result<PingSocketBuilder> {
+        let protocol = match d {
+            Domain::IPV4 => Protocol::ICMPV4,
+            Domain::IPV6 => Protocol::ICMPV6,
+            _ => return Self::new(d),
         };
+        match Self::open(d, Type::RAW, protocol) {
+            Err(SurgeError::PermissionDenied(e)) => {
+                trace!("raw ICMP socket denied, fall back to datagram: {}", e);
+                Self::open(d, Type::DGRAM, protocol).map_err(|_| SurgeError::PermissionDenied(e))
+            }
+            res => res,
+        }
+    }
+
+    fn open(d: Domain, ty: Type, protocol: Protocol) -> Result<PingSocketBuilder> {
+        let socket = Socket::new(d, ty, Some(protocol)).map_err(SurgeError::from_socket_create)?;
 
         // TODO: Type filtering,
         // https://tools.ietf.org/html/rfc3542#section-3.2. Currently blocked
@@ -53,12 +237,89 @@ impl PingSocketBuilder {
         socket.set_nonblocking(true)?;
         Ok(PingSocketBuilder {
             socket,
-            send_limit_pps: DEFAULT_LIMIT_PPS,
+            domain: d,
+            options: SocketOptions {
+                ipv6: d == Domain::IPV6,
+                dgram: ty == Type::DGRAM,
+                ..SocketOptions::default()
+            },
+            binds: Default::default(),
+        })
+    }
+    /// Creates a builder from an already configured `socket2::Socket`, for
+    /// socket options the builder doesn't expose.
+    ///
+    /// The socket must be a raw or datagram ICMPv4 or ICMPv6 socket,
+    /// otherwise an `InvalidInput` error is returned. It is switched to
+    /// non-blocking mode.
+    pub fn from_socket(socket: Socket) -> Result<PingSocketBuilder> {
+        let invalid = |msg: &str| {
+            SurgeError::IOError(io::Error::new(io::ErrorKind::InvalidInput, msg.to_string()))
+        };
+        let ty = socket.r#type()?;
+        if ty != Type::RAW && ty != Type::DGRAM {
+            return Err(invalid("not a raw or datagram socket"));
+        }
+        let domain = socket.local_addr()?.domain();
+        if domain != Domain::IPV4 && domain != Domain::IPV6 {
+            return Err(invalid("not an IPv4 or IPv6 socket"));
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        {
+            let protocol = if domain == Domain::IPV4 {
+                Protocol::ICMPV4
+            } else {
+                Protocol::ICMPV6
+            };
+            if socket.protocol()? != Some(protocol) {
+                return Err(invalid("not an ICMP socket"));
+            }
+        }
+        socket.set_nonblocking(true)?;
+        Ok(PingSocketBuilder {
+            socket,
+            domain,
+            options: SocketOptions {
+                ipv6: domain == Domain::IPV6,
+                dgram: ty == Type::DGRAM,
+                ..SocketOptions::default()
+            },
+            binds: Default::default(),
         })
     }
+
+    /// Whether the builder opened a raw or an unprivileged datagram socket.
+    pub fn socket_kind(&self) -> SocketKind {
+        if self.options.dgram {
+            SocketKind::Dgram
+        } else {
+            SocketKind::Raw
+        }
+    }
+
+    /// Bind the socket to the device `interface` (`SO_BINDTODEVICE`), `None`
+    /// to remove the binding.
+    ///
+    /// Applied by `build`, before `bind_addr`, whatever the order of the
+    /// calls: the kernel then picks the source address and route among
+    /// those of the device.
+    ///
+    /// Fails with `InvalidInput` if `interface` isn't a valid interface
+    /// name. `build` fails if no device has that name.
     #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
     pub fn bind_device(&self, interface: Option<&[u8]>) -> io::Result<()> {
-        self.socket.bind_device(interface)
+        // IFNAMSIZ, the terminating NUL included.
+        const MAX_NAME_LEN: usize = 16;
+        if let Some(name) = interface {
+            if name.len() >= MAX_NAME_LEN || name.contains(&0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid interface name",
+                ));
+            }
+        }
+        self.binds.lock().device = Some(interface.map(<[u8]>::to_vec));
+        Ok(())
     }
 
     #[cfg(target_os = "freebsd")]
@@ -66,16 +327,181 @@ impl PingSocketBuilder {
         self.socket.set_fib(fib)
     }
 
+    /// Bind the socket to the source address `sock_addr`.
+    ///
+    /// Applied by `build`, after `bind_device`. Pingers connect the socket
+    /// they own after that, see `Pinger::connect`.
+    ///
+    /// Fails with `InvalidInput` if `sock_addr` isn't of the address family
+    /// of the socket. `build` fails if the address can't be bound, e.g. isn't
+    /// local.
     pub fn bind_addr(&self, sock_addr: &SockAddr) -> io::Result<()> {
-        self.socket.bind(sock_addr)
+        if sock_addr.domain() != self.domain {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "source address of another family than the socket",
+            ));
+        }
+        self.binds.lock().addr = Some(sock_addr.clone());
+        Ok(())
     }
 
+    /// Set the TTL of IPv4 requests, or the hop limit of IPv6 requests.
     pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
-        self.socket.set_ttl(ttl)
+        set_socket_ttl(&self.socket, self.domain == Domain::IPV6, ttl)
     }
 
+    /// The TTL of IPv4 requests, or the hop limit of IPv6 requests.
+    pub fn ttl(&self) -> io::Result<u32> {
+        socket_ttl(&self.socket, self.domain == Domain::IPV6)
+    }
+
+    /// Set the largest number of requests sent per second on the socket, 0
+    /// for no limit. (default: 10000)
+    ///
+    /// Bursts of up to `limit / 100` requests, those of 10 ms at that rate,
+    /// are sent at once, see `set_send_limit`. Without a limit sends don't
+    /// synchronize with each other.
     pub fn set_send_limit_pps(&mut self, limit: usize) -> io::Result<()> {
-        self.send_limit_pps = limit;
+        self.options.send_limit_pps = limit;
+        self.options.send_burst = 0;
+        Ok(())
+    }
+
+    /// Set the largest number of requests sent per second to each
+    /// destination, 0 for no limit. (default: 0)
+    ///
+    /// Requests over the limit wait, like those over the socket's limit,
+    /// which still applies on top. See `PingSocket::per_target_throttled`.
+    pub fn set_per_target_limit_pps(&mut self, limit: usize) -> io::Result<()> {
+        self.options.per_target_limit_pps = limit;
+        Ok(())
+    }
+
+    /// Set how many times a send failing with `ENOBUFS` or `EAGAIN` is
+    /// retried before the ping fails, 0 to fail at once. (default: 3)
+    ///
+    /// Retries back off from 1 ms, doubling each time. See
+    /// `PingSocket::send_retries`.
+    pub fn set_send_retries(&mut self, limit: usize) -> io::Result<()> {
+        self.options.send_retries = limit;
+        Ok(())
+    }
+
+    /// Limit the requests sent on the socket with a token bucket: up to
+    /// `burst` requests are sent at once, then at `rate_pps` per second
+    /// until the bucket refilled. A `rate_pps` of 0 disables the limit.
+    pub fn set_send_limit(&mut self, rate_pps: usize, burst: usize) -> io::Result<()> {
+        self.options.send_limit_pps = rate_pps;
+        self.options.send_burst = burst.max(1);
+        Ok(())
+    }
+
+    /// Set how many packets the receive task reads per wakeup, at most 64.
+    ///
+    /// On Linux a whole batch is read with a single `recvmmsg` call, other
+    /// platforms always read one packet at a time.
+    pub fn set_recv_batch_size(&mut self, size: usize) -> io::Result<()> {
+        self.options.recv_batch_size = size.clamp(1, MAX_RECV_BATCH);
+        Ok(())
+    }
+
+    /// Set the size of the largest packet received, IP header included for
+    /// IPv4, between 576 and 65535 bytes. (default: 65535)
+    ///
+    /// Receive buffers start at 9216 bytes, enough for jumbo frames, and grow
+    /// up to this size to fit the replies to the largest requests sent on the
+    /// socket. Pings whose reply wouldn't fit fail with
+    /// `SurgeError::InvalidSize`, larger packets received anyway are
+    /// truncated and fail to match their request.
+    pub fn set_recv_packet_size(&mut self, size: usize) -> io::Result<()> {
+        self.options.recv_packet_size = size.clamp(MIN_RECV_PACKET_SIZE, MAX_RECV_PACKET_SIZE);
+        Ok(())
+    }
+
+    /// Set the socket level receive timeout (`SO_RCVTIMEO`), `None` to clear it.
+    ///
+    /// This is a safety net independent from the pinger timeout. The socket
+    /// is non-blocking and received from when it is readable, so the option
+    /// only bounds a receive call that would otherwise block in the kernel;
+    /// timeouts of pings are always handled by the pinger. Keep it longer
+    /// than the pinger timeout so it never fires first.
+    pub fn set_recv_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Get the socket level receive timeout (`SO_RCVTIMEO`).
+    pub fn recv_timeout(&self) -> io::Result<Option<Duration>> {
+        self.socket.read_timeout()
+    }
+
+    /// Receive ICMP errors through the socket error queue (`IP_RECVERR` or
+    /// `IPV6_RECVERR`), off by default.
+    ///
+    /// The kernel matches each error with the echo request it answers and
+    /// reports the address of the host that sent it, so a ping failing with
+    /// an error returns `SurgeError::IcmpError` with these details. The queue
+    /// is read by the receive task whenever the socket wakes it up.
+    #[cfg(target_os = "linux")]
+    pub fn set_recv_err(&mut self, enable: bool) -> io::Result<()> {
+        crate::errqueue::set_recv_err(
+            self.socket.as_raw_fd(),
+            self.domain == Domain::IPV6,
+            enable,
+        )?;
+        self.options.recv_err = enable;
+        Ok(())
+    }
+
+    /// Timestamp the requests and replies in the kernel (`SO_TIMESTAMPING`),
+    /// off by default. The RTT measured from these timestamps is free of
+    /// scheduling delays, see `PingResult::kernel_rtt`.
+    ///
+    /// Hardware timestamps are used when the NIC was set up to take them,
+    /// software timestamps taken by the kernel otherwise. Pings whose request
+    /// or reply wasn't timestamped only have the userspace RTT.
+    #[cfg(target_os = "linux")]
+    pub fn set_kernel_timestamps(&mut self, enable: bool) -> io::Result<()> {
+        crate::kstamp::set_timestamping(self.socket.as_raw_fd(), enable)?;
+        self.options.timestamping = enable;
+        Ok(())
+    }
+
+    /// Send echo requests to link-local and multicast IPv6 destinations
+    /// through the interface with index `scope_id`, using an `IPV6_PKTINFO`
+    /// control message instead of binding the socket to the interface.
+    ///
+    /// Requests to global destinations still follow the routing table. Fails
+    /// with `InvalidInput` on an IPv4 socket.
+    #[cfg(target_os = "linux")]
+    pub fn set_outgoing_if6(&mut self, scope_id: u32) -> io::Result<()> {
+        if self.domain != Domain::IPV6 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "outgoing interface needs an IPv6 socket",
+            ));
+        }
+        self.options.outgoing_if6 = Some(scope_id);
+        Ok(())
+    }
+
+    /// Route received packets to pingers by the ICMP identifier of the
+    /// request they answer instead of by source address. (default: false)
+    ///
+    /// Pingers then receive the ICMP errors sent by routers on the path and
+    /// echo replies coming from another address than the destination, e.g.
+    /// from an anycast or NATed host, and accept them as long as identifier
+    /// and sequence match. Packets with an unknown identifier are still
+    /// routed by source address.
+    pub fn set_route_by_ident(&mut self, enable: bool) -> io::Result<()> {
+        self.options.route_by_ident = enable;
+        Ok(())
+    }
+
+    /// Set the clock the send and receive times are read from, and the
+    /// send rate limit paced with. (default: `TokioClock`)
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) -> io::Result<()> {
+        self.options.clock = clock;
         Ok(())
     }
 
@@ -97,161 +523,715 @@ impl PingSocketBuilder {
         });
     }
 
-    pub fn build(self) -> io::Result<PingSocket> {
-        let limit = self.send_limit_pps;
-        PingSocket::new_socket(AsyncSocket::new(self.inner_run()?, limit))
+    /// Bind the socket to its device, then to its source address.
+    fn apply_binds(&self) -> io::Result<()> {
+        let binds = self.binds.lock();
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(interface) = &binds.device {
+            self.socket.bind_device(interface.as_deref())?;
+        }
+        if let Some(addr) = &binds.addr {
+            self.socket.bind(addr)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the socket, bound to the device set with `bind_device`, then
+    /// to the source address set with `bind_addr`.
+    pub fn build(self) -> Result<PingSocket> {
+        self.apply_binds()?;
+        let options = self.options.clone();
+        let domain = self.domain;
+        Ok(PingSocket::new_socket(
+            AsyncSocket::new(self.inner_run()?, options),
+            domain,
+        ))
     }
 }
-struct LimitBasket {
+/// Token bucket pacing the sends of a socket: up to `burst` requests go
+/// out at once, the bucket refilling at `rate_pps` tokens per second.
+struct TokenBucket {
+    rate_pps: f64,
+    burst: f64,
+    /// Tokens available, negative when sends are waiting for tokens.
+    tokens: f64,
     last: Option<Instant>,
-    cnt: usize,
-    limit_pps: usize,
-    minwait_time: Duration,
 }
-impl LimitBasket {
-    fn new(limit_pps: usize) -> LimitBasket {
-        LimitBasket {
+impl TokenBucket {
+    fn new(rate_pps: usize, burst: usize) -> TokenBucket {
+        let burst = burst.max(1) as f64;
+        TokenBucket {
+            rate_pps: rate_pps as f64,
+            burst,
+            tokens: burst,
             last: None,
-            cnt: 0,
-            limit_pps,
-            minwait_time: Duration::from_millis(1),
         }
     }
-    async fn shot(&mut self) {
-        let mut nw = Instant::now();
+    /// Take `n` tokens at `now`, returning how long to wait until they are
+    /// refilled when the bucket runs short.
+    fn take(&mut self, n: usize, now: Instant) -> Duration {
+        if let Some(last) = self.last {
+            let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate_pps;
+            self.tokens = (self.tokens + refill).min(self.burst);
+        }
+        self.last = Some(now);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate_pps)
+        }
+    }
+    /// Whether the bucket refilled completely by `now`, which makes it
+    /// equivalent to a new one.
+    fn is_full(&self, now: Instant) -> bool {
         match self.last {
-            None => {
-                self.last = Some(nw);
-                self.cnt = 1;
-                return;
-            }
-            Some(l) => {
-                let elapsed = (nw - l).as_secs_f64();
-                let mut sub_pps = ((self.limit_pps as f64) * elapsed).trunc();
-                if sub_pps < 0f64 {
-                    sub_pps = 0f64;
-                }
-                let sub_pps = sub_pps as usize;
-                if self.cnt <= sub_pps {
-                    self.cnt = 0;
-                } else {
-                    self.cnt -= sub_pps;
-                }
-                if self.cnt > 0 {
-                    let wd = Duration::from_secs_f64((self.cnt as f64) / (self.limit_pps as f64));
-                    if wd >= self.minwait_time {
-                        tokio::time::sleep(wd).await;
-                        self.cnt = 0;
-                        nw = Instant::now();
-                    }
-                }
-                self.cnt += 1;
-                self.last = Some(nw);
+            Some(last) => {
+                let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate_pps;
+                self.tokens + refill >= self.burst
             }
+            None => true,
+        }
+    }
+}
+/// Token buckets limiting the requests sent to each destination.
+struct TargetLimiter {
+    rate_pps: usize,
+    buckets: parking_lot::Mutex<HashMap<IpAddr, TokenBucket>>,
+    /// Number of sends delayed by the limit of their destination.
+    throttled: AtomicU64,
+}
+impl TargetLimiter {
+    /// Number of buckets above which full ones are evicted.
+    const EVICT_THRESHOLD: usize = 1024;
+
+    fn new(rate_pps: usize) -> TargetLimiter {
+        TargetLimiter {
+            rate_pps,
+            buckets: parking_lot::Mutex::new(HashMap::new()),
+            throttled: AtomicU64::new(0),
+        }
+    }
+    /// Take a token for each of `targets` at `now`, returning how long to
+    /// wait until the last one is available.
+    fn take<'a, I: IntoIterator<Item = &'a SocketAddr>>(
+        &self,
+        targets: I,
+        now: Instant,
+    ) -> Duration {
+        let mut buckets = self.buckets.lock();
+        if buckets.len() > Self::EVICT_THRESHOLD {
+            // Buckets of destinations idle long enough to have refilled are
+            // recreated on demand.
+            buckets.retain(|_, bucket| !bucket.is_full(now));
+        }
+        let mut wait = Duration::ZERO;
+        for target in targets {
+            let rate_pps = self.rate_pps;
+            let bucket = buckets
+                .entry(target.ip())
+                .or_insert_with(|| TokenBucket::new(rate_pps, rate_pps / 100));
+            wait = wait.max(bucket.take(1, now));
+        }
+        if !wait.is_zero() {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
         }
+        wait
+    }
+}
+/// Set the TTL of an IPv4 socket, or the unicast hop limit of an IPv6 one.
+fn set_socket_ttl(socket: &Socket, ipv6: bool, ttl: u32) -> io::Result<()> {
+    if ipv6 {
+        socket.set_unicast_hops_v6(ttl)
+    } else {
+        socket.set_ttl(ttl)
+    }
+}
+
+/// The TTL of an IPv4 socket, or the unicast hop limit of an IPv6 one.
+fn socket_ttl(socket: &Socket, ipv6: bool) -> io::Result<u32> {
+    if ipv6 {
+        socket.unicast_hops_v6()
+    } else {
+        socket.ttl()
     }
 }
+
 struct InnerSocket {
     socket: UdpSocket,
-    limit: Mutex<LimitBasket>,
+    /// The send rate limiter, `None` when sending is unlimited.
+    limit: Option<parking_lot::Mutex<TokenBucket>>,
+    /// The per destination rate limiter, `None` when unlimited.
+    target_limit: Option<TargetLimiter>,
+    options: SocketOptions,
+    /// Current size of the receive buffers, up to `options.recv_packet_size`.
+    recv_buf_size: AtomicUsize,
+    /// Address the socket is connected to.
+    peer: parking_lot::Mutex<Option<SocketAddr>>,
+    /// Whether sending is paused, the receiver is kept so sending the
+    /// state never fails.
+    paused: (watch::Sender<bool>, watch::Receiver<bool>),
+    /// Send timestamps, on sockets timestamping packets.
+    tx_stamps: Option<parking_lot::Mutex<TxStamps>>,
+    /// Sends retried after a transient failure.
+    send_retries: AtomicU64,
+    /// Buffer the error queue is read into, kept across wakeups.
+    #[cfg(target_os = "linux")]
+    err_buf: Arc<BufferPool>,
 }
 impl InnerSocket {
-    fn new(socket: UdpSocket, send_limit_pps: usize) -> Self {
+    fn new(socket: UdpSocket, options: SocketOptions) -> Self {
         InnerSocket {
             socket,
-            limit: Mutex::new(LimitBasket::new(send_limit_pps)),
+            limit: (options.send_limit_pps > 0).then(|| {
+                let burst = match options.send_burst {
+                    0 => options.send_limit_pps / 100,
+                    burst => burst,
+                };
+                parking_lot::Mutex::new(TokenBucket::new(options.send_limit_pps, burst))
+            }),
+            target_limit: (options.per_target_limit_pps > 0)
+                .then(|| TargetLimiter::new(options.per_target_limit_pps)),
+            recv_buf_size: AtomicUsize::new(DEFAULT_RECV_PACKET_SIZE.min(options.recv_packet_size)),
+            peer: parking_lot::Mutex::new(None),
+            paused: watch::channel(false),
+            tx_stamps: options
+                .timestamping
+                .then(|| parking_lot::Mutex::new(TxStamps::default())),
+            send_retries: AtomicU64::new(0),
+            #[cfg(target_os = "linux")]
+            err_buf: BufferPool::new(1),
+            options,
         }
     }
-    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.socket.recv_from(buf).await
+    /// Grow the receive buffers to fit packets of `size` bytes, up to the
+    /// configured maximum.
+    fn reserve_recv_packet_size(&self, size: usize) {
+        let size = size.min(self.options.recv_packet_size);
+        self.recv_buf_size.fetch_max(size, Ordering::Relaxed);
+    }
+    /// Receive up to a batch of packets per wakeup.
+    ///
+    /// On Linux this drains the socket with a single `recvmmsg` call, other
+    /// platforms fall back to one `recv_from` per call.
+    #[cfg(target_os = "linux")]
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        let fd = self.socket.as_raw_fd();
+        self.socket
+            .read_with(|| {
+                // Replies are received after their request reserved room for
+                // them, so the buffers are large enough once readable.
+                batch.reserve(self.recv_buf_size.load(Ordering::Relaxed));
+                batch.recvmmsg(fd)
+            })
+            .await
+    }
+    #[cfg(not(target_os = "linux"))]
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        self.socket.readable().await?;
+        batch.reserve(self.recv_buf_size.load(Ordering::Relaxed));
+        let (sz, addr) = self.socket.recv_from(batch.first_buf_mut()).await?;
+        batch.set_single(sz, addr);
+        Ok(1)
     }
-    pub async fn send_to(&self, buf: &mut [u8], target: &SocketAddr) -> io::Result<usize> {
+    /// Read the socket error queue until it's empty, recording the send
+    /// timestamps and returning the ICMP errors.
+    #[cfg(target_os = "linux")]
+    pub fn recv_errors(&self) -> io::Result<Vec<IcmpErrorReport>> {
+        let mut reports = Vec::new();
+        if !self.options.recv_err && self.tx_stamps.is_none() {
+            return Ok(reports);
+        }
+        let fd = self.socket.as_raw_fd();
+        // Room for the sent packet looped back with its link and IP headers.
+        let mut data = self
+            .err_buf
+            .get_zeroed(self.recv_buf_size.load(Ordering::Relaxed) + 128);
+        while let Some(queued) = crate::errqueue::recv_err(fd, &mut data)? {
+            match queued {
+                Queued::Error(report) => reports.push(report),
+                Queued::Sent { stamp, len } => {
+                    if let Some(tx_stamps) = &self.tx_stamps {
+                        tx_stamps.lock().stamped(&data[..len], stamp);
+                    }
+                }
+            }
+        }
+        Ok(reports)
+    }
+    /// Record that `packet` is being sent, to match its send timestamp.
+    fn sending(&self, packet: &[u8]) {
+        if let Some(tx_stamps) = &self.tx_stamps {
+            tx_stamps.lock().sending(packet);
+        }
+    }
+    fn take_tx_timestamp(&self, ident: u16, seq: u16) -> Option<KernelTimestamp> {
+        self.tx_stamps.as_ref()?.lock().take(ident, seq)
+    }
+    /// Wait until sending isn't paused.
+    async fn wait_resumed(&self) {
+        let mut paused = self.paused.1.clone();
+        while *paused.borrow() {
+            if paused.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+    /// Wait for the rate limits before sending `n` packets to `targets`.
+    ///
+    /// The tokens are taken under the limiters' locks but waited for without
+    /// them, so concurrent senders are paced in the order they took their
+    /// tokens instead of queuing behind the lock of a waiting sender.
+    async fn throttle(&self, targets: &[SocketAddr], n: usize) {
+        let now = self.options.clock.now();
+        let mut wait = self
+            .target_limit
+            .as_ref()
+            .map_or(Duration::ZERO, |limit| limit.take(targets, now));
+        if let Some(limit) = &self.limit {
+            wait = wait.max(limit.lock().take(n, now));
+        }
+        if !wait.is_zero() {
+            trace_event!(DEBUG, wait = ?wait, count = n, "send rate limited");
+            rt::sleep(wait).await;
+        }
+    }
+    pub async fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        self.send_marked(buf, target, Marking::default()).await
+    }
+    /// Send `buf` to `target` with the TTL and TOS of `marking` set on this
+    /// packet only, with `sendmsg` control messages.
+    pub async fn send_marked(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        marking: Marking,
+    ) -> io::Result<usize> {
+        #[cfg(not(target_os = "linux"))]
+        if !marking.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "marking a single request is only supported on Linux",
+            ));
+        }
+        self.wait_resumed().await;
+        self.throttle(std::slice::from_ref(target), 1).await;
+        self.sending(buf);
+        let mut retries = 0;
+        loop {
+            match self.send_once(buf, target, marking).await {
+                Err(e) if retries < self.options.send_retries && is_transient_send_error(&e) => {
+                    trace!("transient send error to {}, retrying: {}", target, e);
+                    trace_event!(
+                        DEBUG,
+                        dest = %target.ip(),
+                        error = %e,
+                        retry = retries + 1,
+                        "send retried"
+                    );
+                    self.send_retries.fetch_add(1, Ordering::Relaxed);
+                    rt::sleep(SEND_RETRY_BACKOFF * (1 << retries.min(10))).await;
+                    retries += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+    /// Hand `buf` to the kernel once, see `send_marked`.
+    async fn send_once(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        marking: Marking,
+    ) -> io::Result<usize> {
+        if marking.is_empty() && *self.peer.lock() == Some(*target) {
+            return self.socket.send(buf).await;
+        }
+        #[cfg(target_os = "linux")]
+        if !marking.is_empty()
+            || (self.options.outgoing_if6.is_some() && crate::batch::needs_pktinfo6(target))
         {
-            let mut limit_guard = self.limit.lock().await;
-            limit_guard.shot().await;
-        };
+            let fd = self.socket.as_raw_fd();
+            return self
+                .socket
+                .write_with(|| {
+                    crate::batch::sendmsg(fd, buf, target, self.options.outgoing_if6, marking)
+                })
+                .await;
+        }
         self.socket.send_to(buf, target).await
     }
+    /// Set the TTL or hop limit of a single pinger's socket.
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        if !self.options.exclusive {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the TTL of a shared PingSocket is set with PingSocketBuilder::set_ttl",
+            ));
+        }
+        set_socket_ttl(&SockRef::from(&self.socket), self.options.ipv6, ttl)
+    }
+    fn ttl(&self) -> io::Result<u32> {
+        socket_ttl(&SockRef::from(&self.socket), self.options.ipv6)
+    }
+    /// Connect the socket to `target`, see `AsyncSocket::connect`.
+    async fn connect(&self, target: SocketAddr) -> io::Result<bool> {
+        if !self.options.exclusive {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the socket of a PingSocket is shared and can't be connected",
+            ));
+        }
+        match self.socket.connect(target).await {
+            Ok(()) => {
+                *self.peer.lock() = Some(target);
+                Ok(true)
+            }
+            Err(e) => {
+                trace!(
+                    "connect to {} not supported, stay unconnected: {}",
+                    target,
+                    e
+                );
+                *self.peer.lock() = None;
+                Ok(false)
+            }
+        }
+    }
+    /// Send a batch of packets, accounting for the whole batch in the rate limiter.
+    ///
+    /// Returns one result per packet, in order.
+    pub async fn send_batch(
+        &self,
+        packets: &[&[u8]],
+        targets: &[SocketAddr],
+    ) -> Vec<io::Result<usize>> {
+        self.wait_resumed().await;
+        self.throttle(targets, packets.len()).await;
+        for packet in packets {
+            self.sending(packet);
+        }
+        let mut results = Vec::with_capacity(packets.len());
+        #[cfg(target_os = "linux")]
+        {
+            let fd = self.socket.as_raw_fd();
+            let mut start = 0;
+            while start < packets.len() {
+                let sent = self
+                    .socket
+                    .write_with(|| {
+                        crate::batch::sendmmsg(
+                            fd,
+                            &packets[start..],
+                            &targets[start..],
+                            self.options.outgoing_if6,
+                        )
+                    })
+                    .await;
+                match sent {
+                    Ok(sent) => {
+                        results.extend(packets[start..start + sent].iter().map(|p| Ok(p.len())));
+                        start += sent;
+                    }
+                    Err(e) => {
+                        // The first packet of the remaining batch failed, skip it
+                        // and keep submitting the rest.
+                        results.push(Err(e));
+                        start += 1;
+                    }
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        for (packet, target) in packets.iter().zip(targets.iter()) {
+            results.push(self.socket.send_to(packet, target).await);
+        }
+        results
+    }
 }
 #[derive(Clone)]
 pub(crate) struct AsyncSocket {
     inner: Arc<InnerSocket>,
 }
 impl AsyncSocket {
-    fn new(socket: UdpSocket, send_limit_pps: usize) -> Self {
+    fn new(socket: UdpSocket, options: SocketOptions) -> Self {
         AsyncSocket {
-            inner: Arc::new(InnerSocket::new(socket, send_limit_pps)),
+            inner: Arc::new(InnerSocket::new(socket, options)),
         }
     }
-    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.inner.recv_from(buf).await
+    pub fn recv_batch_size(&self) -> usize {
+        self.inner.options.recv_batch_size
+    }
+    pub fn set_paused(&self, paused: bool) {
+        let _ = self.inner.paused.0.send(paused);
+    }
+    pub fn is_paused(&self) -> bool {
+        *self.inner.paused.1.borrow()
+    }
+    pub fn route_by_ident(&self) -> bool {
+        self.inner.options.route_by_ident
+    }
+    pub fn is_ipv6(&self) -> bool {
+        self.inner.options.ipv6
+    }
+    pub fn is_dgram(&self) -> bool {
+        self.inner.options.dgram
+    }
+    pub fn now(&self) -> Instant {
+        self.inner.options.clock.now()
+    }
+    pub fn send_retries(&self) -> u64 {
+        self.inner.send_retries.load(Ordering::Relaxed)
+    }
+    pub fn per_target_throttled(&self) -> u64 {
+        self.inner
+            .target_limit
+            .as_ref()
+            .map_or(0, |limit| limit.throttled.load(Ordering::Relaxed))
+    }
+    pub fn recv_packet_size(&self) -> usize {
+        self.inner.options.recv_packet_size
     }
-    pub async fn send_to(&self, buf: &mut [u8], target: &SocketAddr) -> io::Result<usize> {
+    pub fn reserve_recv_packet_size(&self, size: usize) {
+        self.inner.reserve_recv_packet_size(size)
+    }
+    fn recv_buf_size(&self) -> usize {
+        self.inner.recv_buf_size.load(Ordering::Relaxed)
+    }
+    pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        self.inner.recv_batch(batch).await
+    }
+    #[cfg(target_os = "linux")]
+    pub fn recv_errors(&self) -> io::Result<Vec<IcmpErrorReport>> {
+        self.inner.recv_errors()
+    }
+    pub async fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
         self.inner.send_to(buf, target).await
     }
+    pub async fn send_marked(
+        &self,
+        buf: &[u8],
+        target: &SocketAddr,
+        marking: Marking,
+    ) -> io::Result<usize> {
+        self.inner.send_marked(buf, target, marking).await
+    }
+    pub async fn send_batch(
+        &self,
+        packets: &[&[u8]],
+        targets: &[SocketAddr],
+    ) -> Vec<io::Result<usize>> {
+        self.inner.send_batch(packets, targets).await
+    }
+    /// Local address of the socket, with port 0 as raw sockets have none.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let addr = self.inner.socket.local_addr()?;
+        Ok(SocketAddr::new(addr.ip(), 0))
+    }
+    /// Connect the socket of a single pinger to `target`, so the kernel
+    /// filters the packets of other sources and requests to `target` are
+    /// sent with `send`.
+    ///
+    /// Returns false if the platform doesn't support connecting the socket,
+    /// which then stays unconnected, and an error for a shared socket.
+    pub async fn connect(&self, target: SocketAddr) -> io::Result<bool> {
+        self.inner.connect(target).await
+    }
+    /// Set the TTL, or the hop limit for IPv6, of the socket of a single
+    /// pinger. Fails for a shared socket.
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_ttl(ttl)
+    }
+    /// The TTL, or the hop limit for IPv6, of the socket.
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.inner.ttl()
+    }
+    /// Take the kernel send timestamp of request `seq` of `ident`.
+    pub fn take_tx_timestamp(&self, ident: u16, seq: u16) -> Option<KernelTimestamp> {
+        self.inner.take_tx_timestamp(ident, seq)
+    }
 }
 #[derive(Clone)]
 pub struct PingSocket {
     inner: AsyncSocket,
-    pmap: Arc<Mutex<BTreeMap<IpAddr, Sender<PingResponse>>>>,
-    recv_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    domain: Domain,
+    pmap: Arc<PingerMap>,
+    taps: Arc<TapSet>,
+    recv_task: Arc<Mutex<Option<rt::JoinHandle<()>>>>,
+    send_arena: Arc<Mutex<Vec<u8>>>,
+    idents: Arc<IdentRegistry>,
 }
 
 impl PingSocket {
-    pub fn new(d: Domain) -> io::Result<PingSocket> {
+    pub fn new(d: Domain) -> Result<PingSocket> {
         PingSocketBuilder::new(d)?.build()
     }
-    fn new_socket(inner: AsyncSocket) -> io::Result<PingSocket> {
-        Ok(PingSocket {
+    fn new_socket(inner: AsyncSocket, domain: Domain) -> PingSocket {
+        PingSocket {
             inner,
-            pmap: Arc::new(Mutex::new(BTreeMap::new())),
+            domain,
+            pmap: Arc::new(PingerMap::new()),
+            taps: Arc::new(TapSet::new()),
             recv_task: Arc::new(Mutex::new(None)),
-        })
+            send_arena: Arc::new(Mutex::new(Vec::new())),
+            idents: IdentRegistry::new(),
+        }
     }
-    pub(crate) fn create_pinger(addr: IpAddr) -> io::Result<Pinger> {
+    pub(crate) fn create_pinger(
+        addr: IpAddr,
+        mut config: PingerConfig,
+        ttl: Option<u32>,
+    ) -> Result<Pinger> {
+        // Pingers owning their socket share one identifier space, so their
+        // identifiers are unique in the process.
+        let lease = IdentRegistry::global().claim_for(config.ident, config.ident_fixed)?;
+        config.ident = lease.ident();
         let domain = match addr {
             IpAddr::V4(_) => socket2::Domain::IPV4,
             IpAddr::V6(_) => socket2::Domain::IPV6,
         };
-        let inner = AsyncSocket::new(
-            PingSocketBuilder::new(domain)?.inner_run()?,
-            DEFAULT_LIMIT_PPS,
-        );
-        let mut pmap = BTreeMap::<IpAddr, Sender<PingResponse>>::new();
+        let builder = PingSocketBuilder::new(domain)?;
+        if let Some(ttl) = ttl {
+            builder.set_ttl(ttl)?;
+        }
+        let options = SocketOptions {
+            exclusive: true,
+            ..builder.options.clone()
+        };
+        let inner = AsyncSocket::new(builder.inner_run()?, options);
+        let pmap = Arc::new(PingerMap::new());
         let recv_task = Arc::new(Mutex::new(None));
-        let (tx, rx) = channel(100);
-        pmap.insert(addr, tx);
-        let pmap = Arc::new(Mutex::new(pmap));
-        Self::run_task(inner.clone(), pmap, recv_task);
-        Ok(Pinger::new_pinger(addr, inner, rx))
+        let (tx, rx) = channel(config.channel_capacity);
+        let registration = pmap.insert(addr, config.ident, tx);
+        Self::run_task(&inner, &pmap, &Arc::new(TapSet::new()), &recv_task);
+        Ok(Pinger::new_pinger(
+            addr,
+            Arc::new(inner),
+            rx,
+            config,
+            registration,
+            Some(lease),
+        ))
     }
+    /// Spawn the receive task, which only holds weak references to the
+    /// socket state: it stops once the socket and all its pingers are
+    /// dropped, waking up from a pending receive when the map goes away.
     fn run_task(
-        inner: AsyncSocket,
-        pmap: Arc<Mutex<BTreeMap<IpAddr, Sender<PingResponse>>>>,
-        recv_task: Arc<Mutex<Option<tokio::task::JoinHandle<()>>>>,
-    ) -> tokio::task::JoinHandle<()> {
-        tokio::task::spawn(async move {
-            let mut buffer = [0_u8; 2048];
-            while let Ok((sz, from_addr)) = inner.recv_from(&mut buffer).await {
-                let received = Instant::now();
-                let mut pmapguard = pmap.lock().await;
-                let tx = match pmapguard.get(&from_addr.ip()) {
-                    None => continue,
-                    Some(tx) => tx,
+        inner: &AsyncSocket,
+        pmap: &Arc<PingerMap>,
+        taps: &Arc<TapSet>,
+        recv_task: &Arc<Mutex<Option<rt::JoinHandle<()>>>>,
+    ) -> rt::JoinHandle<()> {
+        let mut batch = RecvBatch::new(inner.recv_batch_size(), inner.recv_buf_size());
+        let mut dropped = pmap.on_drop();
+        let (by_ident, ipv6) = (inner.route_by_ident(), inner.is_ipv6());
+        // Datagram sockets receive IPv4 replies without their IP header.
+        let add_header = inner.is_dgram() && !ipv6;
+        let name = match inner.local_addr() {
+            Ok(addr) if ipv6 => format!("surge-ping-recv-v6 {}", addr),
+            Ok(addr) => format!("surge-ping-recv-v4 {}", addr),
+            Err(_) if ipv6 => "surge-ping-recv-v6".to_string(),
+            Err(_) => "surge-ping-recv-v4".to_string(),
+        };
+        let socket = Arc::downgrade(&inner.inner);
+        let (pmap, taps, recv_task) = (
+            Arc::downgrade(pmap),
+            Arc::downgrade(taps),
+            Arc::downgrade(recv_task),
+        );
+        rt::spawn(&name, async move {
+            loop {
+                let inner = match socket.upgrade() {
+                    Some(inner) => AsyncSocket { inner },
+                    None => break,
+                };
+                let res = tokio::select! {
+                    res = inner.recv_batch(&mut batch) => res,
+                    _ = dropped.changed() => break,
+                };
+                let pmap = match pmap.upgrade() {
+                    Some(pmap) => pmap,
+                    None => break,
+                };
+                // One timestamp per batch: every packet of a batch was already
+                // queued in the kernel when the batch was read.
+                let received = inner.now();
+                let mut evicted = false;
+                #[cfg(target_os = "linux")]
+                let res = match inner.recv_errors() {
+                    // A queued error also fails the regular receive, which
+                    // is fine once the error is read.
+                    Ok(reports) if !reports.is_empty() => {
+                        for report in reports {
+                            let ip = report.destination;
+                            if let Dispatch::Closed = pmap.dispatch_error(received, report) {
+                                pmap.remove_closed(&ip, None);
+                                evicted = true;
+                            }
+                        }
+                        res.or(Ok(0))
+                    }
+                    _ => res,
                 };
-                //let btosend = unsafe { assume_init(&buffer[0..sz]) }.to_vec();
-                if tx
-                    .try_send(PingResponse::new(received, buffer[0..sz].to_vec()))
-                    .is_err()
-                {
-                    pmapguard.remove(&from_addr.ip());
-                    if pmapguard.len() < 1 {
+                let received_count = match res {
+                    Ok(count) => count,
+                    Err(e) => {
+                        trace!("socket receive error: {}", e);
+                        trace_event!(ERROR, error = %e, "receive task stopped");
                         break;
                     }
                 };
+                trace_event!(TRACE, count = received_count, "packets received");
+                let taps = taps.upgrade();
+                for (packet, from_addr, stamp) in batch.iter().take(received_count) {
+                    if let Some(taps) = &taps {
+                        taps.publish(received, from_addr, packet);
+                    }
+                    let with_header;
+                    let packet = match from_addr.ip() {
+                        IpAddr::V4(source) if add_header => {
+                            with_header =
+                                icmpv4::with_ipv4_header(source, Ipv4Addr::UNSPECIFIED, 0, packet);
+                            &with_header[..]
+                        }
+                        _ => packet,
+                    };
+                    let ident = peek_ident(packet, ipv6);
+                    let ip = ident
+                        .filter(|_| by_ident)
+                        .and_then(|ident| pmap.addr_of(ident))
+                        .unwrap_or_else(|| from_addr.ip());
+                    match pmap.dispatch(&ip, ident, from_addr.ip(), received, stamp, packet) {
+                        Dispatch::Delivered | Dispatch::Unknown => {}
+                        Dispatch::Full => {
+                            trace!("pinger channel full, drop reply from {}", ip);
+                            trace_event!(WARN, dest = %ip, "pinger channel full, reply dropped");
+                            meter::recv_dropped(ip);
+                        }
+                        Dispatch::Closed => {
+                            pmap.remove_closed(&ip, ident);
+                            evicted = true;
+                        }
+                    }
+                }
+                if evicted && pmap.is_empty() {
+                    let recv_task = match recv_task.upgrade() {
+                        Some(recv_task) => recv_task,
+                        None => return,
+                    };
+                    // Re-check under the task lock: `pinger()` registers before
+                    // taking it, so a concurrent registration is never missed.
+                    let mut guard_task = recv_task.lock().await;
+                    if pmap.is_empty() {
+                        *guard_task = None;
+                        return;
+                    }
+                }
+            }
+            if let Some(recv_task) = recv_task.upgrade() {
+                let mut guard_task = recv_task.lock().await;
+                *guard_task = None;
             }
-            let mut guard_task = recv_task.lock().await;
-            *guard_task = None;
         })
     }
     async fn check_task(&self) {
@@ -260,15 +1240,510 @@ impl PingSocket {
             return;
         }
         *guard_task = Some(Self::run_task(
-            self.inner.clone(),
-            self.pmap.clone(),
-            self.recv_task.clone(),
+            &self.inner,
+            &self.pmap,
+            &self.taps,
+            &self.recv_task,
         ));
     }
-    pub async fn pinger(&self, addr: IpAddr) -> Pinger {
-        let (tx, rx) = channel(100);
-        self.pmap.lock().await.insert(addr, tx);
+    /// Hold every send on the socket until `resume` is called.
+    ///
+    /// Sends already past the pause complete, later ones wait without
+    /// failing and the RTT of their pings includes the wait. Replies are
+    /// still received.
+    pub fn pause(&self) {
+        self.inner.set_paused(true);
+    }
+    /// Resume sending after `pause`, the held sends going through the
+    /// send limiter.
+    pub fn resume(&self) {
+        self.inner.set_paused(false);
+    }
+    /// Whether sending is paused.
+    pub fn is_paused(&self) -> bool {
+        self.inner.is_paused()
+    }
+    /// Whether the receive task of the socket is running. It starts with the
+    /// first pinger and stops once every pinger is dropped, or on a receive
+    /// error.
+    pub async fn is_receiving(&self) -> bool {
+        // The task clears its handle when it stops.
+        self.recv_task.lock().await.is_some()
+    }
+    /// Subscribe to every ICMP packet received on this socket.
+    ///
+    /// The tap buffers up to `capacity` packets. Packets are only received
+    /// while the socket's receive task runs, i.e. while it has pingers.
+    pub fn tap(&self, capacity: usize) -> Tap {
+        self.taps.subscribe(capacity)
+    }
+    /// The local address the socket is bound to, e.g. to check that
+    /// `PingSocketBuilder::bind_addr` took effect.
+    ///
+    /// The port is always 0, raw sockets having none. The address is
+    /// unspecified until bound, the kernel choosing the source address of
+    /// each request.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+    /// Number of sends delayed by the per destination limit, see
+    /// `PingSocketBuilder::set_per_target_limit_pps`.
+    pub fn per_target_throttled(&self) -> u64 {
+        self.inner.per_target_throttled()
+    }
+    /// Number of sends retried after failing with `ENOBUFS` or `EAGAIN`, see
+    /// `PingSocketBuilder::set_send_retries`.
+    pub fn send_retries(&self) -> u64 {
+        self.inner.send_retries()
+    }
+    /// Whether the socket is raw or an unprivileged datagram socket, see
+    /// `PingSocketBuilder::new_auto`.
+    pub fn socket_kind(&self) -> SocketKind {
+        if self.inner.is_dgram() {
+            SocketKind::Dgram
+        } else {
+            SocketKind::Raw
+        }
+    }
+    /// The file descriptor of the socket, to register it with another
+    /// reactor or hand it to a capture library.
+    ///
+    /// The descriptor stays owned by the socket: don't close it. Reading
+    /// from it takes the packets away from the receive task, and from the
+    /// pingers waiting for them.
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> RawFd {
+        self.inner.inner.socket.as_raw_fd()
+    }
+    /// The handle of the socket, see `as_raw_fd` for the caveats.
+    #[cfg(windows)]
+    pub fn as_raw_socket(&self) -> RawSocket {
+        self.inner.inner.socket.as_raw_socket()
+    }
+    /// Size of the largest reply the receive task can receive.
+    pub(crate) fn recv_packet_size(&self) -> usize {
+        self.inner.recv_packet_size()
+    }
+    /// The address family of the socket.
+    pub(crate) fn domain(&self) -> Domain {
+        self.domain
+    }
+    /// Reject addresses whose family differs from the socket's domain.
+    pub(crate) fn check_family(&self, addr: IpAddr) -> Result<()> {
+        let socket_v4 = self.domain == Domain::IPV4;
+        if addr.is_ipv4() == socket_v4 {
+            return Ok(());
+        }
+        Err(SurgeError::AddressFamilyMismatch {
+            addr,
+            socket: if socket_v4 { "IPv4" } else { "IPv6" },
+        })
+    }
+    /// Creates a pinger for `addr`, which must match the socket's address family.
+    pub async fn pinger(&self, addr: IpAddr) -> Result<Pinger> {
+        self.pinger_with_config(addr, PingerConfig::default()).await
+    }
+    /// Creates a builder for a pinger to `addr` sharing this socket.
+    pub fn pinger_builder(&self, addr: IpAddr) -> PingerBuilder {
+        PingerBuilder::new(addr, Some(self.clone()))
+    }
+    pub(crate) async fn pinger_with_config(
+        &self,
+        addr: IpAddr,
+        config: PingerConfig,
+    ) -> Result<Pinger> {
+        self.register_pinger(addr, config, false).await
+    }
+    /// Creates a pinger to `addr` that leaves its route to the pinger
+    /// already pinging `addr` if any, receiving its replies by identifier.
+    ///
+    /// Fails with `SurgeError::DestinationInUse` on a datagram socket, whose
+    /// replies all carry the identifier of the socket.
+    pub(crate) async fn pinger_beside(&self, addr: IpAddr) -> Result<Pinger> {
+        self.register_pinger(addr, PingerConfig::default(), true)
+            .await
+    }
+    async fn register_pinger(
+        &self,
+        addr: IpAddr,
+        mut config: PingerConfig,
+        beside: bool,
+    ) -> Result<Pinger> {
+        self.check_family(addr)?;
+        let lease = self.idents.claim_for(config.ident, config.ident_fixed)?;
+        config.ident = lease.ident();
+        config.match_source = !self.inner.route_by_ident();
+        config.match_ident = !self.inner.is_dgram();
+        let (tx, rx) = channel(config.channel_capacity);
+        let registration = if !beside {
+            self.pmap.insert(addr, config.ident, tx)
+        } else {
+            match self.pmap.try_insert(addr, config.ident, tx) {
+                Ok(registration) => registration,
+                Err(_) if self.inner.is_dgram() => {
+                    return Err(SurgeError::DestinationInUse { destination: addr })
+                }
+                Err(tx) => self.pmap.insert_side(config.ident, tx),
+            }
+        };
         self.check_task().await;
-        Pinger::new_pinger(addr, self.inner.clone(), rx)
+        Ok(Pinger::new_pinger(
+            addr,
+            Arc::new(self.inner.clone()),
+            rx,
+            config,
+            registration,
+            Some(lease),
+        ))
+    }
+    /// Ping every address `count` times, with at most `concurrency` addresses in flight.
+    ///
+    /// A fixed pool of workers pulls addresses from the list, so sweeping
+    /// thousands of hosts doesn't spawn thousands of tasks. Sends of all
+    /// workers still go through the socket's pps limiter. Fails without sending
+    /// anything if an address doesn't match the socket's address family.
+    ///
+    /// Pingers already pinging one of the addresses keep receiving their
+    /// replies. An address that couldn't be pinged maps to the error, e.g.
+    /// `SurgeError::DestinationInUse` when a pinger of a datagram socket
+    /// already pings it.
+    pub async fn ping_many(
+        &self,
+        addrs: &[IpAddr],
+        concurrency: usize,
+        count: u16,
+    ) -> Result<HashMap<IpAddr, Result<PingStats>>> {
+        for addr in addrs {
+            self.check_family(*addr)?;
+        }
+        let mut seen = HashSet::new();
+        let addrs: Arc<Vec<IpAddr>> = Arc::new(
+            addrs
+                .iter()
+                .copied()
+                .filter(|addr| seen.insert(*addr))
+                .collect(),
+        );
+        let next = Arc::new(AtomicUsize::new(0));
+        let mut workers = Vec::new();
+        for _ in 0..concurrency.max(1).min(addrs.len()) {
+            let socket = self.clone();
+            let addrs = addrs.clone();
+            let next = next.clone();
+            workers.push(rt::spawn("surge-ping-worker", async move {
+                let mut results = Vec::new();
+                while let Some(&addr) = addrs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let stats = match socket.pinger_beside(addr).await {
+                        Ok(mut pinger) => {
+                            let mut stats = PingStats::default();
+                            for seq in 0..count {
+                                stats.update(pinger.ping(seq).await.ok().map(|reply| reply.rtt));
+                            }
+                            Ok(stats)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    results.push((addr, stats));
+                }
+                results
+            }));
+        }
+        let mut stats = HashMap::new();
+        for worker in workers {
+            if let Some(results) = rt::join(worker).await {
+                stats.extend(results);
+            }
+        }
+        Ok(stats)
+    }
+    /// Send one echo request with `size` payload bytes to each `(addr, seq, ident)` probe.
+    ///
+    /// The packets are built into a reusable buffer and submitted with `sendmmsg`
+    /// on Linux. The result for each probe tells whether it actually left,
+    /// in the same order as `probes`. Replies are delivered to the pingers
+    /// registered for the probed addresses, each probe is recorded as an
+    /// outstanding request of its pinger so the reply is matched.
+    pub async fn send_batch(
+        &self,
+        probes: &[(IpAddr, u16, u16)],
+        size: usize,
+    ) -> Vec<Result<usize>> {
+        let recv_packet_size = self.recv_packet_size();
+        if size > MAX_ICMPV6_PAYLOAD {
+            // Too large for either family, don't size the arena for it.
+            return probes
+                .iter()
+                .map(|(addr, _, _)| check_payload_size(*addr, size, recv_packet_size).map(|_| 0))
+                .collect();
+        }
+        let pkt_len = ICMP_HEADER_SIZE + size;
+        // IPv4 replies come with their IP header, the largest probe is the
+        // one whose reply is the largest.
+        if let Some(reply_size) = probes
+            .iter()
+            .map(|(addr, _, _)| reply_packet_size(*addr, size))
+            .max()
+        {
+            self.inner.reserve_recv_packet_size(reply_size);
+        }
+        let mut arena = self.send_arena.lock().await;
+        arena.resize(probes.len() * pkt_len, 0);
+        let mut results: Vec<Result<usize>> = Vec::with_capacity(probes.len());
+        for ((addr, seq, ident), buf) in probes.iter().zip(arena.chunks_mut(pkt_len)) {
+            if let Err(e) = self
+                .check_family(*addr)
+                .and_then(|_| check_payload_size(*addr, size, recv_packet_size))
+            {
+                results.push(Err(e));
+                continue;
+            }
+            let encoded = match addr {
+                IpAddr::V4(_) => icmpv4::write_icmpv4_echo_packet(buf, *ident, *seq, &[]),
+                IpAddr::V6(_) => icmpv6::write_icmpv6_echo_packet(buf, *ident, *seq, &[]),
+            };
+            results.push(encoded.map(|_| 0));
+        }
+        let mut packets = Vec::with_capacity(probes.len());
+        let mut targets = Vec::with_capacity(probes.len());
+        // Recorded before sending, so a reply can't arrive before its request.
+        let sent_at = self.inner.now();
+        for ((probe, buf), result) in probes.iter().zip(arena.chunks(pkt_len)).zip(results.iter()) {
+            if result.is_ok() {
+                let (addr, seq, ident) = *probe;
+                self.pmap.record_sent(&addr, ident, seq, sent_at);
+                packets.push(buf);
+                targets.push(SocketAddr::new(addr, 0));
+            }
+        }
+        let mut sent = self.inner.send_batch(&packets, &targets).await.into_iter();
+        for ((addr, seq, ident), result) in probes.iter().zip(results.iter_mut()) {
+            if result.is_err() {
+                continue;
+            }
+            *result = match sent.next() {
+                Some(res) => res.map_err(|source| SurgeError::from_send(*addr, source)),
+                None => Err(SurgeError::NetworkError),
+            };
+            if result.is_err() {
+                self.pmap.forget_sent(addr, *ident, *seq);
+            }
+        }
+        results
+    }
+}
+
+/// A pair of IPv4 and IPv6 `PingSocket`s, routing pingers by address family.
+#[derive(Clone)]
+pub struct DualPingSocket {
+    v4: PingSocket,
+    v6: PingSocket,
+}
+
+impl DualPingSocket {
+    /// Creates both an IPv4 and an IPv6 ping socket with default options.
+    pub fn new() -> Result<DualPingSocket> {
+        Ok(DualPingSocket {
+            v4: PingSocket::new(Domain::IPV4)?,
+            v6: PingSocket::new(Domain::IPV6)?,
+        })
+    }
+    /// Combines already built IPv4 and IPv6 ping sockets.
+    pub fn from_sockets(v4: PingSocket, v6: PingSocket) -> DualPingSocket {
+        DualPingSocket { v4, v6 }
+    }
+    /// The IPv4 ping socket.
+    pub fn v4(&self) -> &PingSocket {
+        &self.v4
+    }
+    /// The IPv6 ping socket.
+    pub fn v6(&self) -> &PingSocket {
+        &self.v6
+    }
+    /// Creates a pinger on the socket matching the family of `addr`.
+    pub async fn pinger(&self, addr: IpAddr) -> Result<Pinger> {
+        match addr {
+            IpAddr::V4(_) => self.v4.pinger(addr).await,
+            IpAddr::V6(_) => self.v6.pinger(addr).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn ttl_reads_back() {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        set_socket_ttl(&socket, false, 7).unwrap();
+        assert_eq!(socket_ttl(&socket, false).unwrap(), 7);
+    }
+
+    /// A builder around a UDP socket, which binds like an ICMP one without
+    /// privileges.
+    fn udp_builder() -> PingSocketBuilder {
+        PingSocketBuilder {
+            socket: Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap(),
+            domain: Domain::IPV4,
+            options: SocketOptions::default(),
+            binds: Default::default(),
+        }
+    }
+
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    #[test]
+    fn binds_device_then_source() {
+        let builder = udp_builder();
+        let source = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        // Recorded in the reverse order of the binds.
+        builder.bind_addr(&source.into()).unwrap();
+        builder.bind_device(Some(b"lo")).unwrap();
+        match builder.apply_binds() {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return,
+            res => res.unwrap(),
+        }
+        assert_eq!(
+            builder.socket.device().unwrap().as_deref(),
+            Some(&b"lo"[..])
+        );
+        let local = builder.socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn binds_source() {
+        let builder = udp_builder();
+        builder
+            .bind_addr(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        builder.apply_binds().unwrap();
+        let local = builder.socket.local_addr().unwrap().as_socket().unwrap();
+        assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn rejects_invalid_binds() {
+        let builder = udp_builder();
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 0));
+        let err = builder.bind_addr(&v6.into()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        for name in [&b"sixteen-bytes-ab"[..], b"l\0o"] {
+            let err = builder.bind_device(Some(name)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        // Nothing recorded, building binds nothing.
+        builder.apply_binds().unwrap();
+        let local = builder.socket.local_addr().unwrap().as_socket().unwrap();
+        assert!(local.ip().is_unspecified());
+    }
+
+    #[test]
+    fn hop_limit_reads_back() {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, None).unwrap();
+        set_socket_ttl(&socket, true, 9).unwrap();
+        assert_eq!(socket_ttl(&socket, true).unwrap(), 9);
+    }
+
+    /// A clock only moving when told to.
+    #[derive(Debug)]
+    struct FakeClock(parking_lot::Mutex<Instant>);
+
+    impl FakeClock {
+        fn new() -> FakeClock {
+            FakeClock(parking_lot::Mutex::new(Instant::now()))
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock() += by;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock()
+        }
+    }
+
+    fn assert_wait(wait: Duration, millis: f64) {
+        let waited = wait.as_secs_f64() * 1000.0;
+        assert!((waited - millis).abs() < 1e-3, "waited {:?}", wait);
+    }
+
+    #[test]
+    fn bucket_sends_a_burst_at_once() {
+        let clock = FakeClock::new();
+        let mut bucket = TokenBucket::new(100, 10);
+        for _ in 0..10 {
+            assert_eq!(bucket.take(1, clock.now()), Duration::ZERO);
+        }
+        // One token every 10ms once the burst is spent.
+        assert_wait(bucket.take(1, clock.now()), 10.0);
+        assert_wait(bucket.take(1, clock.now()), 20.0);
+    }
+
+    #[test]
+    fn bucket_refills_at_the_rate() {
+        let clock = FakeClock::new();
+        let mut bucket = TokenBucket::new(100, 10);
+        assert_eq!(bucket.take(10, clock.now()), Duration::ZERO);
+        clock.advance(Duration::from_millis(50));
+        for _ in 0..5 {
+            assert_eq!(bucket.take(1, clock.now()), Duration::ZERO);
+        }
+        assert_wait(bucket.take(1, clock.now()), 10.0);
+    }
+
+    #[test]
+    fn bucket_refills_up_to_the_burst() {
+        let clock = FakeClock::new();
+        let mut bucket = TokenBucket::new(100, 10);
+        assert_eq!(bucket.take(10, clock.now()), Duration::ZERO);
+        assert!(!bucket.is_full(clock.now()));
+        clock.advance(Duration::from_secs(10));
+        assert!(bucket.is_full(clock.now()));
+        assert_eq!(bucket.take(10, clock.now()), Duration::ZERO);
+        assert_wait(bucket.take(1, clock.now()), 10.0);
+    }
+
+    #[test]
+    fn bucket_accounts_for_waiting_sends() {
+        let clock = FakeClock::new();
+        let mut bucket = TokenBucket::new(100, 10);
+        // 15 tokens short: the batch waits until they are refilled.
+        assert_wait(bucket.take(25, clock.now()), 150.0);
+        clock.advance(Duration::from_millis(100));
+        assert_wait(bucket.take(1, clock.now()), 60.0);
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(bucket.take(0, clock.now()), Duration::ZERO);
+        assert!(!bucket.is_full(clock.now()));
+    }
+
+    #[test]
+    fn bucket_burst_is_at_least_one() {
+        let clock = FakeClock::new();
+        let mut bucket = TokenBucket::new(10, 0);
+        assert_eq!(bucket.take(1, clock.now()), Duration::ZERO);
+        assert_wait(bucket.take(1, clock.now()), 100.0);
+    }
+
+    #[test]
+    fn target_limiter_has_a_bucket_per_destination() {
+        let clock = FakeClock::new();
+        // A burst of 1000 / 100 = 10 requests per destination.
+        let limiter = TargetLimiter::new(1000);
+        let first = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 1), 0));
+        let second = SocketAddr::from((Ipv4Addr::new(192, 0, 2, 2), 0));
+        for _ in 0..10 {
+            assert_eq!(limiter.take(&[first], clock.now()), Duration::ZERO);
+        }
+        assert_wait(limiter.take(&[first], clock.now()), 1.0);
+        assert_eq!(limiter.take(&[second], clock.now()), Duration::ZERO);
+        assert_eq!(limiter.throttled.load(Ordering::Relaxed), 1);
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(limiter.take(&[second], clock.now()), Duration::ZERO);
+        assert_wait(limiter.take(&[first, second], clock.now()), 1.0);
     }
 }
@@ -0,0 +1,669 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::RwLock;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio::sync::watch;
+
+#[cfg(target_os = "linux")]
+use crate::errqueue::IcmpErrorReport;
+use crate::kstamp::KernelTimestamp;
+use crate::ping::Cache;
+use crate::pingsocket::PingResponse;
+use crate::pool::{BufferPool, DEFAULT_POOLED_BUFFERS};
+
+const SHARD_COUNT: usize = 16;
+
+/// Outcome of routing one received packet to its pinger.
+pub(crate) enum Dispatch {
+    Delivered,
+    Unknown,
+    Full,
+    Closed,
+}
+
+/// Outcome of moving a pinger to another address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Rekey {
+    Moved,
+    /// The pinger isn't registered for its old address anymore.
+    Gone,
+    /// Another pinger is registered for the new address.
+    Taken,
+}
+
+/// The channel of one registered pinger.
+struct Route {
+    id: u64,
+    tx: Sender<PingResponse>,
+    /// Outstanding requests of the pinger and their lifetime, to record the
+    /// probes sent for it by `PingSocket::send_batch`.
+    cache: Option<(Cache, Duration)>,
+}
+
+impl Route {
+    fn new(id: u64, tx: Sender<PingResponse>) -> Route {
+        Route {
+            id,
+            tx,
+            cache: None,
+        }
+    }
+}
+
+type Shard = HashMap<IpAddr, Route>;
+
+/// Registry of pingers keyed by destination address.
+///
+/// The map is sharded by address hash, so the receive path only takes a
+/// short read lock on one shard and registering pingers doesn't stall it.
+pub(crate) struct PingerMap {
+    shards: Vec<RwLock<Shard>>,
+    len: AtomicUsize,
+    next_id: AtomicU64,
+    pool: Arc<BufferPool>,
+    /// Address each pinger is registered for, by ICMP identifier.
+    idents: RwLock<HashMap<u16, IpAddr>>,
+    /// Routes of the pingers sharing their destination with the pinger
+    /// registered for it, by ICMP identifier.
+    side: RwLock<HashMap<u16, Route>>,
+    /// Number of side routes, so the receive path skips their lock when
+    /// there are none.
+    side_len: AtomicUsize,
+    /// Never sent to, subscribers see it closed when the map is dropped.
+    dropped: watch::Sender<()>,
+}
+
+/// Handle of a pinger on its registration in a `PingerMap`.
+///
+/// Keeps the map alive, so the receive task runs until both the socket and
+/// its pingers are dropped.
+pub(crate) struct Registration {
+    pmap: Arc<PingerMap>,
+    id: u64,
+    /// Whether the pinger is routed by identifier, see `insert_side`.
+    side: bool,
+}
+
+impl Registration {
+    /// Move the registration from `old` to `new`, see `PingerMap::rekey`.
+    pub fn rekey(&self, old: &IpAddr, new: IpAddr) -> Rekey {
+        // Side routes don't depend on the destination.
+        if self.side {
+            return Rekey::Moved;
+        }
+        let rekey = self.pmap.rekey(old, new, self.id);
+        if rekey != Rekey::Moved {
+            return rekey;
+        }
+        for registered in self.pmap.idents.write().values_mut() {
+            if registered == old {
+                *registered = new;
+            }
+        }
+        rekey
+    }
+
+    /// Record the probes sent for the pinger registered for `addr` with
+    /// `ident` in `cache`, outstanding for `lifetime`.
+    pub fn attach_cache(&self, addr: &IpAddr, ident: u16, cache: Cache, lifetime: Duration) {
+        let id = self.id;
+        let attach = |route: Option<&mut Route>| {
+            if let Some(route) = route.filter(|route| route.id == id) {
+                route.cache = Some((cache, lifetime));
+            }
+        };
+        if self.side {
+            attach(self.pmap.side.write().get_mut(&ident));
+        } else {
+            attach(self.pmap.shard(addr).write().get_mut(addr));
+        }
+    }
+
+    /// Route the packets carrying `new` instead of `old` to the pinger
+    /// registered for `addr`.
+    pub fn set_ident(&self, addr: IpAddr, old: u16, new: u16) {
+        if self.side {
+            let mut side = self.pmap.side.write();
+            if matches!(side.get(&old), Some(route) if route.id == self.id) {
+                let route = side.remove(&old).expect("side route");
+                side.insert(new, route);
+            }
+            return;
+        }
+        let mut idents = self.pmap.idents.write();
+        if idents.get(&old) == Some(&addr) {
+            idents.remove(&old);
+        }
+        idents.insert(new, addr);
+    }
+}
+
+impl PingerMap {
+    pub fn new() -> PingerMap {
+        PingerMap {
+            shards: (0..SHARD_COUNT)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            len: AtomicUsize::new(0),
+            next_id: AtomicU64::new(0),
+            pool: BufferPool::new(DEFAULT_POOLED_BUFFERS),
+            idents: RwLock::new(HashMap::new()),
+            side: RwLock::new(HashMap::new()),
+            side_len: AtomicUsize::new(0),
+            dropped: watch::channel(()).0,
+        }
+    }
+
+    /// A receiver whose `changed()` fails once the map is dropped.
+    pub fn on_drop(&self) -> watch::Receiver<()> {
+        self.dropped.subscribe()
+    }
+
+    fn shard_index(addr: &IpAddr) -> usize {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        (hasher.finish() as usize) % SHARD_COUNT
+    }
+
+    fn shard(&self, addr: &IpAddr) -> &RwLock<Shard> {
+        &self.shards[Self::shard_index(addr)]
+    }
+
+    /// Route the packets received from `addr`, or carrying `ident` when
+    /// routing by identifier, to `tx`, replacing the pinger registered for
+    /// `addr` if any.
+    pub fn insert(
+        self: &Arc<Self>,
+        addr: IpAddr,
+        ident: u16,
+        tx: Sender<PingResponse>,
+    ) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.idents.write().insert(ident, addr);
+        if self
+            .shard(&addr)
+            .write()
+            .insert(addr, Route::new(id, tx))
+            .is_none()
+        {
+            self.len.fetch_add(1, Ordering::AcqRel);
+        }
+        Registration {
+            pmap: self.clone(),
+            id,
+            side: false,
+        }
+    }
+
+    /// Route the packets received from `addr` to `tx` like `insert`, unless
+    /// a live pinger is registered for `addr`: `tx` is handed back then.
+    pub fn try_insert(
+        self: &Arc<Self>,
+        addr: IpAddr,
+        ident: u16,
+        tx: Sender<PingResponse>,
+    ) -> std::result::Result<Registration, Sender<PingResponse>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut shard = self.shard(&addr).write();
+            if matches!(shard.get(&addr), Some(route) if !route.tx.is_closed()) {
+                return Err(tx);
+            }
+            if shard.insert(addr, Route::new(id, tx)).is_none() {
+                self.len.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+        self.idents.write().insert(ident, addr);
+        Ok(Registration {
+            pmap: self.clone(),
+            id,
+            side: false,
+        })
+    }
+
+    /// Route the packets carrying `ident` to `tx`, whatever their source,
+    /// leaving the route of their address to the pinger registered for it.
+    ///
+    /// Only for sockets whose replies carry the identifier of the request,
+    /// unlike datagram sockets.
+    pub fn insert_side(self: &Arc<Self>, ident: u16, tx: Sender<PingResponse>) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if self
+            .side
+            .write()
+            .insert(ident, Route::new(id, tx))
+            .is_none()
+        {
+            self.len.fetch_add(1, Ordering::AcqRel);
+            self.side_len.fetch_add(1, Ordering::AcqRel);
+        }
+        Registration {
+            pmap: self.clone(),
+            id,
+            side: true,
+        }
+    }
+
+    /// Atomically move the pinger registered as `id` from `old` to `new`.
+    ///
+    /// A live pinger registered for `new` is never displaced, the move fails
+    /// with `Rekey::Taken` instead.
+    fn rekey(&self, old: &IpAddr, new: IpAddr, id: u64) -> Rekey {
+        let (from, to) = (Self::shard_index(old), Self::shard_index(&new));
+        if from == to {
+            let mut shard = self.shards[from].write();
+            return self.move_route(&mut shard, None, old, new, id);
+        }
+        // Lock in index order so concurrent moves can't deadlock.
+        let (mut first, mut second) = (
+            self.shards[from.min(to)].write(),
+            self.shards[from.max(to)].write(),
+        );
+        if from < to {
+            self.move_route(&mut first, Some(&mut second), old, new, id)
+        } else {
+            self.move_route(&mut second, Some(&mut first), old, new, id)
+        }
+    }
+
+    /// Move the route of `old` in `src` to `new` in `dst`, or in `src` when
+    /// both addresses share a shard. A route left at `new` by a dropped
+    /// pinger is replaced.
+    fn move_route(
+        &self,
+        src: &mut Shard,
+        dst: Option<&mut Shard>,
+        old: &IpAddr,
+        new: IpAddr,
+        id: u64,
+    ) -> Rekey {
+        if !matches!(src.get(old), Some(route) if route.id == id) {
+            return Rekey::Gone;
+        }
+        let live = |shard: &Shard| matches!(shard.get(&new), Some(route) if !route.tx.is_closed());
+        if dst.as_deref().map_or_else(|| live(src), live) {
+            return Rekey::Taken;
+        }
+        let route = match src.remove(old) {
+            Some(route) => route,
+            None => return Rekey::Gone,
+        };
+        if dst.unwrap_or(src).insert(new, route).is_some() {
+            self.len.fetch_sub(1, Ordering::AcqRel);
+        }
+        Rekey::Moved
+    }
+
+    /// Record request `seq` of `ident` sent to `addr` at `sent` as
+    /// outstanding for the pinger it is routed to, so the pinger matches its
+    /// reply.
+    pub fn record_sent(&self, addr: &IpAddr, ident: u16, seq: u16, sent: Instant) {
+        let record = |route: Option<&Route>| match route.and_then(|route| route.cache.as_ref()) {
+            Some((cache, lifetime)) => {
+                cache.insert(ident, seq, sent, *lifetime, None);
+                true
+            }
+            None => false,
+        };
+        if self.side_len.load(Ordering::Acquire) > 0 && record(self.side.read().get(&ident)) {
+            return;
+        }
+        record(self.shard(addr).read().get(addr));
+    }
+
+    /// Forget request `seq` of `ident` recorded by `record_sent`.
+    pub fn forget_sent(&self, addr: &IpAddr, ident: u16, seq: u16) {
+        let forget = |route: Option<&Route>| match route.and_then(|route| route.cache.as_ref()) {
+            Some((cache, _)) => cache.remove(ident, seq).is_some(),
+            None => false,
+        };
+        if self.side_len.load(Ordering::Acquire) > 0 && forget(self.side.read().get(&ident)) {
+            return;
+        }
+        forget(self.shard(addr).read().get(addr));
+    }
+
+    /// The address of the pinger using `ident`, to route its packets by
+    /// identifier.
+    pub fn addr_of(&self, ident: u16) -> Option<IpAddr> {
+        self.idents.read().get(&ident).copied()
+    }
+
+    /// Hand a packet received from `from` to the pinger with a side route
+    /// for `ident`, or to the pinger registered for `addr`.
+    pub fn dispatch(
+        &self,
+        addr: &IpAddr,
+        ident: Option<u16>,
+        from: IpAddr,
+        when: Instant,
+        stamp: Option<KernelTimestamp>,
+        packet: &[u8],
+    ) -> Dispatch {
+        let response = || PingResponse::new(when, stamp, from, self.pool.get(packet));
+        if let Some(tx) = ident.and_then(|ident| self.side_route(ident)) {
+            return Self::send_to(&tx, response);
+        }
+        self.send(addr, response)
+    }
+
+    /// The sender of the side route for `ident`.
+    fn side_route(&self, ident: u16) -> Option<Sender<PingResponse>> {
+        if self.side_len.load(Ordering::Acquire) == 0 {
+            return None;
+        }
+        self.side.read().get(&ident).map(|route| route.tx.clone())
+    }
+
+    /// Hand an error read from the socket error queue to the pinger
+    /// registered for the destination of the failed request.
+    #[cfg(target_os = "linux")]
+    pub fn dispatch_error(&self, when: Instant, report: IcmpErrorReport) -> Dispatch {
+        let addr = report.destination;
+        self.send(&addr, || {
+            PingResponse::error(when, self.pool.get(&[]), report)
+        })
+    }
+
+    /// Send the response built by `response` to the pinger registered for
+    /// `addr`.
+    ///
+    /// The sender is cloned out of the shard so its lock isn't held while
+    /// sending, registering a pinger never waits behind the receive path.
+    fn send<F: FnOnce() -> PingResponse>(&self, addr: &IpAddr, response: F) -> Dispatch {
+        let tx = match self.shard(addr).read().get(addr) {
+            None => return Dispatch::Unknown,
+            Some(route) => route.tx.clone(),
+        };
+        Self::send_to(&tx, response)
+    }
+
+    fn send_to<F: FnOnce() -> PingResponse>(tx: &Sender<PingResponse>, response: F) -> Dispatch {
+        match tx.try_send(response()) {
+            Ok(()) => Dispatch::Delivered,
+            Err(TrySendError::Full(_)) => Dispatch::Full,
+            Err(TrySendError::Closed(_)) => Dispatch::Closed,
+        }
+    }
+
+    /// Remove the pinger with a side route for `ident`, or else the pinger
+    /// registered for `addr`, if its receiver was dropped.
+    ///
+    /// A pinger registered again for the same address in the meantime is kept.
+    pub fn remove_closed(&self, addr: &IpAddr, ident: Option<u16>) {
+        if let Some(ident) = ident.filter(|_| self.side_len.load(Ordering::Acquire) > 0) {
+            let mut side = self.side.write();
+            if let Some(route) = side.get(&ident) {
+                if route.tx.is_closed() {
+                    side.remove(&ident);
+                    self.len.fetch_sub(1, Ordering::AcqRel);
+                    self.side_len.fetch_sub(1, Ordering::AcqRel);
+                }
+                return;
+            }
+        }
+        let mut shard = self.shard(addr).write();
+        if matches!(shard.get(addr), Some(route) if route.tx.is_closed()) {
+            shard.remove(addr);
+            self.len.fetch_sub(1, Ordering::AcqRel);
+            self.idents
+                .write()
+                .retain(|_, registered| registered != addr);
+        }
+    }
+
+    /// Number of registered pingers, side routes included.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use tokio::sync::mpsc::channel;
+
+    use super::*;
+
+    fn addr(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn deliver(pmap: &PingerMap, to: IpAddr, ident: Option<u16>) -> Dispatch {
+        pmap.dispatch(&to, ident, to, Instant::now(), None, &[])
+    }
+
+    #[test]
+    fn side_routes_leave_address_routes() {
+        let pmap = Arc::new(PingerMap::new());
+        let (tx1, mut rx1) = channel(4);
+        let (tx2, mut rx2) = channel(4);
+        let _first = pmap.insert(addr(1), 1, tx1);
+        let tx2 = match pmap.try_insert(addr(1), 2, tx2) {
+            Ok(_) => panic!("displaced a live pinger"),
+            Err(tx2) => tx2,
+        };
+        let _second = pmap.insert_side(2, tx2);
+        assert!(matches!(
+            deliver(&pmap, addr(1), Some(1)),
+            Dispatch::Delivered
+        ));
+        assert!(matches!(
+            deliver(&pmap, addr(1), Some(2)),
+            Dispatch::Delivered
+        ));
+        assert!(matches!(deliver(&pmap, addr(1), None), Dispatch::Delivered));
+        assert_eq!(
+            (rx1.try_recv().is_ok(), rx1.try_recv().is_ok()),
+            (true, true)
+        );
+        assert!(rx2.try_recv().is_ok());
+        assert!(rx2.try_recv().is_err());
+
+        drop(rx2);
+        assert!(matches!(deliver(&pmap, addr(1), Some(2)), Dispatch::Closed));
+        pmap.remove_closed(&addr(1), Some(2));
+        assert!(matches!(
+            deliver(&pmap, addr(1), Some(2)),
+            Dispatch::Delivered
+        ));
+        assert!(!pmap.is_empty());
+    }
+
+    #[test]
+    fn rekey_keeps_live_pingers() {
+        let pmap = Arc::new(PingerMap::new());
+        let (tx1, _rx1) = channel(1);
+        let (tx2, _rx2) = channel(1);
+        let first = pmap.insert(addr(1), 1, tx1);
+        let _second = pmap.insert(addr(2), 2, tx2);
+        assert_eq!(first.rekey(&addr(1), addr(2)), Rekey::Taken);
+        assert_eq!(first.rekey(&addr(1), addr(3)), Rekey::Moved);
+        assert_eq!(first.rekey(&addr(1), addr(4)), Rekey::Gone);
+        assert_eq!(pmap.addr_of(1), Some(addr(3)));
+        assert_eq!(pmap.addr_of(2), Some(addr(2)));
+    }
+
+    #[test]
+    fn rekey_replaces_dropped_pingers() {
+        let pmap = Arc::new(PingerMap::new());
+        let (tx1, _rx1) = channel(1);
+        let (tx2, rx2) = channel(1);
+        let first = pmap.insert(addr(1), 1, tx1);
+        let _second = pmap.insert(addr(2), 2, tx2);
+        drop(rx2);
+        assert_eq!(first.rekey(&addr(1), addr(2)), Rekey::Moved);
+        assert!(!pmap.is_empty());
+    }
+
+    #[test]
+    fn insert_remove_stress() {
+        const TASKS: u8 = 16;
+        const PER_TASK: u32 = 100_000 / TASKS as u32;
+        let pmap = Arc::new(PingerMap::new());
+        // Routes registered throughout, which churn elsewhere must not lose.
+        let (kept, mut kept_rx): (Vec<_>, Vec<_>) = (0..TASKS)
+            .map(|task| {
+                let (tx, rx) = channel(4);
+                let to = IpAddr::V4(Ipv4Addr::new(10, 255, 0, task));
+                (pmap.insert(to, u16::from(task), tx), rx)
+            })
+            .unzip();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let pmap = pmap.clone();
+                thread::spawn(move || {
+                    for i in 0..PER_TASK {
+                        let [_, _, hi, lo] = i.to_be_bytes();
+                        let to = IpAddr::V4(Ipv4Addr::new(10, task, hi, lo));
+                        let (tx, mut rx) = channel(1);
+                        let _registration = pmap.insert(to, 1000 + i as u16, tx);
+                        assert!(matches!(deliver(&pmap, to, None), Dispatch::Delivered));
+                        assert!(rx.try_recv().is_ok());
+                        drop(rx);
+                        pmap.remove_closed(&to, None);
+                        assert!(matches!(deliver(&pmap, to, None), Dispatch::Unknown));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.join().unwrap();
+        }
+
+        assert_eq!(pmap.len(), usize::from(TASKS));
+        for (task, rx) in kept_rx.iter_mut().enumerate() {
+            let to = IpAddr::V4(Ipv4Addr::new(10, 255, 0, task as u8));
+            assert!(matches!(deliver(&pmap, to, None), Dispatch::Delivered));
+            assert!(rx.try_recv().is_ok());
+            assert_eq!(pmap.addr_of(task as u16), Some(to));
+        }
+        drop((kept, kept_rx));
+        for task in 0..TASKS {
+            pmap.remove_closed(&IpAddr::V4(Ipv4Addr::new(10, 255, 0, task)), None);
+        }
+        assert_eq!(pmap.len(), 0);
+        assert!(pmap.is_empty());
+    }
+
+    #[test]
+    fn churn_during_reply_flood() {
+        const ADDRS: u8 = 64;
+        let pmap = Arc::new(PingerMap::new());
+        let stop = Arc::new(AtomicBool::new(false));
+        let flood: Vec<_> = (0..4)
+            .map(|_| {
+                let (pmap, stop) = (pmap.clone(), stop.clone());
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        for last in 0..ADDRS {
+                            let ident = Some(u16::from(last));
+                            if let Dispatch::Closed = deliver(&pmap, addr(last), ident) {
+                                pmap.remove_closed(&addr(last), ident);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        // Pingers come and go for the flooded addresses, half of them
+        // routed by identifier.
+        let churn: Vec<_> = (0..8u16)
+            .map(|task| {
+                let pmap = pmap.clone();
+                thread::spawn(move || {
+                    for i in 0..2000u16 {
+                        let last = ((task * 2000 + i) % u16::from(ADDRS)) as u8;
+                        let (tx, mut rx) = channel(8);
+                        let registration = if task % 2 == 0 {
+                            pmap.insert(addr(last), u16::from(last), tx)
+                        } else {
+                            pmap.insert_side(u16::from(last), tx)
+                        };
+                        while rx.try_recv().is_ok() {}
+                        drop((registration, rx));
+                    }
+                })
+            })
+            .collect();
+        for task in churn {
+            task.join().unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+        for task in flood {
+            task.join().unwrap();
+        }
+
+        for last in 0..ADDRS {
+            pmap.remove_closed(&addr(last), Some(u16::from(last)));
+            pmap.remove_closed(&addr(last), None);
+        }
+        assert_eq!(pmap.len(), 0);
+    }
+
+    #[test]
+    fn registration_during_reply_flood() {
+        const PINGERS: u16 = 1000;
+        let to = |i: u16| {
+            let [hi, lo] = i.to_be_bytes();
+            IpAddr::V4(Ipv4Addr::new(10, 0, hi, lo))
+        };
+        let pmap = Arc::new(PingerMap::new());
+        // A pinger not reading its replies, whose channel stays full.
+        let (tx, _rx) = channel(1);
+        let _stalled = pmap.insert(addr(1), 1, tx);
+        let stop = Arc::new(AtomicBool::new(false));
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let flood: Vec<_> = (0..4)
+            .map(|_| {
+                let (pmap, stop, delivered) = (pmap.clone(), stop.clone(), delivered.clone());
+                thread::spawn(move || {
+                    let mut i: u16 = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        deliver(&pmap, addr(1), None);
+                        deliver(&pmap, to(i % PINGERS), None);
+                        delivered.fetch_add(1, Ordering::Relaxed);
+                        i = i.wrapping_add(1);
+                    }
+                })
+            })
+            .collect();
+        while delivered.load(Ordering::Relaxed) == 0 {
+            thread::yield_now();
+        }
+
+        // Every registration completes while the flood keeps going.
+        let mut pingers = Vec::new();
+        for i in 0..PINGERS {
+            let (tx, rx) = channel(1);
+            pingers.push((pmap.insert(to(i), 1000 + i, tx), rx));
+        }
+        let registered = delivered.load(Ordering::Relaxed);
+        // The flood reaches the new pingers.
+        while !pingers.iter_mut().any(|(_, rx)| rx.try_recv().is_ok()) {
+            thread::yield_now();
+        }
+        // The flood goes on after the registrations.
+        while delivered.load(Ordering::Relaxed) == registered {
+            thread::yield_now();
+        }
+        stop.store(true, Ordering::Relaxed);
+        for task in flood {
+            task.join().unwrap();
+        }
+        assert_eq!(pmap.len(), usize::from(PINGERS) + 1);
+    }
+}
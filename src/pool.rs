@@ -0,0 +1,81 @@
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use crossbeam_queue::ArrayQueue;
+
+/// Default number of idle buffers kept by a pool.
+pub(crate) const DEFAULT_POOLED_BUFFERS: usize = 1024;
+
+/// A pool of reusable packet buffers.
+///
+/// Buffers are handed out as `PooledBuffer`s which go back to the pool when
+/// dropped, so the steady-state receive path doesn't allocate. The idle
+/// buffers sit in a lock-free queue, shared by the receive task taking them
+/// and the pingers dropping them.
+pub(crate) struct BufferPool {
+    free: ArrayQueue<Vec<u8>>,
+}
+
+impl BufferPool {
+    pub fn new(max_pooled: usize) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            free: ArrayQueue::new(max_pooled.max(1)),
+        })
+    }
+
+    /// Take a buffer from the pool holding a copy of `data`.
+    pub fn get(self: &Arc<Self>, data: &[u8]) -> PooledBuffer {
+        let mut buf = self.free.pop().unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(data);
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    /// Take a buffer from the pool holding `len` zero bytes, to receive
+    /// into.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn get_zeroed(self: &Arc<Self>, len: usize) -> PooledBuffer {
+        let mut buf = self.free.pop().unwrap_or_default();
+        buf.clear();
+        buf.resize(len, 0);
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, buf: Vec<u8>) {
+        // A full pool just frees the buffer.
+        let _ = self.free.push(buf);
+    }
+}
+
+/// An owned packet buffer returned to its pool on drop.
+pub(crate) struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(mem::take(&mut self.buf));
+    }
+}
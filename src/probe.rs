@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::channel;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::error::SurgeError;
+use crate::icmp::IcmpPacket;
+use crate::ping::Pinger;
+use crate::result::PingResult;
+use crate::rt::{self, Interval, Sleep};
+use crate::stats::PingSummary;
+
+/// A probe transmitted by `Pinger::send_probe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SentProbe {
+    pub seq: u16,
+    pub sent: Instant,
+}
+
+/// An item of the stream returned by `Pinger::replies`.
+#[derive(Debug)]
+pub enum PingReply {
+    /// A reply matched to a probe.
+    Reply {
+        seq: u16,
+        rtt: Duration,
+        packet: IcmpPacket,
+    },
+    /// No reply to the probe arrived within the timeout.
+    Timeout(u16),
+    /// Another reply to a probe already answered, with `duplicate` set.
+    Duplicate(PingResult),
+}
+
+/// An item of the stream returned by `Pinger::into_stream`.
+#[derive(Debug)]
+pub enum PingEvent {
+    /// An echo reply.
+    Reply {
+        seq: u16,
+        rtt: Duration,
+        packet: IcmpPacket,
+    },
+    /// No reply arrived within the timeout, the request is considered lost.
+    Timeout(u16),
+    /// The request failed to send or was answered by an ICMP error.
+    Error { seq: u16, error: SurgeError },
+}
+
+/// Stream of replies to the probes of a pinger, see `Pinger::replies`.
+pub(crate) struct Replies<'a> {
+    pinger: &'a mut Pinger,
+    sleep: Sleep,
+}
+
+impl<'a> Replies<'a> {
+    pub(crate) fn new(pinger: &'a mut Pinger) -> Replies<'a> {
+        Replies {
+            pinger,
+            sleep: Sleep::new(),
+        }
+    }
+}
+
+impl Stream for Replies<'_> {
+    type Item = PingReply;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<PingReply>> {
+        let this = self.get_mut();
+        this.pinger.poll_reply(cx, &mut this.sleep)
+    }
+}
+
+/// Drive `pinger` from a task sending a probe every `interval`, see
+/// `Pinger::into_stream`.
+pub(crate) fn interval_stream(mut pinger: Pinger, interval: Duration) -> ReceiverStream<PingEvent> {
+    let (tx, rx) = channel(16);
+    rt::spawn("surge-ping-stream", async move {
+        let sender = pinger.probe_sender();
+        let replies = pinger.replies();
+        tokio::pin!(replies);
+        let mut ticker = Interval::new(interval);
+        // Sent probes in sequence order, with their event once known.
+        let mut in_flight: VecDeque<(u16, Option<PingEvent>)> = VecDeque::new();
+        loop {
+            tokio::select! {
+                _ = tx.closed() => return,
+                _ = ticker.tick() => {
+                    let seq_cnt = sender.next_seq();
+                    let event = sender
+                        .send_probe(seq_cnt)
+                        .await
+                        .err()
+                        .map(|error| PingEvent::Error { seq: seq_cnt, error });
+                    in_flight.push_back((seq_cnt, event));
+                }
+                reply = replies.next() => {
+                    let (seq_cnt, event) = match reply {
+                        Some(PingReply::Reply { seq, packet, .. }) if !packet.is_echo_reply() => {
+                            let error = packet.to_error().unwrap_or(SurgeError::UnexpectedReply {
+                                icmp_type: packet.icmp_type(),
+                                icmp_code: packet.icmp_code(),
+                            });
+                            (seq, PingEvent::Error { seq, error })
+                        }
+                        Some(PingReply::Reply { seq, rtt, packet }) => {
+                            (seq, PingEvent::Reply { seq, rtt, packet })
+                        }
+                        Some(PingReply::Timeout(seq)) => (seq, PingEvent::Timeout(seq)),
+                        Some(PingReply::Duplicate(_)) => continue,
+                        None => return,
+                    };
+                    if let Some(slot) = in_flight
+                        .iter_mut()
+                        .find(|(seq, done)| *seq == seq_cnt && done.is_none())
+                    {
+                        slot.1 = Some(event);
+                    }
+                }
+            }
+            while matches!(in_flight.front(), Some((_, Some(_)))) {
+                if let Some((_, Some(event))) = in_flight.pop_front() {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+/// Send `count` probes every `interval` and collect their results, see
+/// `Pinger::summary`.
+pub(crate) async fn summary(pinger: &mut Pinger, count: usize, interval: Duration) -> PingSummary {
+    let sender = pinger.probe_sender();
+    let replies = pinger.replies();
+    tokio::pin!(replies);
+    let mut ticker = Interval::new(interval);
+
+    let mut rtts = vec![None; count];
+    // Index in `rtts` of the probes awaiting a reply, by sequence number.
+    let mut in_flight = HashMap::new();
+    let (mut sent, mut transmitted, mut duplicates, mut errors) = (0, 0, 0, 0);
+    while sent < count || !in_flight.is_empty() {
+        tokio::select! {
+            _ = ticker.tick(), if sent < count => {
+                let seq_cnt = sender.next_seq();
+                match sender.send_probe(seq_cnt).await {
+                    Ok(_) => {
+                        transmitted += 1;
+                        in_flight.insert(seq_cnt, sent);
+                    }
+                    Err(_) => errors += 1,
+                }
+                sent += 1;
+            }
+            reply = replies.next() => match reply {
+                Some(PingReply::Reply { seq, rtt, packet }) => {
+                    if let Some(idx) = in_flight.remove(&seq) {
+                        if packet.is_echo_reply() {
+                            rtts[idx] = Some(rtt);
+                        } else {
+                            errors += 1;
+                        }
+                    }
+                }
+                Some(PingReply::Timeout(seq)) => {
+                    in_flight.remove(&seq);
+                }
+                Some(PingReply::Duplicate(_)) => duplicates += 1,
+                None => break,
+            }
+        }
+    }
+    PingSummary::new(transmitted, duplicates, errors, rtts)
+}
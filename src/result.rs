@@ -0,0 +1,218 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use crate::icmp::IcmpPacket;
+
+/// The reply to one echo request.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct PingResult {
+    /// Sequence number of the request.
+    pub seq: u16,
+    /// Identifier of the request.
+    pub ident: u16,
+    /// Round trip time.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::secs"))]
+    pub rtt: Duration,
+    /// Size of the ICMP reply, header included.
+    pub size: usize,
+    /// Source address of the reply.
+    pub source: IpAddr,
+    /// TTL of the reply, `None` for IPv6 where the hop limit isn't reported.
+    pub ttl: Option<u8>,
+    /// The decoded reply.
+    pub packet: IcmpPacket,
+    /// Number of requests sent, more than one when retransmitted.
+    pub attempts: usize,
+    /// Whether the reply repeats one already received, flagged `(DUP!)`
+    /// when displayed.
+    pub duplicate: bool,
+    /// Round trip time measured from kernel timestamps, see
+    /// `PingSocketBuilder::set_kernel_timestamps`.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::option_secs"))]
+    pub kernel_rtt: Option<Duration>,
+    /// When the reply was received, read from the socket's clock.
+    ///
+    /// An `Instant` has no meaning outside the process, so it isn't
+    /// serialized and is the time of deserialization once deserialized.
+    #[cfg_attr(feature = "serde", serde(skip, default = "Instant::now"))]
+    pub received: Instant,
+    /// Length of the packet as received, IP header included for IPv4.
+    pub len: usize,
+}
+
+impl PingResult {
+    pub(crate) fn new(meta: ReplyMeta, packet: IcmpPacket) -> PingResult {
+        PingResult {
+            seq: meta.seq,
+            ident: meta.ident,
+            rtt: meta.rtt,
+            size: packet.get_size(),
+            source: meta.source.ip(),
+            ttl: meta.ttl,
+            packet,
+            attempts: 1,
+            duplicate: false,
+            kernel_rtt: None,
+            received: meta.received,
+            len: meta.len,
+        }
+    }
+}
+
+/// What is known of a reply when it is received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ReplyMeta {
+    /// Sequence number of the request.
+    pub seq: u16,
+    /// Identifier of the request.
+    pub ident: u16,
+    /// Round trip time.
+    pub rtt: Duration,
+    /// When the reply was received, read from the socket's clock.
+    pub received: Instant,
+    /// Address the reply came from, with port 0.
+    pub source: SocketAddr,
+    /// TTL of the reply, `None` for IPv6 where the hop limit isn't reported.
+    pub ttl: Option<u8>,
+    /// Length of the packet as received, IP header included for IPv4.
+    pub len: usize,
+}
+
+/// The line printed by iputils `ping` for a reply, e.g.
+/// `64 bytes from 8.8.8.8: icmp_seq=3 ttl=117 time=12.3 ms`.
+///
+/// The hop limit of IPv6 replies is shown as `hlim=` when known. The time
+/// has the precision of `ping`: 3 decimals below 1 ms, 2 below 10 ms, 1
+/// below 100 ms, none above.
+impl fmt::Display for PingResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes from {}: icmp_seq={}",
+            self.size, self.source, self.seq
+        )?;
+        match (self.source, self.ttl) {
+            (IpAddr::V4(_), Some(ttl)) => write!(f, " ttl={}", ttl)?,
+            (IpAddr::V6(_), Some(hlim)) => write!(f, " hlim={}", hlim)?,
+            (_, None) => {}
+        }
+        // Microseconds, rounded to each precision in integers like ping does.
+        let micros = self.rtt.as_micros();
+        if micros >= 100_000 - 50 {
+            write!(f, " time={} ms", (micros + 500) / 1000)?;
+        } else if micros >= 10_000 - 5 {
+            let t = micros + 50;
+            write!(f, " time={}.{} ms", t / 1000, t % 1000 / 100)?;
+        } else if micros >= 1000 {
+            let t = micros + 5;
+            write!(f, " time={}.{:02} ms", t / 1000, t % 1000 / 10)?;
+        } else {
+            write!(f, " time={}.{:03} ms", micros / 1000, micros % 1000)?;
+        }
+        if self.duplicate {
+            write!(f, " (DUP!)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Conversion to the `(IcmpPacket, Duration)` tuple returned by `Pinger::ping`
+/// before `PingResult`, to ease migration.
+impl From<PingResult> for (IcmpPacket, Duration) {
+    fn from(result: PingResult) -> Self {
+        (result.packet, result.rtt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::icmp::icmpv4::{self, Icmpv4Packet};
+    use crate::icmp::icmpv6::{self, Icmpv6Packet};
+    use crate::icmp::wire;
+
+    const GOOGLE_V4: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8);
+
+    /// Result of a 64 bytes echo reply to request `seq` from `source`.
+    fn reply(source: IpAddr, seq: u16, rtt: Duration) -> PingResult {
+        let packet = match source {
+            IpAddr::V4(addr) => {
+                let mut message = icmpv4::make_icmpv4_echo_packet(0x3a2b, seq, 56, &[]).unwrap();
+                message[0] = 0;
+                wire::set_icmp_checksum(&mut message);
+                let buf =
+                    icmpv4::with_ipv4_header(addr, Ipv4Addr::new(192, 168, 1, 10), 117, &message);
+                IcmpPacket::V4(Icmpv4Packet::decode(&buf).unwrap())
+            }
+            IpAddr::V6(addr) => {
+                let mut message = icmpv6::make_icmpv6_echo_packet(0x3a2b, seq, 56, &[]).unwrap();
+                message[0] = 0x81;
+                IcmpPacket::V6(Icmpv6Packet::decode(&message, addr).unwrap())
+            }
+        };
+        let meta = ReplyMeta {
+            seq,
+            ident: 0x3a2b,
+            rtt,
+            received: Instant::now(),
+            source: SocketAddr::new(source, 0),
+            ttl: packet.get_ttl(),
+            len: packet.as_bytes().len(),
+        };
+        PingResult::new(meta, packet)
+    }
+
+    /// Lines printed by iputils `ping` for the same replies.
+    #[test]
+    fn display_matches_iputils() {
+        let v4 = IpAddr::V4(GOOGLE_V4);
+        let cases = [
+            (42, "time=0.042 ms"),
+            (999, "time=0.999 ms"),
+            (1000, "time=1.00 ms"),
+            (1234, "time=1.23 ms"),
+            (1235, "time=1.24 ms"),
+            (9994, "time=9.99 ms"),
+            (9995, "time=10.0 ms"),
+            (12_345, "time=12.3 ms"),
+            (99_949, "time=99.9 ms"),
+            (99_950, "time=100 ms"),
+            (123_456, "time=123 ms"),
+            (1_234_567, "time=1235 ms"),
+        ];
+        for &(micros, time) in cases.iter() {
+            let result = reply(v4, 3, Duration::from_micros(micros));
+            assert_eq!(
+                result.to_string(),
+                format!("64 bytes from 8.8.8.8: icmp_seq=3 ttl=117 {}", time)
+            );
+        }
+    }
+
+    #[test]
+    fn display_hop_limit_and_duplicates() {
+        let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888));
+        let mut result = reply(v6, 1, Duration::from_micros(512));
+        assert_eq!(
+            result.to_string(),
+            "64 bytes from 2001:4860:4860::8888: icmp_seq=1 time=0.512 ms"
+        );
+        result.ttl = Some(52);
+        assert_eq!(
+            result.to_string(),
+            "64 bytes from 2001:4860:4860::8888: icmp_seq=1 hlim=52 time=0.512 ms"
+        );
+
+        let mut result = reply(IpAddr::V4(GOOGLE_V4), 3, Duration::from_micros(12_345));
+        result.duplicate = true;
+        assert_eq!(
+            result.to_string(),
+            "64 bytes from 8.8.8.8: icmp_seq=3 ttl=117 time=12.3 ms (DUP!)"
+        );
+    }
+}
@@ -0,0 +1,514 @@
+//! The async runtime the crate runs on: socket readiness, timers and
+//! spawning, selected with the `runtime-tokio` (default) or the
+//! `runtime-async-std` feature. `runtime-tokio` wins when both are enabled.
+//!
+//! Channels and locks come from `tokio::sync`, which works on any runtime.
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, AsSocket, BorrowedSocket, RawSocket};
+
+#[cfg(not(any(feature = "runtime-tokio", feature = "runtime-async-std")))]
+compile_error!("surge-ping needs the `runtime-tokio` or the `runtime-async-std` feature");
+
+#[cfg(feature = "runtime-tokio")]
+use tokio_rt as imp;
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+use async_std_rt as imp;
+
+pub(crate) use imp::JoinHandle;
+
+/// The deadline of a timeout expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Elapsed;
+
+/// A UDP socket, of type `SOCK_RAW` or `SOCK_DGRAM` here, registered with
+/// the runtime's reactor.
+pub(crate) struct UdpSocket(imp::Socket);
+
+impl UdpSocket {
+    /// Register `socket`, which must be non-blocking, with the runtime.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
+        imp::Socket::from_std(socket).map(UdpSocket)
+    }
+
+    /// Wait until the socket may be readable.
+    #[cfg(not(target_os = "linux"))]
+    pub async fn readable(&self) -> io::Result<()> {
+        self.0.readable().await
+    }
+
+    /// Run the non-blocking read `op` once the socket is readable, again
+    /// each time it fails with `WouldBlock`.
+    #[cfg(target_os = "linux")]
+    pub async fn read_with<R>(&self, op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+        self.0.read_with(op).await
+    }
+
+    /// Run the non-blocking write `op` once the socket is writable, again
+    /// each time it fails with `WouldBlock`.
+    #[cfg(target_os = "linux")]
+    pub async fn write_with<R>(&self, op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+        self.0.write_with(op).await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.recv_from(buf).await
+    }
+
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.0.send(buf).await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+        self.0.send_to(buf, *target).await
+    }
+
+    pub async fn connect(&self, target: SocketAddr) -> io::Result<()> {
+        self.0.connect(target).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.std().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl AsFd for UdpSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.std().as_fd()
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for UdpSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        self.0.std().as_raw_socket()
+    }
+}
+
+#[cfg(windows)]
+impl AsSocket for UdpSocket {
+    fn as_socket(&self) -> BorrowedSocket<'_> {
+        self.0.std().as_socket()
+    }
+}
+
+/// Spawn `future` as a task named `name`.
+///
+/// tokio only names tasks with the `task-names` feature and
+/// `--cfg tokio_unstable`, for tokio-console.
+pub(crate) fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    imp::spawn(name, future)
+}
+
+/// Wait for the task of `handle`, `None` if it panicked or was cancelled.
+pub(crate) async fn join<T>(handle: JoinHandle<T>) -> Option<T> {
+    imp::join(handle).await
+}
+
+/// Run the blocking `f` on the runtime's thread pool.
+#[cfg(windows)]
+pub(crate) fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    imp::spawn_blocking(f)
+}
+
+/// Resolve `host` with the system resolver, through the runtime's thread
+/// pool.
+#[cfg(feature = "dns")]
+pub(crate) async fn lookup_host(host: &str) -> io::Result<Vec<SocketAddr>> {
+    imp::lookup_host(host).await
+}
+
+/// The current time of the runtime's clock.
+pub(crate) fn now() -> Instant {
+    imp::now()
+}
+
+pub(crate) async fn sleep(duration: Duration) {
+    imp::sleep(duration).await
+}
+
+/// Wait up to `duration` for `future` to complete.
+pub(crate) async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    imp::timeout_at(now() + duration, future).await
+}
+
+/// Wait until `deadline` for `future` to complete.
+pub(crate) async fn timeout_at<F: Future>(
+    deadline: Instant,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    imp::timeout_at(deadline, future).await
+}
+
+/// A timer polled by hand, moved with `reset`.
+pub(crate) struct Sleep(imp::Sleep);
+
+impl Sleep {
+    /// A timer firing at once.
+    pub fn new() -> Sleep {
+        Sleep(imp::Sleep::new())
+    }
+
+    /// Fire at `deadline` instead.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.0.reset(deadline)
+    }
+
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.poll(cx)
+    }
+}
+
+/// Ticks every `period`, the first one at once. A late tick delays the
+/// following ones rather than bursting to catch up.
+pub(crate) struct Interval(imp::Interval);
+
+impl Interval {
+    pub fn new(period: Duration) -> Interval {
+        Interval(imp::Interval::new(period))
+    }
+
+    /// Wait for the next tick. Cancelling the wait doesn't lose the tick.
+    pub async fn tick(&mut self) {
+        self.0.tick().await
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+mod tokio_rt {
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    #[cfg(target_os = "linux")]
+    use tokio::io::Interest;
+    use tokio::time::MissedTickBehavior;
+
+    use super::Elapsed;
+
+    pub(crate) type JoinHandle<T> = tokio::task::JoinHandle<T>;
+
+    pub(super) struct Socket(tokio::net::UdpSocket);
+
+    impl Socket {
+        pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Socket> {
+            tokio::net::UdpSocket::from_std(socket).map(Socket)
+        }
+
+        pub fn std(&self) -> &tokio::net::UdpSocket {
+            &self.0
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub async fn readable(&self) -> io::Result<()> {
+            self.0.readable().await
+        }
+
+        #[cfg(target_os = "linux")]
+        pub async fn read_with<R>(&self, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+            loop {
+                self.0.readable().await?;
+                match self.0.try_io(Interest::READABLE, &mut op) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    res => return res,
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        pub async fn write_with<R>(&self, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+            loop {
+                self.0.writable().await?;
+                match self.0.try_io(Interest::WRITABLE, &mut op) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    res => return res,
+                }
+            }
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+
+        pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf).await
+        }
+
+        pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, target).await
+        }
+
+        pub async fn connect(&self, target: SocketAddr) -> io::Result<()> {
+            self.0.connect(target).await
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.local_addr()
+        }
+    }
+
+    #[cfg_attr(
+        not(all(tokio_unstable, feature = "task-names")),
+        allow(unused_variables)
+    )]
+    pub(super) fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        #[cfg(all(tokio_unstable, feature = "task-names"))]
+        return tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("failed to spawn a task");
+        #[cfg(not(all(tokio_unstable, feature = "task-names")))]
+        tokio::task::spawn(future)
+    }
+
+    pub(super) async fn join<T>(handle: JoinHandle<T>) -> Option<T> {
+        handle.await.ok()
+    }
+
+    #[cfg(windows)]
+    pub(super) fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f)
+    }
+
+    #[cfg(feature = "dns")]
+    pub(super) async fn lookup_host(host: &str) -> io::Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, 0)).await?.collect())
+    }
+
+    /// tokio's clock, which tests may pause and advance.
+    pub(super) fn now() -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    pub(super) async fn sleep(duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    pub(super) async fn timeout_at<F: Future>(
+        deadline: Instant,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        tokio::time::timeout_at(tokio::time::Instant::from_std(deadline), future)
+            .await
+            .map_err(|_| Elapsed)
+    }
+
+    pub(super) struct Sleep(Pin<Box<tokio::time::Sleep>>);
+
+    impl Sleep {
+        pub fn new() -> Sleep {
+            Sleep(Box::pin(tokio::time::sleep(Duration::ZERO)))
+        }
+
+        pub fn reset(&mut self, deadline: Instant) {
+            self.0
+                .as_mut()
+                .reset(tokio::time::Instant::from_std(deadline));
+        }
+
+        pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            self.0.as_mut().poll(cx)
+        }
+    }
+
+    pub(super) struct Interval(tokio::time::Interval);
+
+    impl Interval {
+        pub fn new(period: Duration) -> Interval {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Interval(interval)
+        }
+
+        pub async fn tick(&mut self) {
+            self.0.tick().await;
+        }
+    }
+}
+
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+mod async_std_rt {
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+
+    use async_io::{Async, Timer};
+
+    use super::Elapsed;
+
+    pub(crate) type JoinHandle<T> = async_std::task::JoinHandle<T>;
+
+    pub(super) struct Socket(Async<std::net::UdpSocket>);
+
+    impl Socket {
+        pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Socket> {
+            Async::new(socket).map(Socket)
+        }
+
+        pub fn std(&self) -> &std::net::UdpSocket {
+            self.0.get_ref()
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub async fn readable(&self) -> io::Result<()> {
+            self.0.readable().await
+        }
+
+        #[cfg(target_os = "linux")]
+        pub async fn read_with<R>(&self, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+            self.0.read_with(|_| op()).await
+        }
+
+        #[cfg(target_os = "linux")]
+        pub async fn write_with<R>(&self, mut op: impl FnMut() -> io::Result<R>) -> io::Result<R> {
+            self.0.write_with(|_| op()).await
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf).await
+        }
+
+        pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf).await
+        }
+
+        pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, target).await
+        }
+
+        pub async fn connect(&self, target: SocketAddr) -> io::Result<()> {
+            self.0.get_ref().connect(target)
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.0.get_ref().local_addr()
+        }
+    }
+
+    pub(super) fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        async_std::task::Builder::new()
+            .name(name.to_string())
+            .spawn(future)
+            .expect("failed to spawn a task")
+    }
+
+    pub(super) async fn join<T>(handle: JoinHandle<T>) -> Option<T> {
+        Some(handle.await)
+    }
+
+    #[cfg(windows)]
+    pub(super) fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        async_std::task::spawn_blocking(f)
+    }
+
+    #[cfg(feature = "dns")]
+    pub(super) async fn lookup_host(host: &str) -> io::Result<Vec<SocketAddr>> {
+        use async_std::net::ToSocketAddrs;
+        Ok((host, 0).to_socket_addrs().await?.collect())
+    }
+
+    pub(super) fn now() -> Instant {
+        Instant::now()
+    }
+
+    pub(super) async fn sleep(duration: Duration) {
+        Timer::after(duration).await;
+    }
+
+    pub(super) async fn timeout_at<F: Future>(
+        deadline: Instant,
+        future: F,
+    ) -> Result<F::Output, Elapsed> {
+        let duration = deadline.saturating_duration_since(Instant::now());
+        async_std::future::timeout(duration, future)
+            .await
+            .map_err(|_| Elapsed)
+    }
+
+    pub(super) struct Sleep(Timer);
+
+    impl Sleep {
+        pub fn new() -> Sleep {
+            Sleep(Timer::at(Instant::now()))
+        }
+
+        pub fn reset(&mut self, deadline: Instant) {
+            self.0.set_at(deadline);
+        }
+
+        pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+            Pin::new(&mut self.0).poll(cx).map(|_| ())
+        }
+    }
+
+    pub(super) struct Interval {
+        period: Duration,
+        next: Instant,
+    }
+
+    impl Interval {
+        pub fn new(period: Duration) -> Interval {
+            Interval {
+                period,
+                next: Instant::now(),
+            }
+        }
+
+        pub async fn tick(&mut self) {
+            Timer::at(self.next).await;
+            self.next = Instant::now() + self.period;
+        }
+    }
+}
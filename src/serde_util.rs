@@ -0,0 +1,93 @@
+//! Serialization helpers of the `serde` feature.
+//!
+//! Durations are serialized as float seconds, e.g. `0.0123` for 12.3 ms.
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A `Duration` serialized as float seconds.
+#[derive(Serialize, Deserialize)]
+struct Secs(#[serde(with = "secs")] Duration);
+
+pub mod secs {
+    use super::*;
+    use serde::de::Error as _;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_f64(duration.as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let secs = f64::deserialize(d)?;
+        if !secs.is_finite() || secs < 0f64 || secs > u64::MAX as f64 {
+            return Err(D::Error::custom(format!("invalid duration {} s", secs)));
+        }
+        Ok(Duration::from_secs_f64(secs))
+    }
+}
+
+pub mod option_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+        duration.map(Secs).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<Secs>::deserialize(d)?.map(|secs| secs.0))
+    }
+}
+
+pub mod vec_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(durations: &[Duration], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(durations.iter().map(|duration| Secs(*duration)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Duration>, D::Error> {
+        let durations = Vec::<Secs>::deserialize(d)?;
+        Ok(durations.into_iter().map(|secs| secs.0).collect())
+    }
+}
+
+pub mod vec_option_secs {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        durations: &[Option<Duration>],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.collect_seq(durations.iter().map(|duration| duration.map(Secs)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Option<Duration>>, D::Error> {
+        let durations = Vec::<Option<Secs>>::deserialize(d)?;
+        Ok(durations
+            .into_iter()
+            .map(|secs| secs.map(|secs| secs.0))
+            .collect())
+    }
+}
+
+/// ICMP type and code newtypes serialized as their number.
+macro_rules! serde_u8_newtype {
+    ($module:ident, $ty:ty) => {
+        pub mod $module {
+            use super::*;
+
+            pub fn serialize<S: Serializer>(value: &$ty, s: S) -> Result<S::Ok, S::Error> {
+                s.serialize_u8(value.0)
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<$ty, D::Error> {
+                u8::deserialize(d).map(<$ty>::new)
+            }
+        }
+    };
+}
+
+serde_u8_newtype!(icmp_type, crate::icmp::icmpv4::IcmpType);
+serde_u8_newtype!(icmp_code, crate::icmp::icmpv4::IcmpCode);
+serde_u8_newtype!(icmpv6_type, crate::icmp::icmpv6::Icmpv6Type);
+serde_u8_newtype!(icmpv6_code, crate::icmp::icmpv6::Icmpv6Code);
@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+/// Aggregate statistics of the pings sent to one destination.
+#[derive(Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingStats {
+    transmitted: usize,
+    received: usize,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::vec_secs"))]
+    rtts: Vec<Duration>,
+}
+
+impl PingStats {
+    /// Record one probe, `None` meaning it was lost.
+    pub(crate) fn update(&mut self, rtt: Option<Duration>) {
+        self.transmitted += 1;
+        if let Some(rtt) = rtt {
+            self.received += 1;
+            self.rtts.push(rtt);
+        }
+    }
+
+    /// Get the number of echo requests sent.
+    pub fn get_transmitted(&self) -> usize {
+        self.transmitted
+    }
+
+    /// Get the number of echo replies received.
+    pub fn get_received(&self) -> usize {
+        self.received
+    }
+
+    /// Get the round trip times of the received replies.
+    pub fn get_rtts(&self) -> &[Duration] {
+        &self.rtts
+    }
+
+    /// Packet loss in percent.
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0f64;
+        }
+        (self.transmitted - self.received) as f64 / self.transmitted as f64 * 100f64
+    }
+
+    /// Minimum round trip time.
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    /// Maximum round trip time.
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    /// Average round trip time.
+    pub fn avg(&self) -> Option<Duration> {
+        let sum: Duration = self.rtts.iter().sum();
+        sum.checked_div(self.rtts.len() as u32)
+    }
+}
+
+/// Summary of a run of pings, like the statistics printed by `ping` when it
+/// exits, see `Pinger::summary`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PingSummary {
+    /// Number of echo requests sent.
+    pub transmitted: usize,
+    /// Number of requests answered by an echo reply.
+    pub received: usize,
+    /// Number of additional replies to already answered requests.
+    pub duplicates: usize,
+    /// Number of requests answered by an ICMP error or that failed to send.
+    pub errors: usize,
+    /// Packet loss in percent.
+    pub loss_pct: f64,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::option_secs"))]
+    pub min: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::option_secs"))]
+    pub avg: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::option_secs"))]
+    pub max: Option<Duration>,
+    /// Standard deviation of the round trip times (`mdev` of iputils).
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::option_secs"))]
+    pub stddev: Option<Duration>,
+    /// Round trip time of each request in send order, `None` if unanswered.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_util::vec_option_secs"))]
+    pub rtts: Vec<Option<Duration>>,
+}
+
+impl PingSummary {
+    pub(crate) fn new(
+        transmitted: usize,
+        duplicates: usize,
+        errors: usize,
+        rtts: Vec<Option<Duration>>,
+    ) -> PingSummary {
+        let received: Vec<f64> = rtts.iter().flatten().map(Duration::as_secs_f64).collect();
+        let loss_pct = if transmitted == 0 {
+            0f64
+        } else {
+            transmitted.saturating_sub(received.len()) as f64 / transmitted as f64 * 100f64
+        };
+        let (avg, stddev) = if received.is_empty() {
+            (None, None)
+        } else {
+            let n = received.len() as f64;
+            let mean = received.iter().sum::<f64>() / n;
+            let variance = received.iter().map(|rtt| rtt * rtt).sum::<f64>() / n - mean * mean;
+            (
+                Some(Duration::from_secs_f64(mean)),
+                Some(Duration::from_secs_f64(variance.max(0f64).sqrt())),
+            )
+        };
+        PingSummary {
+            transmitted,
+            received: received.len(),
+            duplicates,
+            errors,
+            loss_pct,
+            min: rtts.iter().flatten().min().copied(),
+            avg,
+            max: rtts.iter().flatten().max().copied(),
+            stddev,
+            rtts,
+        }
+    }
+}
+
+/// Idle time after which the RTT estimate is considered stale and restarted.
+const RTT_IDLE_RESET: Duration = Duration::from_secs(60);
+
+/// Smoothed RTT and RTT variation, estimated as in RFC 6298.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    last: Option<Instant>,
+}
+
+impl RttEstimator {
+    /// Account for a reply received at `now` after `rtt`.
+    pub fn update(&mut self, rtt: Duration, now: Instant) {
+        if matches!(self.last, Some(last) if now.saturating_duration_since(last) > RTT_IDLE_RESET) {
+            self.reset();
+        }
+        self.last = Some(now);
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = RttEstimator::default();
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.srtt.map(|_| self.rttvar)
+    }
+
+    /// Retransmission timeout `srtt + 4 * rttvar`, once there is an estimate.
+    pub fn rto(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
+    }
+}
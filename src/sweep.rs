@@ -0,0 +1,178 @@
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use ipnet::{IpNet, Ipv4AddrRange, Ipv6AddrRange};
+use parking_lot::Mutex;
+use tokio::sync::mpsc::channel;
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+
+use crate::error::Result;
+use crate::ping::PingOptions;
+use crate::pingsocket::PingSocket;
+use crate::result::PingResult;
+use crate::rt;
+
+/// Options of a subnet sweep.
+#[derive(Debug, Clone)]
+pub struct SweepOptions {
+    concurrency: usize,
+    size: usize,
+    timeout: Duration,
+    skip_network_broadcast: bool,
+    tos: Option<u8>,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        SweepOptions {
+            concurrency: 256,
+            size: 56,
+            timeout: Duration::from_secs(2),
+            skip_network_broadcast: true,
+            tos: None,
+        }
+    }
+}
+
+impl SweepOptions {
+    /// Set the number of hosts pinged at the same time. (default: 256)
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Set the packet size.(default: 56)
+    pub fn size(&mut self, size: usize) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    /// The timeout of each Ping. (default: 2s)
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Skip the network and broadcast addresses of IPv4 networks. (default: true)
+    pub fn skip_network_broadcast(&mut self, skip: bool) -> &mut Self {
+        self.skip_network_broadcast = skip;
+        self
+    }
+
+    /// Set the TOS byte, or the traffic class for IPv6, of the probes, e.g.
+    /// to sweep with a given DSCP. Linux only. (default: the socket's)
+    pub fn tos(&mut self, tos: u8) -> &mut Self {
+        self.tos = Some(tos);
+        self
+    }
+}
+
+/// Ping every host address of `cidr` once, yielding results as they complete.
+///
+/// All probes share `socket`, so its pps limiter caps the overall send rate.
+/// Pingers already pinging a host of `cidr` on `socket` keep receiving their
+/// replies. Dropping the stream stops the sweep. `Sweep::collect_responders` keeps
+/// only the hosts that replied.
+///
+/// # Examples
+/// ```no_run
+/// use surge_ping::{sweep, PingSocket, SweepOptions};
+/// use tokio_stream::StreamExt;
+///
+/// #[tokio::main]
+/// async fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let socket = PingSocket::new(socket2::Domain::IPV4)?;
+///     let mut results = sweep(&socket, "192.168.1.0/24", &SweepOptions::default())?;
+///     while let Some((addr, result)) = results.next().await {
+///         println!("{} {:?}", addr, result.map(|reply| reply.rtt));
+///     }
+///     Ok(())
+/// }
+/// ```
+pub fn sweep(socket: &PingSocket, cidr: &str, options: &SweepOptions) -> Result<Sweep> {
+    let net: IpNet = cidr.parse()?;
+    socket.check_family(net.network())?;
+    let hosts: Box<dyn Iterator<Item = IpAddr> + Send> = match net {
+        IpNet::V4(net) if options.skip_network_broadcast => Box::new(net.hosts().map(IpAddr::V4)),
+        IpNet::V4(net) => {
+            Box::new(Ipv4AddrRange::new(net.network(), net.broadcast()).map(IpAddr::V4))
+        }
+        IpNet::V6(net) => {
+            Box::new(Ipv6AddrRange::new(net.network(), net.broadcast()).map(IpAddr::V6))
+        }
+    };
+    let hosts = Arc::new(Mutex::new(hosts));
+    let concurrency = options.concurrency.max(1);
+    let (tx, rx) = channel(concurrency);
+    for _ in 0..concurrency {
+        let socket = socket.clone();
+        let hosts = hosts.clone();
+        let tx = tx.clone();
+        let options = options.clone();
+        rt::spawn("surge-ping-sweep", async move {
+            loop {
+                let next = hosts.lock().next();
+                let addr = match next {
+                    Some(addr) => addr,
+                    None => break,
+                };
+                let result = match socket.pinger_beside(addr).await {
+                    Ok(mut pinger) => {
+                        pinger.size(options.size).timeout(options.timeout);
+                        let ping_options = PingOptions {
+                            tos: options.tos,
+                            ..Default::default()
+                        };
+                        pinger.ping_with(0, &ping_options).await
+                    }
+                    Err(e) => Err(e),
+                };
+                if tx.send((addr, result)).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Ok(Sweep {
+        results: ReceiverStream::new(rx),
+    })
+}
+
+/// The results of a running sweep, a `Stream` of each host address with the
+/// result of its ping, in completion order. See `sweep`.
+pub struct Sweep {
+    results: ReceiverStream<(IpAddr, Result<PingResult>)>,
+}
+
+impl Sweep {
+    /// Drain the sweep for up to `timeout`, returning the addresses of the
+    /// hosts that replied, in reply order.
+    ///
+    /// Hosts that didn't reply, failed, or were still pending at the
+    /// deadline are left out, the rest of the sweep is stopped.
+    pub async fn collect_responders(mut self, timeout: Duration) -> Vec<IpAddr> {
+        let deadline = rt::now() + timeout;
+        let mut responders = Vec::new();
+        while let Ok(Some((addr, result))) = rt::timeout_at(deadline, self.next()).await {
+            if result.is_ok() {
+                responders.push(addr);
+            }
+        }
+        responders
+    }
+}
+
+impl Stream for Sweep {
+    type Item = (IpAddr, Result<PingResult>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.results).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.results.size_hint()
+    }
+}
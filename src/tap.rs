@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use parking_lot::RwLock;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+type TapPacket = (Instant, SocketAddr, Bytes);
+
+/// A subscription to every ICMP packet received on a `PingSocket`.
+///
+/// Packets are copied to the tap before being routed to pingers, including
+/// packets no pinger is waiting for. A tap that doesn't keep up loses packets
+/// instead of stalling the receive task, see [`Tap::dropped`]. Dropping the
+/// tap unsubscribes it.
+pub struct Tap {
+    rx: Receiver<TapPacket>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Tap {
+    /// Receive the next packet with its receive time and source address.
+    ///
+    /// Returns `None` once the socket and its receive task are gone.
+    pub async fn recv(&mut self) -> Option<(Instant, SocketAddr, Bytes)> {
+        self.rx.recv().await
+    }
+
+    /// Number of packets lost because the tap's buffer was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct Subscriber {
+    tx: Sender<TapPacket>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// The taps subscribed to one socket.
+pub(crate) struct TapSet {
+    subscribers: RwLock<Vec<Subscriber>>,
+    count: AtomicUsize,
+}
+
+impl TapSet {
+    pub fn new() -> TapSet {
+        TapSet {
+            subscribers: RwLock::new(Vec::new()),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn subscribe(&self, capacity: usize) -> Tap {
+        let (tx, rx) = channel(capacity.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        let mut subscribers = self.subscribers.write();
+        subscribers.push(Subscriber {
+            tx,
+            dropped: dropped.clone(),
+        });
+        self.count.store(subscribers.len(), Ordering::Release);
+        Tap { rx, dropped }
+    }
+
+    /// Copy a received packet to every tap, never waiting on a slow one.
+    pub fn publish(&self, when: Instant, from: SocketAddr, packet: &[u8]) {
+        if self.count.load(Ordering::Acquire) == 0 {
+            return;
+        }
+        let packet = Bytes::copy_from_slice(packet);
+        let mut closed = false;
+        for subscriber in self.subscribers.read().iter() {
+            match subscriber.tx.try_send((when, from, packet.clone())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Closed(_)) => closed = true,
+            }
+        }
+        if closed {
+            let mut subscribers = self.subscribers.write();
+            subscribers.retain(|subscriber| !subscriber.tx.is_closed());
+            self.count.store(subscribers.len(), Ordering::Release);
+        }
+    }
+}
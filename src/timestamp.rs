@@ -0,0 +1,49 @@
+use std::convert::TryInto;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Bytes of payload used by an embedded send timestamp.
+pub(crate) const TIMESTAMP_SIZE: usize = 16;
+/// Bytes of payload used by an embedded nonce.
+pub(crate) const NONCE_SIZE: usize = 8;
+
+/// How the round trip time of a reply was measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RttSource {
+    /// From the send timestamp echoed back in the reply payload.
+    Payload,
+    /// From the send time recorded by the pinger.
+    Cache,
+}
+
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+/// Write `when` to the start of `buf`: monotonic nanoseconds since a process
+/// wide epoch followed by their bitwise complement, both big endian.
+pub(crate) fn write_timestamp(buf: &mut [u8], when: Instant) {
+    let nanos = when.saturating_duration_since(epoch()).as_nanos() as u64;
+    buf[0..8].copy_from_slice(&nanos.to_be_bytes());
+    buf[8..16].copy_from_slice(&(!nanos).to_be_bytes());
+}
+
+/// Read a timestamp written by `write_timestamp`, `None` if `buf` is too
+/// short or the complement doesn't match.
+pub(crate) fn read_timestamp(buf: &[u8]) -> Option<Instant> {
+    if buf.len() < TIMESTAMP_SIZE {
+        return None;
+    }
+    let nanos = u64::from_be_bytes(buf[0..8].try_into().ok()?);
+    let check = u64::from_be_bytes(buf[8..16].try_into().ok()?);
+    if nanos != !check {
+        return None;
+    }
+    epoch().checked_add(Duration::from_nanos(nanos))
+}
+
+/// Read a nonce from the start of `buf`, `None` if `buf` is too short.
+pub(crate) fn read_nonce(buf: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.get(..NONCE_SIZE)?.try_into().ok()?))
+}
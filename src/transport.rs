@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::mpsc::channel;
+
+use crate::clock::{Clock, TokioClock};
+use crate::icmp::icmpv4::{with_ipv4_header, IcmpType};
+use crate::icmp::icmpv6::Icmpv6Type;
+use crate::icmp::wire;
+use crate::kstamp::KernelTimestamp;
+use crate::ping::{Pinger, PingerConfig};
+use crate::pingsocket::{AsyncSocket, MAX_RECV_PACKET_SIZE};
+use crate::pmap::PingerMap;
+use crate::rt;
+
+pub(crate) type IoFuture<'a, T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'a>>;
+
+/// IP header fields overridden for a single send, see `PingOptions`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Marking {
+    pub ttl: Option<u8>,
+    pub tos: Option<u8>,
+}
+
+impl Marking {
+    pub fn is_empty(&self) -> bool {
+        self.ttl.is_none() && self.tos.is_none()
+    }
+}
+
+/// Sends the echo requests of a `Pinger`.
+///
+/// Replies don't go through the transport, they are routed to the pinger's
+/// channel by whoever owns it: the receive task of a socket, or the mock.
+pub(crate) trait Transport: Send + Sync {
+    /// Send `packet`, an ICMP message without IP header, to `target`.
+    fn send_to<'a>(&'a self, packet: &'a [u8], target: SocketAddr) -> IoFuture<'a, usize>;
+
+    /// Send `packet` to `target` with the TTL and TOS of `marking` instead of
+    /// the transport's own, for this packet only.
+    fn send_marked<'a>(
+        &'a self,
+        packet: &'a [u8],
+        target: SocketAddr,
+        marking: Marking,
+    ) -> IoFuture<'a, usize> {
+        if marking.is_empty() {
+            return self.send_to(packet, target);
+        }
+        Box::pin(async {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the transport can't mark a single request",
+            ))
+        })
+    }
+
+    /// Connect to `target` if the transport belongs to a single pinger,
+    /// returning false if connecting isn't supported.
+    fn connect(&self, target: SocketAddr) -> IoFuture<'_, bool>;
+
+    /// Local address the requests are sent from.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Set the TTL, or the hop limit for IPv6, of the requests.
+    fn set_ttl(&self, ttl: u32) -> io::Result<()>;
+
+    /// The TTL, or the hop limit for IPv6, of the requests.
+    fn ttl(&self) -> io::Result<u32>;
+
+    /// The current time of the transport's clock.
+    fn now(&self) -> Instant;
+
+    /// Take the kernel send timestamp of request `seq` of `ident`, if the
+    /// transport timestamps requests.
+    fn take_tx_timestamp(&self, ident: u16, seq: u16) -> Option<KernelTimestamp>;
+
+    /// Size of the largest reply that can be received, IP header included for
+    /// IPv4.
+    fn recv_packet_size(&self) -> usize;
+
+    /// Make room to receive replies of `size` bytes, IP header included.
+    fn reserve_recv_packet_size(&self, size: usize);
+}
+
+impl Transport for AsyncSocket {
+    fn send_to<'a>(&'a self, packet: &'a [u8], target: SocketAddr) -> IoFuture<'a, usize> {
+        Box::pin(async move { AsyncSocket::send_to(self, packet, &target).await })
+    }
+
+    fn send_marked<'a>(
+        &'a self,
+        packet: &'a [u8],
+        target: SocketAddr,
+        marking: Marking,
+    ) -> IoFuture<'a, usize> {
+        Box::pin(async move { AsyncSocket::send_marked(self, packet, &target, marking).await })
+    }
+
+    fn connect(&self, target: SocketAddr) -> IoFuture<'_, bool> {
+        Box::pin(AsyncSocket::connect(self, target))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        AsyncSocket::local_addr(self)
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        AsyncSocket::set_ttl(self, ttl)
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        AsyncSocket::ttl(self)
+    }
+
+    fn now(&self) -> Instant {
+        AsyncSocket::now(self)
+    }
+
+    fn take_tx_timestamp(&self, ident: u16, seq: u16) -> Option<KernelTimestamp> {
+        AsyncSocket::take_tx_timestamp(self, ident, seq)
+    }
+
+    fn recv_packet_size(&self) -> usize {
+        AsyncSocket::recv_packet_size(self)
+    }
+
+    fn reserve_recv_packet_size(&self, size: usize) {
+        AsyncSocket::reserve_recv_packet_size(self, size)
+    }
+}
+
+/// An in-memory transport answering echo requests with canned replies, to
+/// test code built on `Pinger` without a raw socket.
+///
+/// Requests without a canned reply are never answered and time out, like
+/// dropped packets. A request answered twice gets a duplicate reply, and
+/// `inject` delivers arbitrary, e.g. malformed, packets.
+///
+/// # Examples
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use surge_ping::MockTransport;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let mock = MockTransport::new();
+///     let mut pinger = mock.pinger("192.0.2.1".parse().unwrap());
+///     pinger.ident(7);
+///     mock.reply(7, 0, Duration::from_millis(10));
+///     assert!(pinger.ping(0).await.is_ok());
+///     assert!(pinger.ping(1).await.is_err());
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MockTransport {
+    inner: Arc<MockInner>,
+}
+
+struct MockInner {
+    replies: Mutex<HashMap<(u16, u16), Vec<Duration>>>,
+    sent: Mutex<Vec<(IpAddr, u16, u16)>>,
+    ttl: Mutex<u32>,
+    clock: Arc<dyn Clock>,
+    pmap: Arc<PingerMap>,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        MockTransport::new()
+    }
+}
+
+impl MockTransport {
+    pub fn new() -> MockTransport {
+        MockTransport::with_clock(Arc::new(TokioClock))
+    }
+
+    /// Creates a mock transport reading the send and reply times from
+    /// `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> MockTransport {
+        MockTransport {
+            inner: Arc::new(MockInner {
+                replies: Mutex::new(HashMap::new()),
+                sent: Mutex::new(Vec::new()),
+                ttl: Mutex::new(64),
+                clock,
+                pmap: Arc::new(PingerMap::new()),
+            }),
+        }
+    }
+
+    /// Creates a pinger to `destination` sending through this transport.
+    pub fn pinger(&self, destination: IpAddr) -> Pinger {
+        let config = PingerConfig::default();
+        let (tx, rx) = channel(config.channel_capacity);
+        let registration = self.inner.pmap.insert(destination, config.ident, tx);
+        Pinger::new_pinger(
+            destination,
+            Arc::new(self.clone()),
+            rx,
+            config,
+            registration,
+            None,
+        )
+    }
+
+    /// Answer the next request with `ident` and `seq` after `delay`.
+    ///
+    /// The reply echoes the request, like a real host does. Calling it again
+    /// for the same request answers it once more, with a duplicate reply.
+    pub fn reply(&self, ident: u16, seq: u16, delay: Duration) {
+        self.inner
+            .replies
+            .lock()
+            .entry((ident, seq))
+            .or_default()
+            .push(delay);
+    }
+
+    /// Deliver `packet` to the pinger of `destination` after `delay`, as
+    /// received from it, whatever was sent.
+    ///
+    /// The packet is what a raw socket receives: an ICMP message with its
+    /// IPv4 header for IPv4, without header for IPv6. It may be malformed,
+    /// to check how the pinger handles it.
+    pub fn inject(&self, destination: IpAddr, packet: &[u8], delay: Duration) {
+        self.inner.deliver(destination, packet.to_vec(), delay);
+    }
+
+    /// The `(destination, ident, seq)` of every request sent so far.
+    pub fn sent(&self) -> Vec<(IpAddr, u16, u16)> {
+        self.inner.sent.lock().clone()
+    }
+}
+
+impl MockInner {
+    /// Hand `packet` to the pinger of `destination` after `delay`.
+    fn deliver(self: &Arc<Self>, destination: IpAddr, packet: Vec<u8>, delay: Duration) {
+        let inner = self.clone();
+        rt::spawn("surge-ping-mock-reply", async move {
+            rt::sleep(delay).await;
+            let now = inner.clock.now();
+            inner
+                .pmap
+                .dispatch(&destination, None, destination, now, None, &packet);
+        });
+    }
+}
+
+impl Transport for MockTransport {
+    fn send_to<'a>(&'a self, packet: &'a [u8], target: SocketAddr) -> IoFuture<'a, usize> {
+        Box::pin(async move {
+            let (ident, seq) = wire::read_query_header(packet).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "ICMP message too short")
+            })?;
+            let destination = target.ip();
+            self.inner.sent.lock().push((destination, ident, seq));
+            let delays = self.inner.replies.lock().remove(&(ident, seq));
+            for delay in delays.unwrap_or_default() {
+                self.inner
+                    .deliver(destination, echo_reply(destination, packet), delay);
+            }
+            Ok(packet.len())
+        })
+    }
+
+    fn send_marked<'a>(
+        &'a self,
+        packet: &'a [u8],
+        target: SocketAddr,
+        _marking: Marking,
+    ) -> IoFuture<'a, usize> {
+        self.send_to(packet, target)
+    }
+
+    fn connect(&self, _target: SocketAddr) -> IoFuture<'_, bool> {
+        Box::pin(async { Ok(true) })
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "a mock transport has no local address",
+        ))
+    }
+
+    fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        *self.inner.ttl.lock() = ttl;
+        Ok(())
+    }
+
+    fn ttl(&self) -> io::Result<u32> {
+        Ok(*self.inner.ttl.lock())
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.clock.now()
+    }
+
+    fn take_tx_timestamp(&self, _ident: u16, _seq: u16) -> Option<KernelTimestamp> {
+        None
+    }
+
+    fn recv_packet_size(&self) -> usize {
+        MAX_RECV_PACKET_SIZE
+    }
+
+    fn reserve_recv_packet_size(&self, _size: usize) {}
+}
+
+/// Build the echo reply of `destination` to `request` as a raw socket
+/// receives it: with the IPv4 header for IPv4, without for IPv6.
+fn echo_reply(destination: IpAddr, request: &[u8]) -> Vec<u8> {
+    let mut message = request.to_vec();
+    match destination {
+        IpAddr::V4(source) => {
+            message[0] = IcmpType::ECHO_REPLY.0;
+            wire::set_icmp_checksum(&mut message);
+            with_ipv4_header(source, Ipv4Addr::LOCALHOST, 64, &message)
+        }
+        IpAddr::V6(_) => {
+            message[0] = Icmpv6Type::ECHO_REPLY.0;
+            message
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Identifiers stay unique among the pingers of a socket.
+//!
+//! Needs an ICMP socket like `tests/runtime.rs`, and passes without checking
+//! anything when none can be opened.
+#![cfg(feature = "runtime-tokio")]
+use std::net::{IpAddr, Ipv4Addr};
+
+use socket2::Domain;
+use surge_ping::{PingSocketBuilder, SurgeError};
+
+#[tokio::test]
+async fn ident_in_use_is_refused() {
+    let builder = PingSocketBuilder::new_auto(Domain::IPV4);
+    let socket = match builder.and_then(PingSocketBuilder::build) {
+        Ok(socket) => socket,
+        Err(SurgeError::PermissionDenied(_)) => {
+            eprintln!("no ICMP socket available, skipped");
+            return;
+        }
+        Err(e) => panic!("failed to open an ICMP socket: {}", e),
+    };
+    let loopback = |host| IpAddr::V4(Ipv4Addr::new(127, 0, 0, host));
+    let mut first = socket
+        .pinger_builder(loopback(1))
+        .ident(1)
+        .build()
+        .await
+        .unwrap();
+    let _second = socket
+        .pinger_builder(loopback(2))
+        .ident(2)
+        .build()
+        .await
+        .unwrap();
+
+    match first.try_ident(2) {
+        Err(SurgeError::IdentInUse { ident: 2 }) => {}
+        Err(e) => panic!("expected IdentInUse, got {}", e),
+        Ok(_) => panic!("took the identifier of another pinger"),
+    }
+    assert_eq!(first.get_ident(), 1);
+    first.ident(2);
+    assert_eq!(first.get_ident(), 1);
+
+    // The identifier kept is still reserved, a third pinger can't take it.
+    match socket.pinger_builder(loopback(3)).ident(1).build().await {
+        Err(SurgeError::IdentInUse { ident: 1 }) => {}
+        Err(e) => panic!("expected IdentInUse, got {}", e),
+        Ok(_) => panic!("took the identifier of another pinger"),
+    }
+
+    first.try_ident(3).unwrap();
+    assert_eq!(first.get_ident(), 3);
+    socket
+        .pinger_builder(loopback(4))
+        .ident(1)
+        .build()
+        .await
+        .unwrap();
+}
@@ -0,0 +1,163 @@
+//! Reply matching of `Pinger` over `MockTransport`, on tokio's paused clock.
+//!
+//! The send timestamp in the payload is turned off so the RTTs come from the
+//! send times recorded by the pinger, which follow the paused clock exactly.
+#![cfg(feature = "runtime-tokio")]
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use surge_ping::icmp::icmpv6::make_icmpv6_echo_packet;
+use surge_ping::{MockTransport, PingReply, Pinger, SurgeError};
+use tokio_stream::StreamExt;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn addr(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
+
+fn pinger(mock: &MockTransport, destination: &str, ident: u16) -> Pinger {
+    let mut pinger = mock.pinger(addr(destination));
+    pinger
+        .ident(ident)
+        .timeout(TIMEOUT)
+        .timestamp_payload(false);
+    pinger
+}
+
+/// An echo reply as a raw IPv6 socket receives it.
+fn echo_reply_v6(ident: u16, seq: u16) -> Vec<u8> {
+    let mut message = make_icmpv6_echo_packet(ident, seq, 56, &[]).unwrap();
+    message[0] = 129;
+    message
+}
+
+#[tokio::test(start_paused = true)]
+async fn reply_within_the_timeout() {
+    let mock = MockTransport::new();
+    let mut pinger = pinger(&mock, "192.0.2.1", 7);
+    mock.reply(7, 0, ms(40));
+    let result = pinger.ping(0).await.unwrap();
+    assert_eq!((result.seq, result.ident), (0, 7));
+    assert_eq!(result.rtt, ms(40));
+    assert_eq!(result.source, addr("192.0.2.1"));
+    assert!(!result.duplicate);
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_request_times_out() {
+    let mock = MockTransport::new();
+    let mut pinger = pinger(&mock, "192.0.2.1", 7);
+    let start = tokio::time::Instant::now();
+    match pinger.ping(0).await {
+        Err(SurgeError::Timeout {
+            ident: 7,
+            seq: 0,
+            timeout,
+            ..
+        }) => assert_eq!(timeout, TIMEOUT),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    assert!(start.elapsed() >= TIMEOUT);
+    assert_eq!(pinger.pending(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn late_reply_is_dropped_and_counted() {
+    let mock = MockTransport::new();
+    let mut pinger = pinger(&mock, "192.0.2.1", 7);
+    let late = Arc::new(Mutex::new(Vec::new()));
+    let seen = late.clone();
+    pinger.on_late_reply(move |seq, rtt| seen.lock().unwrap().push((seq, rtt)));
+
+    mock.reply(7, 0, TIMEOUT + ms(50));
+    assert!(matches!(
+        pinger.ping(0).await,
+        Err(SurgeError::Timeout { seq: 0, .. })
+    ));
+    // The reply to request 0 arrives while waiting for request 1.
+    mock.reply(7, 1, ms(80));
+    let result = pinger.ping(1).await.unwrap();
+    assert_eq!((result.seq, result.rtt), (1, ms(80)));
+    assert_eq!(pinger.late_replies(), 1);
+    assert_eq!(*late.lock().unwrap(), [(0, TIMEOUT + ms(50))]);
+    assert_eq!(pinger.duplicates(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicate_reply_is_flagged() {
+    let mock = MockTransport::new();
+    let mut pinger = pinger(&mock, "192.0.2.1", 7);
+    mock.reply(7, 0, ms(10));
+    mock.reply(7, 0, ms(30));
+    pinger.send_probe(0).await.unwrap();
+
+    let replies = pinger.replies();
+    tokio::pin!(replies);
+    match replies.next().await {
+        Some(PingReply::Reply { seq: 0, rtt, .. }) => assert_eq!(rtt, ms(10)),
+        other => panic!("expected the reply, got {:?}", other),
+    }
+    match replies.next().await {
+        Some(PingReply::Duplicate(result)) => {
+            assert!(result.duplicate);
+            assert_eq!((result.seq, result.rtt), (0, ms(30)));
+            assert!(result.to_string().ends_with(" (DUP!)"));
+        }
+        other => panic!("expected the duplicate, got {:?}", other),
+    }
+    drop(replies);
+    assert_eq!(pinger.duplicates(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn duplicate_reply_is_not_taken_for_the_next_request() {
+    let mock = MockTransport::new();
+    let mut pinger = pinger(&mock, "192.0.2.1", 7);
+    mock.reply(7, 0, ms(10));
+    mock.reply(7, 0, ms(20));
+    assert_eq!(pinger.ping(0).await.unwrap().rtt, ms(10));
+    mock.reply(7, 1, ms(50));
+    let result = pinger.ping(1).await.unwrap();
+    assert_eq!((result.seq, result.rtt), (1, ms(50)));
+    assert_eq!(pinger.duplicates(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn replies_reach_the_pinger_of_their_source() {
+    let mock = MockTransport::new();
+    let mut first = pinger(&mock, "192.0.2.1", 1);
+    let mut second = pinger(&mock, "192.0.2.2", 2);
+    mock.reply(1, 0, ms(20));
+    mock.reply(2, 0, ms(10));
+    let (first, second) = tokio::join!(first.ping(0), second.ping(0));
+    let (first, second) = (first.unwrap(), second.unwrap());
+    assert_eq!(
+        (first.source, first.ident, first.rtt),
+        (addr("192.0.2.1"), 1, ms(20))
+    );
+    assert_eq!(
+        (second.source, second.ident, second.rtt),
+        (addr("192.0.2.2"), 2, ms(10))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn replies_to_other_requests_are_ignored() {
+    let mock = MockTransport::new();
+    let destination = "2001:db8::1";
+    let mut pinger = pinger(&mock, destination, 7);
+    // Another identifier, a request never sent, and a truncated message.
+    mock.inject(addr(destination), &echo_reply_v6(8, 0), ms(10));
+    mock.inject(addr(destination), &echo_reply_v6(7, 5), ms(10));
+    mock.inject(addr(destination), &echo_reply_v6(7, 0)[..6], ms(10));
+    mock.reply(7, 0, ms(30));
+    let result = pinger.ping(0).await.unwrap();
+    assert_eq!((result.seq, result.ident, result.rtt), (0, 7, ms(30)));
+    assert_eq!(pinger.duplicates(), 0);
+}
@@ -0,0 +1,47 @@
+//! Ping the loopback address on each runtime.
+//!
+//! Needs an ICMP socket: a raw one with privileges, or a datagram one for the
+//! groups in the `net.ipv4.ping_group_range` sysctl on Linux. The tests pass
+//! without pinging when neither can be opened.
+//!
+//! The async-std test runs with
+//! `cargo test --no-default-features --features runtime-async-std`.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+use socket2::Domain;
+use surge_ping::{PingSocketBuilder, SurgeError};
+
+async fn ping_loopback() {
+    let builder = PingSocketBuilder::new_auto(Domain::IPV4);
+    let socket = match builder.and_then(PingSocketBuilder::build) {
+        Ok(socket) => socket,
+        Err(SurgeError::PermissionDenied(_)) => {
+            eprintln!("no ICMP socket available, skipped");
+            return;
+        }
+        Err(e) => panic!("failed to open an ICMP socket: {}", e),
+    };
+    let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let mut pinger = socket.pinger(loopback).await.unwrap();
+    pinger.timeout(Duration::from_secs(1));
+    for seq in 0..3 {
+        let result = pinger.ping(seq).await.unwrap();
+        assert_eq!((result.seq, result.source), (seq, loopback));
+        assert!(result.packet.is_echo_reply());
+        assert!(result.rtt < Duration::from_secs(1));
+    }
+}
+
+#[cfg(feature = "runtime-tokio")]
+#[tokio::test]
+async fn loopback_on_tokio() {
+    ping_loopback().await;
+}
+
+// The crate runs on tokio when both runtimes are enabled.
+#[cfg(all(feature = "runtime-async-std", not(feature = "runtime-tokio")))]
+#[async_std::test]
+async fn loopback_on_async_std() {
+    ping_loopback().await;
+}